@@ -0,0 +1,50 @@
+// Times every registered effect with its default params on a synthetic image.
+//
+//     cargo bench --bench effects                 # 24 MP, every effect
+//     cargo bench --bench effects -- blur wind    # only effects matching a filter
+//     BENCH_MEGAPIXELS=4 BENCH_RUNS=5 cargo bench --bench effects
+use std::time::{Duration, Instant};
+
+use image::{DynamicImage, GenericImageView, Rgba, RgbaImage};
+use img_corroder::effects::{self, Params};
+
+fn test_image(megapixels: f64) -> DynamicImage {
+    // 3:2 like most camera sensors, 6000x4000 for 24 MP
+    let width = (megapixels * 1_000_000.0 * 1.5).sqrt() as u32;
+    let height = (width as f64 / 1.5) as u32;
+    let img = RgbaImage::from_fn(width, height, |x, y| {
+        let noise = (x.wrapping_mul(2654435761) ^ y.wrapping_mul(40503)) >> 24;
+        Rgba([(x % 256) as u8, (y % 256) as u8, noise as u8, 255])
+    });
+    DynamicImage::ImageRgba8(img)
+}
+
+fn main() {
+    let megapixels = std::env::var("BENCH_MEGAPIXELS").ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(24.0);
+    let runs: usize = std::env::var("BENCH_RUNS").ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(1);
+    // cargo passes --bench along with the user's filters
+    let filters: Vec<String> = std::env::args().skip(1).filter(|a| !a.starts_with("--")).collect();
+
+    let img = test_image(megapixels);
+    println!("{}x{}, {} thread(s), best of {} run(s)", img.width(), img.height(), img_corroder::parallel::current_threads(), runs);
+    for effect in effects::EFFECTS {
+        if effect.has_required() || !filters.is_empty() && !filters.iter().any(|f| effect.name.contains(f.as_str())) {
+            continue;
+        }
+        let params = effect.default_params();
+        let params = Params::new(effect, &params);
+        let mut best = Duration::MAX;
+        for _ in 0..runs.max(1) {
+            let input = img.clone();
+            let start = Instant::now();
+            let output = (effect.apply)(input, &params).expect("Effect failed");
+            best = best.min(start.elapsed());
+            drop(output);
+        }
+        println!("{:<16} {:>8} ms", effect.name, best.as_millis());
+    }
+}
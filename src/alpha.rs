@@ -0,0 +1,118 @@
+use std::error::Error;
+
+use image::{DynamicImage, GenericImageView};
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::depth::{self, RgbaBuffer, Sample};
+use crate::parallel;
+
+/// What an effect does with the alpha channel.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AlphaPolicy {
+    /// The effect only changes color; the input alpha is put back afterwards
+    Preserve,
+    /// The effect gets the alpha channel and its output alpha is kept
+    Process,
+    /// Colors are weighted by alpha while the effect runs, so averaging
+    /// effects do not pull in the color of transparent pixels
+    Premultiply,
+}
+
+impl AlphaPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AlphaPolicy::Preserve => "preserve",
+            AlphaPolicy::Process => "process",
+            AlphaPolicy::Premultiply => "premultiply",
+        }
+    }
+}
+
+pub fn is_opaque(img: &DynamicImage) -> bool {
+    if !img.color().has_alpha() {
+        return true;
+    }
+    if depth::is_16bit(img) {
+        depth::rgba16(img).pixels().all(|p| p[3] == u16::MAX)
+    } else {
+        parallel::rgba(img).pixels().all(|p| p[3] == u8::MAX)
+    }
+}
+
+fn alpha_channel<S: Sample>(buffer: &RgbaBuffer<S>) -> Vec<S> {
+    buffer.pixels().map(|p| p[3]).collect()
+}
+
+fn set_alpha<S: Sample>(buffer: &mut RgbaBuffer<S>, alpha: &[S]) {
+    for (pixel, &a) in buffer.pixels_mut().zip(alpha) {
+        pixel[3] = a;
+    }
+}
+
+/// Runs `apply` on `img` under `policy`. The result is RGBA at the depth of
+/// the input, except under `Process`, which returns whatever the effect
+/// made. Opaque images are passed straight through.
+pub fn apply_with<F>(img: DynamicImage, policy: AlphaPolicy, apply: F) -> Result<DynamicImage, Box<dyn Error>>
+where
+    F: FnOnce(DynamicImage) -> Result<DynamicImage, Box<dyn Error>>,
+{
+    if policy == AlphaPolicy::Process || is_opaque(&img) {
+        return apply(img);
+    }
+    let sixteen_bit = depth::is_16bit(&img);
+    let dimensions = img.dimensions();
+
+    match policy {
+        AlphaPolicy::Preserve => {
+            if sixteen_bit {
+                let alpha = alpha_channel(&depth::rgba16(&img));
+                let output = apply(img)?;
+                if output.dimensions() != dimensions {
+                    debug!("Effect changed the image size, keeping its own alpha");
+                    return Ok(output);
+                }
+                let mut output = depth::to_rgba16(&output);
+                set_alpha(&mut output, &alpha);
+                Ok(DynamicImage::ImageRgba16(output))
+            } else {
+                let alpha = alpha_channel(&parallel::rgba(&img));
+                let output = apply(img)?;
+                if output.dimensions() != dimensions {
+                    debug!("Effect changed the image size, keeping its own alpha");
+                    return Ok(output);
+                }
+                let mut output = output.to_rgba8();
+                set_alpha(&mut output, &alpha);
+                Ok(DynamicImage::ImageRgba8(output))
+            }
+        },
+        _ => {
+            // Premultiplied at 16 bits, so faint pixels keep their color
+            let mut premultiplied = depth::to_rgba16(&img);
+            parallel::for_each_row(&mut premultiplied, |_, row| {
+                for pixel in row.chunks_exact_mut(4) {
+                    let a = pixel[3] as u32;
+                    for value in pixel.iter_mut().take(3) {
+                        *value = ((*value as u32 * a + 32767) / 65535) as u16;
+                    }
+                }
+            });
+            let mut output = depth::to_rgba16(&apply(DynamicImage::ImageRgba16(premultiplied))?);
+            parallel::for_each_row(&mut output, |_, row| {
+                for pixel in row.chunks_exact_mut(4) {
+                    let a = pixel[3] as u32;
+                    for value in pixel.iter_mut().take(3) {
+                        *value = (*value as u32 * 65535 + a / 2).checked_div(a).unwrap_or(0).min(65535) as u16;
+                    }
+                }
+            });
+            if sixteen_bit {
+                Ok(DynamicImage::ImageRgba16(output))
+            } else {
+                Ok(DynamicImage::ImageRgba8(depth::to_rgba8(&DynamicImage::ImageRgba16(output))))
+            }
+        },
+    }
+}
@@ -0,0 +1,79 @@
+use image::{Rgb, RgbImage};
+use serde_json::{json, Value};
+
+use crate::colorfx::utils::{rgb_to_hsv, rgb_to_lab};
+
+fn hex(color: &Rgb<u8>) -> String {
+    format!("#{:02x}{:02x}{:02x}", color[0], color[1], color[2])
+}
+
+// Trims the float noise of f32 math from the report
+fn round(value: f32, places: i32) -> f64 {
+    let scale = 10f64.powi(places);
+    (value as f64 * scale).round() / scale
+}
+
+/// Dominant colors with their share of the image as a JSON report.
+pub fn palette_json(colors: &[(Rgb<u8>, f32)]) -> Value {
+    let colors: Vec<Value> = colors.iter()
+        .map(|(color, share)| {
+            let (h, s, v) = rgb_to_hsv(color[0], color[1], color[2]);
+            let lab = rgb_to_lab(color[0] as f32, color[1] as f32, color[2] as f32);
+            json!({
+                "hex": hex(color),
+                "rgb": color.0,
+                "hsv": [round(h.rem_euclid(360.0), 1), round(s, 3), round(v, 3)],
+                "lab": lab.map(|c| round(c, 2)),
+                "share": round(*share, 4),
+            })
+        })
+        .collect();
+    json!({ "colors": colors })
+}
+
+/// GIMP palette (.gpl), also read by Inkscape, Krita and Aseprite. Each
+/// color is named after its hex code and share.
+pub fn to_gpl(colors: &[(Rgb<u8>, f32)], name: &str) -> String {
+    let mut gpl = format!("GIMP Palette\nName: {}\nColumns: {}\n#\n", name, colors.len().min(16));
+    for (color, share) in colors {
+        gpl.push_str(&format!("{:3} {:3} {:3}\t{} ({:.1}%)\n", color[0], color[1], color[2], hex(color), share * 100.0));
+    }
+    gpl
+}
+
+/// Adobe Swatch Exchange (.ase) file with one RGB swatch per color.
+pub fn to_ase(colors: &[(Rgb<u8>, f32)]) -> Vec<u8> {
+    let mut ase = Vec::new();
+    ase.extend_from_slice(b"ASEF");
+    ase.extend_from_slice(&1u16.to_be_bytes());
+    ase.extend_from_slice(&0u16.to_be_bytes());
+    ase.extend_from_slice(&(colors.len() as u32).to_be_bytes());
+    for (color, _) in colors {
+        // Names are null-terminated UTF-16
+        let name: Vec<u16> = hex(color).encode_utf16().chain(Some(0)).collect();
+        let mut block = Vec::new();
+        block.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        for unit in &name {
+            block.extend_from_slice(&unit.to_be_bytes());
+        }
+        block.extend_from_slice(b"RGB ");
+        for channel in color.0 {
+            block.extend_from_slice(&(channel as f32 / 255.0).to_be_bytes());
+        }
+        // Normal (not global or spot) color
+        block.extend_from_slice(&2u16.to_be_bytes());
+
+        ase.extend_from_slice(&1u16.to_be_bytes());
+        ase.extend_from_slice(&(block.len() as u32).to_be_bytes());
+        ase.extend_from_slice(&block);
+    }
+    ase
+}
+
+/// A strip of `size` x `size` squares, one per color, in order.
+pub fn swatch(colors: &[(Rgb<u8>, f32)], size: u32) -> RgbImage {
+    let width = size * colors.len().max(1) as u32;
+    RgbImage::from_fn(width, size, |x, _| {
+        colors.get((x / size) as usize).map_or(Rgb([0, 0, 0]), |(color, _)| *color)
+    })
+}
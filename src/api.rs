@@ -0,0 +1,496 @@
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use image::{DynamicImage, GenericImageView, ImageFormat};
+use log::{error, info, warn};
+use serde_json::{json, Value};
+use tiny_http::{Method, Request, Server};
+
+use crate::depth::Depth;
+use crate::effects;
+use crate::icc::Profile;
+use crate::metadata::{self, Metadata};
+use crate::output;
+use crate::pipeline::{self, Config};
+use crate::server::{header, json_response, text, HttpResponse};
+
+// Finished jobs are kept this long for clients to fetch their results
+const JOB_RETENTION: Duration = Duration::from_secs(600);
+
+// At most this many finished jobs are kept; the oldest go first
+const MAX_FINISHED_JOBS: usize = 256;
+
+#[derive(Debug, Clone)]
+pub struct ApiOptions {
+    pub host: String,
+    pub port: u16,
+    /// Number of pipelines rendered concurrently.
+    pub workers: usize,
+    /// Renders waiting for a worker before new requests are rejected.
+    pub queue_size: usize,
+    /// Largest accepted request body.
+    pub max_body_bytes: usize,
+    /// Largest accepted image, in pixels.
+    pub max_pixels: u64,
+    /// Directory that file params (LUTs, palette references, threshold
+    /// maps, output profiles) are looked up in. Without one, pipelines that
+    /// name files are rejected.
+    pub files_dir: Option<PathBuf>,
+    /// Time a render may run before it is abandoned. The timeout is soft:
+    /// it is checked between pipeline steps, and a step that has started
+    /// keeps its worker busy until it finishes.
+    pub job_timeout: Duration,
+}
+
+struct RenderRequest {
+    input: DynamicImage,
+    metadata: Metadata,
+    config: Config,
+    format: ImageFormat,
+}
+
+enum RenderError {
+    TimedOut(String),
+    Failed(String),
+}
+
+impl RenderError {
+    fn status(&self) -> u16 {
+        match self {
+            RenderError::TimedOut(_) => 504,
+            RenderError::Failed(_) => 422,
+        }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            RenderError::TimedOut(m) | RenderError::Failed(m) => m,
+        }
+    }
+}
+
+type RenderResult = Result<Vec<u8>, RenderError>;
+
+enum JobStatus {
+    Queued,
+    Running,
+    Done(Vec<u8>),
+    Failed(RenderError),
+}
+
+struct Job {
+    status: JobStatus,
+    format: ImageFormat,
+    finished: Option<Instant>,
+}
+
+struct Task {
+    job_id: Option<u64>,
+    request: RenderRequest,
+    reply: Option<Sender<RenderResult>>,
+}
+
+struct Api {
+    options: ApiOptions,
+    jobs: Mutex<HashMap<u64, Job>>,
+    next_id: AtomicU64,
+    queue: SyncSender<Task>,
+}
+
+struct HttpError {
+    status: u16,
+    message: String,
+}
+
+type HttpResult = Result<HttpResponse, HttpError>;
+
+fn http_error(status: u16, message: impl Into<String>) -> HttpError {
+    HttpError { status, message: message.into() }
+}
+
+fn render(request: RenderRequest, timeout: Duration) -> RenderResult {
+    let deadline = Instant::now() + timeout;
+    let processed = pipeline::apply_pipeline_until(request.input, &request.config.transformations, deadline)
+        .map_err(|e| if Instant::now() >= deadline {
+            RenderError::TimedOut(e.to_string())
+        } else {
+            RenderError::Failed(e.to_string())
+        })?;
+    output::encode_image(&processed, request.format, &request.config.output, &request.metadata).map_err(RenderError::Failed)
+}
+
+fn worker(api: Arc<Api>, tasks: Arc<Mutex<Receiver<Task>>>) {
+    loop {
+        // The lock is only held while waiting, so idle workers take turns
+        let task = match tasks.lock().unwrap().recv() {
+            Ok(task) => task,
+            Err(_) => return,
+        };
+        if let Some(id) = task.job_id {
+            api.update_job(id, JobStatus::Running);
+        }
+        let start = Instant::now();
+        // A panicking effect fails its own render instead of the worker
+        let timeout = api.options.job_timeout;
+        let result = panic::catch_unwind(AssertUnwindSafe(|| render(task.request, timeout)))
+            .unwrap_or_else(|payload| {
+                let reason = payload.downcast_ref::<&str>().copied()
+                    .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                    .unwrap_or("unknown cause");
+                Err(RenderError::Failed(format!("Render panicked: {}", reason)))
+            });
+        match &result {
+            Ok(bytes) => info!("Rendered {} bytes in {} ms", bytes.len(), start.elapsed().as_millis()),
+            Err(e) => warn!("Render failed: {}", e.message()),
+        }
+
+        if let Some(id) = task.job_id {
+            api.update_job(id, match result {
+                Ok(bytes) => JobStatus::Done(bytes),
+                Err(e) => JobStatus::Failed(e),
+            });
+        } else if let Some(reply) = task.reply {
+            // The client may have hung up; nothing left to do then
+            let _ = reply.send(result);
+        }
+    }
+}
+
+impl Api {
+    fn update_job(&self, id: u64, status: JobStatus) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs.get_mut(&id) {
+            if matches!(status, JobStatus::Done(_) | JobStatus::Failed(_)) {
+                job.finished = Some(Instant::now());
+            }
+            job.status = status;
+        }
+        prune_jobs(&mut jobs);
+    }
+
+    fn enqueue(&self, task: Task) -> Result<(), HttpError> {
+        self.queue.try_send(task).map_err(|e| match e {
+            TrySendError::Full(_) => http_error(503, "Render queue is full, try again later"),
+            TrySendError::Disconnected(_) => http_error(500, "Workers are not running"),
+        })
+    }
+
+    fn read_body(&self, request: &mut Request) -> Result<Vec<u8>, HttpError> {
+        let limit = self.options.max_body_bytes;
+        let too_large = || http_error(413, format!("Request body exceeds {} bytes", limit));
+        if request.body_length().is_some_and(|length| length > limit) {
+            return Err(too_large());
+        }
+        let mut body = Vec::new();
+        request.as_reader()
+            .take(limit as u64 + 1)
+            .read_to_end(&mut body)
+            .map_err(|e| http_error(400, e.to_string()))?;
+        if body.len() > limit {
+            return Err(too_large());
+        }
+        Ok(body)
+    }
+
+    fn parse_render_request(&self, request: &mut Request) -> Result<RenderRequest, HttpError> {
+        let content_type = request.headers().iter()
+            .find(|h| h.field.equiv("Content-Type"))
+            .map(|h| h.value.as_str().to_string())
+            .unwrap_or_default();
+        let format = match query_param(request.url(), "format") {
+            Some(ext) => ImageFormat::from_extension(&ext)
+                .ok_or_else(|| http_error(400, format!("Unknown output format: {}", ext)))?,
+            None => ImageFormat::Png,
+        };
+        let body = self.read_body(request)?;
+        let parts = parse_multipart(&content_type, &body).map_err(|e| http_error(400, e))?;
+
+        let image_part = parts.iter().find(|p| p.name == "image")
+            .ok_or_else(|| http_error(400, "Missing 'image' part"))?;
+        let pipeline_part = parts.iter().find(|p| p.name == "pipeline")
+            .ok_or_else(|| http_error(400, "Missing 'pipeline' part"))?;
+
+        let (img, metadata) = decode_upload(image_part.data, self.options.max_pixels)?;
+        let mut config: Config = serde_json::from_slice(pipeline_part.data)
+            .map_err(|e| http_error(400, format!("Invalid pipeline: {}", e)))?;
+        confine_files(&mut config, self.options.files_dir.as_deref())?;
+        let (width, height) = img.dimensions();
+        let max_pixels = self.options.max_pixels;
+        for (width, height) in pipeline::planned_sizes(width, height, &config.transformations) {
+            if width.saturating_mul(height) > max_pixels {
+                return Err(http_error(413, format!("Pipeline would make a {}x{} image, more than the limit of {} pixels", width, height, max_pixels)));
+            }
+        }
+
+        Ok(RenderRequest {
+            input: Depth::Auto.standardize(&img),
+            metadata,
+            config,
+            format,
+        })
+    }
+
+    // POST /render: waits for a worker and answers with the image itself
+    fn render_now(&self, request: &mut Request) -> HttpResult {
+        let render_request = self.parse_render_request(request)?;
+        let format = render_request.format;
+        let (reply, result) = mpsc::channel();
+        self.enqueue(Task { job_id: None, request: render_request, reply: Some(reply) })?;
+        match result.recv() {
+            Ok(Ok(bytes)) => Ok(tiny_http::Response::from_data(bytes)
+                .with_header(header("Content-Type", output::mime_type(format)))),
+            Ok(Err(e)) => Err(http_error(e.status(), e.message())),
+            Err(_) => Err(http_error(500, "Worker stopped before finishing")),
+        }
+    }
+
+    // POST /jobs: queues the render and answers with a job id to poll
+    fn submit_job(&self, request: &mut Request) -> HttpResult {
+        let render_request = self.parse_render_request(request)?;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        {
+            let mut jobs = self.jobs.lock().unwrap();
+            prune_jobs(&mut jobs);
+            jobs.insert(id, Job { status: JobStatus::Queued, format: render_request.format, finished: None });
+        }
+        if let Err(e) = self.enqueue(Task { job_id: Some(id), request: render_request, reply: None }) {
+            self.jobs.lock().unwrap().remove(&id);
+            return Err(e);
+        }
+        Ok(json_response(&json!({ "id": id, "status": "queued" }))
+            .with_status_code(202)
+            .with_header(header("Location", &format!("/jobs/{}", id))))
+    }
+
+    fn job_status(&self, id: u64) -> HttpResult {
+        let jobs = self.jobs.lock().unwrap();
+        let job = jobs.get(&id).ok_or_else(|| http_error(404, "Unknown job"))?;
+        let body = match &job.status {
+            JobStatus::Queued => json!({ "id": id, "status": "queued" }),
+            JobStatus::Running => json!({ "id": id, "status": "running" }),
+            JobStatus::Done(bytes) => json!({
+                "id": id,
+                "status": "done",
+                "content_type": output::mime_type(job.format),
+                "size": bytes.len(),
+                "result": format!("/jobs/{}/result", id),
+            }),
+            JobStatus::Failed(e) => json!({
+                "id": id,
+                "status": if matches!(e, RenderError::TimedOut(_)) { "timed_out" } else { "failed" },
+                "error": e.message(),
+            }),
+        };
+        Ok(json_response(&body))
+    }
+
+    fn job_result(&self, id: u64) -> HttpResult {
+        let jobs = self.jobs.lock().unwrap();
+        let job = jobs.get(&id).ok_or_else(|| http_error(404, "Unknown job"))?;
+        match &job.status {
+            JobStatus::Done(bytes) => Ok(tiny_http::Response::from_data(bytes.clone())
+                .with_header(header("Content-Type", output::mime_type(job.format)))),
+            JobStatus::Failed(e) => Err(http_error(e.status(), e.message())),
+            _ => Err(http_error(409, "Job has not finished yet")),
+        }
+    }
+
+    fn handle(&self, request: &mut Request) -> HttpResult {
+        let path = request.url().split('?').next().unwrap_or("/").to_string();
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        match (request.method(), segments.as_slice()) {
+            (Method::Get, ["health"]) => Ok(json_response(&json!({
+                "status": "ok",
+                "workers": self.options.workers,
+                "queue_size": self.options.queue_size,
+            }))),
+            (Method::Post, ["render"]) => self.render_now(request),
+            (Method::Post, ["jobs"]) => self.submit_job(request),
+            (Method::Get, ["jobs", id]) => self.job_status(parse_id(id)?),
+            (Method::Get, ["jobs", id, "result"]) => self.job_result(parse_id(id)?),
+            _ => Err(http_error(404, "Not found")),
+        }
+    }
+}
+
+// Drops finished jobs past their retention, then the oldest finished jobs
+// beyond MAX_FINISHED_JOBS
+fn prune_jobs(jobs: &mut HashMap<u64, Job>) {
+    jobs.retain(|_, job| job.finished.is_none_or(|t| t.elapsed() < JOB_RETENTION));
+    let mut finished: Vec<(Instant, u64)> = jobs.iter()
+        .filter_map(|(id, job)| job.finished.map(|t| (t, *id)))
+        .collect();
+    if finished.len() > MAX_FINISHED_JOBS {
+        finished.sort_unstable();
+        for (_, id) in &finished[..finished.len() - MAX_FINISHED_JOBS] {
+            jobs.remove(id);
+        }
+    }
+}
+
+// Decodes an uploaded image, refusing images of more than `max_pixels`. The
+// size is checked from the header first, so oversized images are rejected
+// before their pixels are allocated
+fn decode_upload(data: &[u8], max_pixels: u64) -> Result<(DynamicImage, Metadata), HttpError> {
+    let check = |(width, height): (u32, u32)| {
+        if width as u64 * height as u64 > max_pixels {
+            return Err(http_error(413, format!("Image is {}x{}, more than the limit of {} pixels", width, height, max_pixels)));
+        }
+        Ok(())
+    };
+    let header = image::io::Reader::new(Cursor::new(data)).with_guessed_format().ok()
+        .and_then(|reader| reader.into_dimensions().ok());
+    if let Some(dimensions) = header {
+        check(dimensions)?;
+    }
+    let (img, metadata) = metadata::decode(data)
+        .map_err(|e| http_error(400, format!("Could not decode image: {}", e)))?;
+    check(img.dimensions())?;
+    Ok((img, metadata))
+}
+
+// Points the params that name files at the files directory, so clients
+// cannot read anything else on the server
+fn confine_files(config: &mut Config, files_dir: Option<&Path>) -> Result<(), HttpError> {
+    for transform in &mut config.transformations {
+        let Some(effect) = effects::find(&transform.name) else { continue };
+        for spec in effect.params.iter().filter(|p| p.file) {
+            let Some(value) = transform.params.get_mut(spec.name) else { continue };
+            let name = value.as_str()
+                .ok_or_else(|| http_error(400, format!("{}: '{}' must be a file name", effect.name, spec.name)))?;
+            *value = Value::String(resolve_file(name, files_dir)?);
+        }
+    }
+    if let Some(profile) = config.output.profile.as_mut() {
+        if profile != "input" && Profile::named(profile).is_none() {
+            *profile = resolve_file(profile, files_dir)?;
+        }
+    }
+    Ok(())
+}
+
+fn resolve_file(name: &str, files_dir: Option<&Path>) -> Result<String, HttpError> {
+    let dir = files_dir.ok_or_else(|| {
+        http_error(400, format!("'{}' names a file on the server, which this API does not allow (see --files-dir)", name))
+    })?;
+    // Canonicalized, so neither ".." nor symlinks lead out of the directory
+    let path = dir.join(name).canonicalize().ok()
+        .filter(|path| path.starts_with(dir) && path.is_file())
+        .ok_or_else(|| http_error(400, format!("No file named '{}' in the files directory", name)))?;
+    Ok(path.to_string_lossy().into_owned())
+}
+
+fn parse_id(id: &str) -> Result<u64, HttpError> {
+    id.parse().map_err(|_| http_error(404, "Unknown job"))
+}
+
+fn query_param(url: &str, name: &str) -> Option<String> {
+    url.split_once('?')?.1
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.to_string())
+}
+
+pub struct Part<'a> {
+    pub name: String,
+    pub data: &'a [u8],
+}
+
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    haystack.get(from..)?
+        .windows(needle.len())
+        .position(|w| w == needle)
+        .map(|i| i + from)
+}
+
+/// Splits a `multipart/form-data` body into its named parts.
+pub fn parse_multipart<'a>(content_type: &str, body: &'a [u8]) -> Result<Vec<Part<'a>>, String> {
+    if !content_type.starts_with("multipart/form-data") {
+        return Err("Expected a multipart/form-data body".into());
+    }
+    let boundary = content_type.split(';')
+        .filter_map(|p| p.trim().strip_prefix("boundary="))
+        .next()
+        .ok_or("Missing multipart boundary")?
+        .trim_matches('"');
+    let delimiter = format!("--{}", boundary).into_bytes();
+
+    let mut parts = Vec::new();
+    let mut position = find(body, &delimiter, 0).ok_or("Malformed multipart body")? + delimiter.len();
+    loop {
+        if body[position..].starts_with(b"--") {
+            break;
+        }
+        let headers_start = position + 2; // skip CRLF after the delimiter
+        let headers_end = find(body, b"\r\n\r\n", headers_start).ok_or("Malformed multipart headers")?;
+        let next = find(body, &delimiter, headers_end).ok_or("Unterminated multipart body")?;
+        let headers = String::from_utf8_lossy(&body[headers_start..headers_end]);
+        let name = headers.lines()
+            .filter(|l| l.to_ascii_lowercase().starts_with("content-disposition"))
+            .flat_map(|l| l.split(';'))
+            .filter_map(|p| p.trim().strip_prefix("name="))
+            .map(|n| n.trim_matches('"').to_string())
+            .next()
+            .unwrap_or_default();
+        // Part data ends with the CRLF that precedes the next delimiter
+        let data_end = next.saturating_sub(2).max(headers_end + 4);
+        parts.push(Part { name, data: &body[headers_end + 4..data_end] });
+        position = next + delimiter.len();
+    }
+    Ok(parts)
+}
+
+/// Runs the HTTP processing API until the process is stopped.
+pub fn run(mut options: ApiOptions) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(dir) = &options.files_dir {
+        options.files_dir = Some(dir.canonicalize().map_err(|e| format!("Invalid files directory {}: {}", dir.display(), e))?);
+    }
+    let server = Arc::new(Server::http((options.host.as_str(), options.port)).map_err(|e| e.to_string())?);
+    let (queue, tasks) = mpsc::sync_channel(options.queue_size);
+    let tasks = Arc::new(Mutex::new(tasks));
+    let api = Arc::new(Api {
+        options: options.clone(),
+        jobs: Mutex::new(HashMap::new()),
+        next_id: AtomicU64::new(1),
+        queue,
+    });
+
+    for _ in 0..options.workers.max(1) {
+        let (api, tasks) = (Arc::clone(&api), Arc::clone(&tasks));
+        thread::spawn(move || worker(api, tasks));
+    }
+
+    // Connection threads only parse and wait, so allow more of them than workers
+    let http_threads = (options.workers * 2).max(4);
+    let handlers: Vec<_> = (0..http_threads)
+        .map(|_| {
+            let (api, server) = (Arc::clone(&api), Arc::clone(&server));
+            thread::spawn(move || {
+                for mut request in server.incoming_requests() {
+                    let response = api.handle(&mut request).unwrap_or_else(|e| {
+                        error!("{} {}: {} {}", request.method(), request.url(), e.status, e.message);
+                        text(e.status, &e.message)
+                    });
+                    if let Err(e) = request.respond(response) {
+                        error!("Failed to send response: {}", e);
+                    }
+                }
+            })
+        })
+        .collect();
+
+    info!("Processing API listening on http://{}:{} with {} worker(s)", options.host, options.port, options.workers);
+    println!("Processing API running at http://{}:{}/ (Ctrl+C to stop)", options.host, options.port);
+    for handler in handlers {
+        let _ = handler.join();
+    }
+    Ok(())
+}
@@ -1,28 +1,29 @@
-use image::{DynamicImage, GenericImage, GenericImageView, Pixel, Rgba};
-use rand::Rng;
-mod utils;
-use utils::{color_distance, create_color_map, hsv_to_rgb, rgb_to_hsv};
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+
+use image::{DynamicImage, GrayImage};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+pub mod gradient;
+pub mod halftone;
+pub mod palette;
+pub mod replace;
+pub mod tone;
+pub mod utils;
+use crate::depth::{self, RgbaBuffer, Sample};
+use crate::parallel;
+use utils::{hsv_to_rgb_f32, rgb_to_hsv_f32};
 
 pub fn contrast(img: &DynamicImage, factor: f32) -> DynamicImage {
-    let (width, height) = img.dimensions();
-    let mut output = DynamicImage::new_rgba8(width, height);
+    depth::map_rgba(img, |pixel| {
+        let mut adjusted = pixel; // Preserve alpha
 
-    for y in 0..height {
-        for x in 0..width {
-            let pixel = img.get_pixel(x, y);
-            let mut adjusted = Rgba([0, 0, 0, pixel[3]]); // Preserve alpha
-
-            for c in 0..3 {
-                let value = pixel[c] as f32;
-                let adjusted_value = 128.0 + factor * (value - 128.0);
-                adjusted[c] = adjusted_value.min(255.0).max(0.0) as u8;
-            }
-
-            output.put_pixel(x, y, adjusted);
+        for c in 0..3 {
+            adjusted[c] = (128.0 + factor * (pixel[c] - 128.0)).clamp(0.0, 255.0);
         }
-    }
 
-    output
+        adjusted
+    })
 }
 
 
@@ -32,141 +33,75 @@ pub fn saturation(img: &DynamicImage, factor: f32) -> Result<DynamicImage, Strin
         return Err("Saturation factor must be non-negative".into());
     }
     
-    let (width, height) = img.dimensions();
-    let mut output = DynamicImage::new_rgba8(width, height);
-
-    for y in 0..height {
-        for x in 0..width {
-            let pixel = img.get_pixel(x, y);
-            let mut hsv = rgb_to_hsv(pixel[0], pixel[1], pixel[2]);
-            hsv.1 = (hsv.1 * factor).min(1.0);
-            let rgb = hsv_to_rgb(hsv.0, hsv.1, hsv.2);
-            output.put_pixel(x, y, Rgba([rgb.0, rgb.1, rgb.2, pixel[3]])); // Preserve alpha
-        }
-    }
-
-    Ok(output)
+    Ok(depth::map_rgba(img, |pixel| {
+        let mut hsv = rgb_to_hsv_f32(pixel[0], pixel[1], pixel[2]);
+        hsv.1 = (hsv.1 * factor).min(1.0);
+        let rgb = hsv_to_rgb_f32(hsv.0, hsv.1, hsv.2);
+        [rgb.0, rgb.1, rgb.2, pixel[3]] // Preserve alpha
+    }))
 }
 
 
 
 pub fn add_noise(img: &DynamicImage, intensity: f32) -> Result<DynamicImage, String> {
     
-    if intensity < 0.0 || intensity > 1.0 {
+    if !(0.0..=1.0).contains(&intensity) {
         return Err("Noise intensity must be between 0.0 and 1.0".into());
     }
     
-    let (width, height) = img.dimensions();
-    let mut output = DynamicImage::new_rgba8(width, height);
-    let mut rng = rand::thread_rng();
+    Ok(depth::map_rgba(img, |mut pixel| {
+        let mut rng = rand::thread_rng();
 
-    for y in 0..height {
-        for x in 0..width {
-            let mut pixel = img.get_pixel(x, y);
-            
-            if rng.gen::<f32>() < intensity {
-                for c in 0..3 {  // Only apply noise to RGB channels, not alpha
-                    let noise = rng.gen_range(-50..=50);
-                    pixel[c] = (pixel[c] as i16 + noise).clamp(0, 255) as u8;
-                }
+        if rng.gen::<f32>() < intensity {
+            for value in pixel.iter_mut().take(3) {  // Only apply noise to RGB channels, not alpha
+                let noise = rng.gen_range(-50..=50);
+                *value = (*value + noise as f32).clamp(0.0, 255.0);
             }
-            
-            output.put_pixel(x, y, pixel);
         }
-    }
 
-    Ok(output)
+        pixel
+    }))
 }
 
 pub fn brightness(img: &DynamicImage, factor: f32) -> Result<DynamicImage, String> {
     
-    if factor < 0.0 || factor > 2.0 {
+    if !(0.0..=2.0).contains(&factor) {
         return Err("Brightness factor must be between 0.0 and 2.0".into());
     }
     
-    let (width, height) = img.dimensions();
-    let mut output = DynamicImage::new_rgba8(width, height);
+    Ok(depth::map_rgba(img, |pixel| {
+        let mut adjusted = pixel; // Preserve alpha
 
-    for y in 0..height {
-        for x in 0..width {
-            let pixel = img.get_pixel(x, y);
-            let mut adjusted = Rgba([0, 0, 0, pixel[3]]); // Preserve alpha
-
-            for c in 0..3 {
-                adjusted[c] = ((pixel[c] as f32 * factor).min(255.0)) as u8;
-            }
-
-            output.put_pixel(x, y, adjusted);
+        for c in 0..3 {
+            adjusted[c] = (pixel[c] * factor).min(255.0);
         }
-    }
 
-    Ok(output)
+        adjusted
+    }))
 }
 
 pub fn sepia(img: &DynamicImage) -> Result<DynamicImage, String> {
-    let (width, height) = img.dimensions();
-    let mut sepia_img = DynamicImage::new_rgba8(width, height);
-
-    for y in 0..height {
-        for x in 0..width {
-            let pixel = img.get_pixel(x, y);
-            let rgb = pixel.to_rgb();
-            
-            let r = rgb[0] as f32;
-            let g = rgb[1] as f32;
-            let b = rgb[2] as f32;
-
-            let sepia_r = (0.393 * r + 0.769 * g + 0.189 * b).min(255.0) as u8;
-            let sepia_g = (0.349 * r + 0.686 * g + 0.168 * b).min(255.0) as u8;
-            let sepia_b = (0.272 * r + 0.534 * g + 0.131 * b).min(255.0) as u8;
-
-            sepia_img.put_pixel(x, y, Rgba([sepia_r, sepia_g, sepia_b, pixel[3]]));
-        }
-    }
-
-    Ok(sepia_img)
-}
-
-pub fn color_replacer(img: &DynamicImage, target_color: &str, replacement_color: &str, tolerance: u8) -> Result<DynamicImage, String> {
-    
-    let color_map = create_color_map();
-    let target_rgb = color_map.get(target_color)
-        .ok_or_else(|| format!("Invalid target color: {}", target_color))?;
-    let replacement_rgb = color_map.get(replacement_color)
-        .ok_or_else(|| format!("Invalid replacement color: {}", replacement_color))?;
-    
-    let mut rgb_image = img.to_rgb8();
-    for (_, _, pixel) in rgb_image.enumerate_pixels_mut() {
-        if color_distance(pixel, target_rgb) <= tolerance {
-            *pixel = *replacement_rgb;
-        }
-    }
+    Ok(depth::map_rgba(img, |[r, g, b, a]| {
+        let sepia_r = (0.393 * r + 0.769 * g + 0.189 * b).min(255.0);
+        let sepia_g = (0.349 * r + 0.686 * g + 0.168 * b).min(255.0);
+        let sepia_b = (0.272 * r + 0.534 * g + 0.131 * b).min(255.0);
 
-    Ok(DynamicImage::ImageRgb8(rgb_image))
+        [sepia_r, sepia_g, sepia_b, a]
+    }))
 }
 
 pub fn vaporwave(img: &DynamicImage) -> Result<DynamicImage, String> {
-    let (width, height) = img.dimensions();
-    let mut vapor_img = DynamicImage::new_rgb8(width, height);
-
-    for y in 0..height {
-        for x in 0..width {
-            let pixel = img.get_pixel(x, y);
-            let rgb = pixel.to_rgb();
-            
-            let mut new_pixel = [0u8; 3];
-            new_pixel[0] = ((rgb[0] as f32 * 0.8 + 50.0).min(255.0)) as u8; // Boost red
-            new_pixel[2] = ((rgb[2] as f32 * 1.2 + 30.0).min(255.0)) as u8; // Boost blue
-            // Add a slight purple tint
-            new_pixel[0] = ((new_pixel[0] as f32 * 0.9 + 25.0).min(255.0)) as u8;
-            new_pixel[1] = ((rgb[1] as f32 * 0.8).min(255.0)) as u8;
-            new_pixel[2] = ((new_pixel[2] as f32 * 1.1).min(255.0)) as u8;
-
-            vapor_img.put_pixel(x, y, Rgba([new_pixel[0], new_pixel[1], new_pixel[2], 255]));
-        }
-    }
-
-    Ok(vapor_img)
+    Ok(depth::map_rgba(img, |rgb| {
+        let mut new_pixel = [0.0, 0.0, 0.0, rgb[3]]; // Preserve alpha
+        new_pixel[0] = (rgb[0] * 0.8 + 50.0).min(255.0); // Boost red
+        new_pixel[2] = (rgb[2] * 1.2 + 30.0).min(255.0); // Boost blue
+        // Add a slight purple tint
+        new_pixel[0] = (new_pixel[0] * 0.9 + 25.0).min(255.0);
+        new_pixel[1] = (rgb[1] * 0.8).min(255.0);
+        new_pixel[2] = (new_pixel[2] * 1.1).min(255.0);
+
+        new_pixel
+    }))
 }
 
 pub fn deep_fry(img: &DynamicImage, factor: f32) -> Result<DynamicImage, String> {
@@ -178,23 +113,13 @@ pub fn deep_fry(img: &DynamicImage, factor: f32) -> Result<DynamicImage, String>
 }
 
 pub fn hue_rotate(img: &DynamicImage, angle: f32) -> Result<DynamicImage, String> {
-    let (width, height) = img.dimensions();
-    let mut rotated_img = DynamicImage::new_rgb8(width, height);
+    Ok(depth::map_rgba(img, |pixel| {
+        let hsv = rgb_to_hsv_f32(pixel[0], pixel[1], pixel[2]);
+        let new_hue = (hsv.0 + angle) % 360.0;
+        let new_rgb = hsv_to_rgb_f32(new_hue, hsv.1, hsv.2);
 
-    for y in 0..height {
-        for x in 0..width {
-            let pixel = img.get_pixel(x, y);
-            let rgb = pixel.to_rgb();
-            
-            let hsv = rgb_to_hsv(rgb[0], rgb[1], rgb[2]);
-            let new_hue = (hsv.0 + angle) % 360.0;
-            let new_rgb = hsv_to_rgb(new_hue, hsv.1, hsv.2);
-            
-            rotated_img.put_pixel(x, y, Rgba([new_rgb.0, new_rgb.1, new_rgb.2, pixel[3]]));
-        }
-    }
-
-    Ok(rotated_img)
+        [new_rgb.0, new_rgb.1, new_rgb.2, pixel[3]] // Preserve alpha
+    }))
 }
 
 // Helper function to generate a Bayer matrix of size n x n (n must be a power of two)
@@ -219,12 +144,35 @@ fn generate_bayer_matrix(n: u32) -> Vec<Vec<f32>> {
     }
 }
 
-// Dithering filter: applies ordered dithering effect using a Bayer matrix.
-// 'levels' is the number of quantization levels (minimum 2).
-// 'size' is the requested dimension for the Bayer matrix; if not a power of two, defaults to 4.
+/// Tile of thresholds an ordered dither compares pixels against.
+pub enum ThresholdMap<'a> {
+    /// Recursive Bayer matrix, with its crosshatch look
+    Bayer,
+    /// Blue noise from void-and-cluster: even, grain-free stippling
+    BlueNoise,
+    /// Dots that grow from the middle of each cell, like a print halftone
+    ClusteredDot,
+    /// Horizontal lines that thicken with darker tones
+    Lines,
+    /// A grayscale tile; its darker pixels turn bright first
+    Image(&'a GrayImage),
+}
+
+// Largest blue-noise map; generating one takes time growing with size^4,
+// about a second at 128
+const MAX_BLUE_NOISE_SIZE: u32 = 128;
+// Largest Bayer map and dot or line cell
+const MAX_MATRIX_SIZE: u32 = 256;
+
+// Ordered dithering: every pixel is compared against a threshold from a map
+// tiled over the image. 'levels' is the number of quantization levels
+// (minimum 2) and 'matrix_size' the size of the generated maps: a power of
+// two for Bayer (default 4), any size for blue noise (default 64) and for the
+// cells of clustered dots and lines (default 8).
 pub fn dither(
     img: &DynamicImage,
     levels: u8,
+    map: ThresholdMap,
     matrix_size: Option<u32>,
     point_size: Option<u32>,
     threshold_bias: Option<f32>
@@ -234,40 +182,361 @@ pub fn dither(
     }
 
     // Set defaults and validate
-    let matrix_size = matrix_size.unwrap_or(4);
-    let matrix_size = if matrix_size.is_power_of_two() { matrix_size } else { 4 };
     let point_size = point_size.unwrap_or(1).max(1); // Ensure at least 1
     let bias = threshold_bias.unwrap_or(0.0);
+    let thresholds = threshold_matrix(map, matrix_size)?;
 
-    // Generate the Bayer matrix of the specified size
-    let mut bayer_matrix = generate_bayer_matrix(matrix_size);
-    
-    // Normalize the matrix values to [0, 1] range
-    let divisor = (matrix_size * matrix_size) as f32;
-    for row in bayer_matrix.iter_mut() {
-        for val in row.iter_mut() {
-            *val = (*val + 0.5) / divisor;
+    // Dither in place on a copy at the working depth; alpha is left untouched
+    if depth::is_16bit(img) {
+        let mut output = img.to_rgba16();
+        dither_buffer(&mut output, &thresholds, levels, point_size, bias);
+        Ok(DynamicImage::ImageRgba16(output))
+    } else {
+        let mut output = img.to_rgba8();
+        dither_buffer(&mut output, &thresholds, levels, point_size, bias);
+        Ok(DynamicImage::ImageRgba8(output))
+    }
+}
+
+/// The thresholds of `map` as rows of values in (0, 1), with `matrix_size`
+/// as described for `dither`.
+pub fn threshold_matrix(map: ThresholdMap, matrix_size: Option<u32>) -> Result<Vec<Vec<f32>>, String> {
+    let limit = |size: u32, max: u32| {
+        if size > max {
+            return Err(format!("The threshold map size must be at most {}", max));
         }
+        Ok(size)
+    };
+    let thresholds = match map {
+        ThresholdMap::Bayer => {
+            let matrix_size = limit(matrix_size.unwrap_or(4), MAX_MATRIX_SIZE)?;
+            let matrix_size = if matrix_size.is_power_of_two() && matrix_size > 1 { matrix_size } else { 4 };
+            let mut bayer_matrix = generate_bayer_matrix(matrix_size);
+
+            // Normalize the matrix values to [0, 1] range
+            let divisor = (matrix_size * matrix_size) as f32;
+            for row in bayer_matrix.iter_mut() {
+                for val in row.iter_mut() {
+                    *val = (*val + 0.5) / divisor;
+                }
+            }
+            bayer_matrix
+        },
+        ThresholdMap::BlueNoise => {
+            let size = limit(matrix_size.unwrap_or(64).max(2), MAX_BLUE_NOISE_SIZE)?;
+            blue_noise(size)
+        },
+        ThresholdMap::ClusteredDot => generate_clustered_dot(limit(matrix_size.unwrap_or(8).max(1), MAX_MATRIX_SIZE)?),
+        ThresholdMap::Lines => generate_lines(limit(matrix_size.unwrap_or(8).max(1), MAX_MATRIX_SIZE)?),
+        ThresholdMap::Image(tile) => {
+            if tile.width() == 0 || tile.height() == 0 {
+                return Err("Threshold map image is empty".to_string());
+            }
+            tile.rows()
+                .map(|row| row.map(|p| (p[0] as f32 + 0.5) / 256.0).collect())
+                .collect()
+        },
+    };
+    Ok(thresholds)
+}
+
+// Thresholds (rank + 0.5) / count, for cells ordered by `rank`
+fn normalize_ranks(ranks: &[usize], size: u32) -> Vec<Vec<f32>> {
+    let count = ranks.len() as f32;
+    ranks.chunks(size as usize)
+        .map(|row| row.iter().map(|&rank| (rank as f32 + 0.5) / count).collect())
+        .collect()
+}
+
+// Blue-noise maps are slow to generate and the same for a size every time, so
+// each size is generated once per process
+fn blue_noise(size: u32) -> Vec<Vec<f32>> {
+    static MAPS: OnceLock<Mutex<HashMap<u32, Vec<Vec<f32>>>>> = OnceLock::new();
+    let maps = MAPS.get_or_init(Default::default);
+    if let Some(map) = maps.lock().unwrap().get(&size) {
+        return map.clone();
     }
+    let map = generate_blue_noise(size);
+    maps.lock().unwrap().insert(size, map.clone());
+    map
+}
 
-    let (width, height) = img.dimensions();
-    let mut output = image::DynamicImage::new_rgba8(width, height);
-    for y in 0..height {
-        for x in 0..width {
-            let pixel = img.get_pixel(x, y);
-            let mut new_pixel = pixel; // preserve alpha
-            for c in 0..3 {
-                let old_val = pixel[c] as f32;
-                let normalized = old_val / 255.0;
+// Void-and-cluster (Ulichney 1993): starting from a random pattern relaxed
+// until it is evenly spread, pixels are ranked by repeatedly removing the
+// tightest cluster and filling the largest void. Seeded by the size, so a
+// size always gives the same map.
+fn generate_blue_noise(size: u32) -> Vec<Vec<f32>> {
+    let n = size as usize;
+    let count = n * n;
+    let field = EnergyField::new(n);
+    let mut rng = StdRng::seed_from_u64(size as u64);
+
+    // Initial pattern: a tenth of the pixels, relaxed until swapping the
+    // tightest cluster into the largest void changes nothing
+    let mut pattern = vec![false; count];
+    let mut energy = vec![0.0; count];
+    let mut placed = 0;
+    while placed < (count / 10).max(1) {
+        let index = rng.gen_range(0..count);
+        if !pattern[index] {
+            field.set(&mut pattern, &mut energy, index, true);
+            placed += 1;
+        }
+    }
+    for _ in 0..count {
+        let cluster = field.tightest_cluster(&pattern, &energy);
+        field.set(&mut pattern, &mut energy, cluster, false);
+        let void = field.largest_void(&pattern, &energy);
+        field.set(&mut pattern, &mut energy, void, true);
+        if void == cluster {
+            break;
+        }
+    }
+
+    let mut ranks = vec![0; count];
+    // Ranks below the initial pattern, taking clusters away
+    let (mut ones, mut ones_energy) = (pattern.clone(), energy.clone());
+    for rank in (0..placed).rev() {
+        let cluster = field.tightest_cluster(&ones, &ones_energy);
+        field.set(&mut ones, &mut ones_energy, cluster, false);
+        ranks[cluster] = rank;
+    }
+    // Ranks above it, filling voids until every pixel is set
+    for rank in placed..count {
+        let void = field.largest_void(&pattern, &energy);
+        field.set(&mut pattern, &mut energy, void, true);
+        ranks[void] = rank;
+    }
+    normalize_ranks(&ranks, size)
+}
+
+// Gaussian-weighted density of set pixels on a torus
+struct EnergyField {
+    size: usize,
+    // (dx, dy, weight) within three sigma
+    kernel: Vec<(usize, usize, f32)>,
+}
+
+impl EnergyField {
+    const SIGMA: f32 = 1.5;
+
+    fn new(size: usize) -> Self {
+        let reach = (3.0 * Self::SIGMA).ceil() as i32;
+        let mut kernel = Vec::new();
+        for dy in -reach..=reach {
+            for dx in -reach..=reach {
+                let weight = (-((dx * dx + dy * dy) as f32) / (2.0 * Self::SIGMA * Self::SIGMA)).exp();
+                kernel.push((dx.rem_euclid(size as i32) as usize, dy.rem_euclid(size as i32) as usize, weight));
+            }
+        }
+        EnergyField { size, kernel }
+    }
+
+    fn set(&self, pattern: &mut [bool], energy: &mut [f32], index: usize, value: bool) {
+        pattern[index] = value;
+        let sign = if value { 1.0 } else { -1.0 };
+        let (x, y) = (index % self.size, index / self.size);
+        for &(dx, dy, weight) in &self.kernel {
+            energy[(y + dy) % self.size * self.size + (x + dx) % self.size] += sign * weight;
+        }
+    }
+
+    fn tightest_cluster(&self, pattern: &[bool], energy: &[f32]) -> usize {
+        (0..pattern.len())
+            .filter(|&i| pattern[i])
+            .max_by(|&a, &b| energy[a].total_cmp(&energy[b]))
+            .unwrap()
+    }
+
+    fn largest_void(&self, pattern: &[bool], energy: &[f32]) -> usize {
+        (0..pattern.len())
+            .filter(|&i| !pattern[i])
+            .min_by(|&a, &b| energy[a].total_cmp(&energy[b]))
+            .unwrap()
+    }
+}
+
+// Cells ranked by distance from their middle, so the middle needs the
+// brightest input to turn on and stays a dark dot longest
+fn generate_clustered_dot(size: u32) -> Vec<Vec<f32>> {
+    let center = (size as f32 - 1.0) / 2.0;
+    let mut order: Vec<usize> = (0..(size * size) as usize).collect();
+    let distance = |i: usize| {
+        let (x, y) = ((i as u32 % size) as f32, (i as u32 / size) as f32);
+        (x - center).powi(2) + (y - center).powi(2)
+    };
+    order.sort_by(|&a, &b| distance(a).total_cmp(&distance(b)));
+    let mut ranks = vec![0; order.len()];
+    for (rank, index) in order.into_iter().enumerate() {
+        ranks[index] = rank;
+    }
+    normalize_ranks(&ranks, size)
+}
+
+// Whole rows share a threshold, ranked by distance from the middle row
+fn generate_lines(size: u32) -> Vec<Vec<f32>> {
+    let classes = size.div_ceil(2);
+    (0..size)
+        .map(|y| {
+            let class = (2 * y as i32 - (size as i32 - 1)).unsigned_abs() / 2;
+            vec![(class as f32 + 0.5) / classes as f32; size as usize]
+        })
+        .collect()
+}
+
+fn dither_buffer<S: Sample>(
+    output: &mut RgbaBuffer<S>,
+    thresholds: &[Vec<f32>],
+    levels: u8,
+    point_size: u32,
+    bias: f32,
+) {
+    parallel::for_each_row(output, |y, row| {
+        let thresholds = &thresholds[(y / point_size) as usize % thresholds.len()];
+        for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
+            let threshold = thresholds[(x as u32 / point_size) as usize % thresholds.len()] + bias;
+            for value in pixel.iter_mut().take(3) {
+                let normalized = value.to_f32() / 255.0;
                 let scaled = normalized * ((levels - 1) as f32);
-                let threshold = bayer_matrix[((y / point_size) % matrix_size) as usize][((x / point_size) % matrix_size) as usize] + bias;
                 let new_level = (scaled + threshold).floor().min((levels - 1) as f32);
+                // Output levels stay on the 0-255 grid at any depth
                 let new_val = (new_level * (255.0 / ((levels - 1) as f32))).round();
-                new_pixel[c] = new_val.clamp(0.0, 255.0) as u8;
+                *value = S::from_f32(new_val.clamp(0.0, 255.0));
             }
-            output.put_pixel(x, y, new_pixel);
         }
+    });
+}
+/// Error-diffusion kernel: (dx, dy, weight) taps to the right of and below
+/// the current pixel, with weights summing to at most `divisor`.
+pub struct DiffusionKernel {
+    pub divisor: f32,
+    pub taps: &'static [(i32, i32, f32)],
+}
+
+impl DiffusionKernel {
+    /// The kernel called `method`, one of `DIFFUSION_METHODS`.
+    pub fn named(method: &str) -> Result<&'static DiffusionKernel, String> {
+        match method {
+            "floyd-steinberg" => Ok(&FLOYD_STEINBERG),
+            "atkinson" => Ok(&ATKINSON),
+            "jarvis-judice-ninke" => Ok(&JARVIS_JUDICE_NINKE),
+            "stucki" => Ok(&STUCKI),
+            "burkes" => Ok(&BURKES),
+            "sierra" => Ok(&SIERRA),
+            other => Err(format!("Invalid diffusion method: {} (use {})", other, DIFFUSION_METHODS.join(", "))),
+        }
+    }
+
+    /// Taps with their weights divided by the divisor.
+    pub fn normalized(&self) -> Vec<(i32, i32, f32)> {
+        self.taps.iter()
+            .map(|&(dx, dy, weight)| (dx, dy, weight / self.divisor))
+            .collect()
+    }
+}
+
+const FLOYD_STEINBERG: DiffusionKernel = DiffusionKernel {
+    divisor: 16.0,
+    taps: &[(1, 0, 7.0), (-1, 1, 3.0), (0, 1, 5.0), (1, 1, 1.0)],
+};
+
+// Only diffuses 3/4 of the error, which keeps highlights and shadows clean
+const ATKINSON: DiffusionKernel = DiffusionKernel {
+    divisor: 8.0,
+    taps: &[(1, 0, 1.0), (2, 0, 1.0), (-1, 1, 1.0), (0, 1, 1.0), (1, 1, 1.0), (0, 2, 1.0)],
+};
+
+const JARVIS_JUDICE_NINKE: DiffusionKernel = DiffusionKernel {
+    divisor: 48.0,
+    taps: &[
+        (1, 0, 7.0), (2, 0, 5.0),
+        (-2, 1, 3.0), (-1, 1, 5.0), (0, 1, 7.0), (1, 1, 5.0), (2, 1, 3.0),
+        (-2, 2, 1.0), (-1, 2, 3.0), (0, 2, 5.0), (1, 2, 3.0), (2, 2, 1.0),
+    ],
+};
+
+const STUCKI: DiffusionKernel = DiffusionKernel {
+    divisor: 42.0,
+    taps: &[
+        (1, 0, 8.0), (2, 0, 4.0),
+        (-2, 1, 2.0), (-1, 1, 4.0), (0, 1, 8.0), (1, 1, 4.0), (2, 1, 2.0),
+        (-2, 2, 1.0), (-1, 2, 2.0), (0, 2, 4.0), (1, 2, 2.0), (2, 2, 1.0),
+    ],
+};
+
+const BURKES: DiffusionKernel = DiffusionKernel {
+    divisor: 32.0,
+    taps: &[
+        (1, 0, 8.0), (2, 0, 4.0),
+        (-2, 1, 2.0), (-1, 1, 4.0), (0, 1, 8.0), (1, 1, 4.0), (2, 1, 2.0),
+    ],
+};
+
+const SIERRA: DiffusionKernel = DiffusionKernel {
+    divisor: 32.0,
+    taps: &[
+        (1, 0, 5.0), (2, 0, 3.0),
+        (-2, 1, 2.0), (-1, 1, 4.0), (0, 1, 5.0), (1, 1, 4.0), (2, 1, 2.0),
+        (-1, 2, 2.0), (0, 2, 3.0), (1, 2, 2.0),
+    ],
+};
+
+/// Names accepted by `error_diffusion`.
+pub const DIFFUSION_METHODS: &[&str] = &["floyd-steinberg", "atkinson", "jarvis-judice-ninke", "stucki", "burkes", "sierra"];
+
+/// Error-diffusion dithering: each channel is quantized to `levels` values and
+/// the rounding error is pushed onto the neighbors still to be visited.
+/// `serpentine` scans every other row right to left, which breaks up the
+/// diagonal worms a fixed direction leaves behind. Alpha is left untouched.
+pub fn error_diffusion(img: &DynamicImage, levels: u8, method: &str, serpentine: bool) -> Result<DynamicImage, String> {
+    if levels < 2 {
+        return Err("Levels must be at least 2".to_string());
     }
+    let kernel = DiffusionKernel::named(method)?;
 
-    Ok(output)
-}
\ No newline at end of file
+    if depth::is_16bit(img) {
+        let mut output = img.to_rgba16();
+        diffuse_buffer(&mut output, kernel, levels, serpentine);
+        Ok(DynamicImage::ImageRgba16(output))
+    } else {
+        let mut output = img.to_rgba8();
+        diffuse_buffer(&mut output, kernel, levels, serpentine);
+        Ok(DynamicImage::ImageRgba8(output))
+    }
+}
+
+// Runs row by row, since every pixel depends on the error of those before it
+fn diffuse_buffer<S: Sample>(output: &mut RgbaBuffer<S>, kernel: &DiffusionKernel, levels: u8, serpentine: bool) {
+    let (width, height) = output.dimensions();
+    let (width, height) = (width as usize, height as usize);
+    let step = 255.0 / (levels - 1) as f32;
+    let taps = kernel.normalized();
+
+    // Error-adjusted RGB on the 0-255 scale
+    let mut values: Vec<f32> = output.pixels()
+        .flat_map(|p| [p[0].to_f32(), p[1].to_f32(), p[2].to_f32()])
+        .collect();
+    let pixels: &mut [S] = output;
+
+    for y in 0..height {
+        let reverse = serpentine && y % 2 == 1;
+        for i in 0..width {
+            let x = if reverse { width - 1 - i } else { i };
+            let index = y * width + x;
+            for c in 0..3 {
+                let old = values[index * 3 + c];
+                // Output levels stay on the 0-255 grid at any depth
+                let new = ((old / step).round().clamp(0.0, (levels - 1) as f32) * step).round();
+                pixels[index * 4 + c] = S::from_f32(new);
+                let error = old - new;
+                for &(dx, dy, weight) in &taps {
+                    let nx = x as i32 + if reverse { -dx } else { dx };
+                    let ny = y + dy as usize;
+                    if nx >= 0 && (nx as usize) < width && ny < height {
+                        values[(ny * width + nx as usize) * 3 + c] += error * weight;
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use image::{DynamicImage, Rgba};
+
+use crate::depth::{self, RgbaBuffer, Sample};
+use crate::parallel;
+
+/// Names of the dot shapes.
+pub const SHAPE_NAMES: &[&str] = &["round", "ellipse", "line"];
+
+// Samples per side of every pixel, to antialias the dot edges
+const SUPERSAMPLING: u32 = 3;
+// Resolution of the spot function table
+const SPOT_SAMPLES: usize = 128;
+const COVERAGE_STEPS: usize = 1024;
+
+/// How the dots of a screen grow from light to dark.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DotShape {
+    /// Circles that merge into a checkerboard at 50% and close to white
+    /// holes in the shadows
+    Round,
+    /// Elongated dots that join into chains in the midtones
+    Ellipse,
+    /// Parallel lines that thicken with the ink
+    Line,
+}
+
+impl DotShape {
+    /// One of `SHAPE_NAMES`.
+    pub fn named(name: &str) -> Result<DotShape, String> {
+        match name {
+            "round" => Ok(DotShape::Round),
+            "ellipse" => Ok(DotShape::Ellipse),
+            "line" => Ok(DotShape::Line),
+            _ => Err(format!("Unknown dot shape: {} (use {})", name, SHAPE_NAMES.join(", "))),
+        }
+    }
+
+    // Spot function over a cell, with `u` and `v` in [-0.5, 0.5): the lower
+    // the value, the earlier that spot takes ink
+    fn spot(self, u: f32, v: f32) -> f32 {
+        match self {
+            DotShape::Round => u * u + v * v,
+            DotShape::Ellipse => u * u + 2.0 * v * v,
+            DotShape::Line => v.abs(),
+        }
+    }
+
+    // Spot values below which a cell has the given share of ink, so every
+    // shape covers exactly as much of the cell as the tone asks for
+    fn thresholds(self) -> Vec<f32> {
+        let mut values: Vec<f32> = (0..SPOT_SAMPLES * SPOT_SAMPLES)
+            .map(|i| {
+                let u = ((i % SPOT_SAMPLES) as f32 + 0.5) / SPOT_SAMPLES as f32 - 0.5;
+                let v = ((i / SPOT_SAMPLES) as f32 + 0.5) / SPOT_SAMPLES as f32 - 0.5;
+                self.spot(u, v)
+            })
+            .collect();
+        values.sort_by(f32::total_cmp);
+        (0..=COVERAGE_STEPS)
+            .map(|step| match step {
+                0 => f32::NEG_INFINITY,
+                COVERAGE_STEPS => f32::INFINITY,
+                _ => values[step * values.len() / COVERAGE_STEPS],
+            })
+            .collect()
+    }
+}
+
+/// The inks of the print.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Separation {
+    /// Cyan, magenta, yellow and black screens at the given angles, with
+    /// `black_generation` of the shared gray printed in black instead of the
+    /// colored inks (0 prints no black, 1 as much as possible)
+    Cmyk { angles: [f32; 4], black_generation: f32 },
+    /// A single black screen of the luminance
+    Monochrome { angle: f32 },
+}
+
+// A rotated grid of cells
+struct Screen {
+    cos: f32,
+    sin: f32,
+}
+
+impl Screen {
+    fn new(angle: f32) -> Screen {
+        let (sin, cos) = angle.to_radians().sin_cos();
+        Screen { cos, sin }
+    }
+}
+
+/// Print-style AM halftone: the image is separated into inks and every ink is
+/// printed as a screen of dots `cell_size` pixels apart, rotated to its angle,
+/// whose size follows the tone. The screens are then overprinted on white
+/// paper. `cell_size` is the resolution divided by the lines per inch, so
+/// 85 lpi at 300 dpi is a cell of about 3.5 pixels. Alpha is left untouched.
+pub fn halftone(img: &DynamicImage, cell_size: f32, shape: DotShape, separation: Separation) -> Result<DynamicImage, String> {
+    if cell_size.is_nan() || cell_size < 1.0 {
+        return Err("The cell size must be at least 1 pixel".to_string());
+    }
+    if let Separation::Cmyk { black_generation, .. } = separation {
+        if !(0.0..=1.0).contains(&black_generation) {
+            return Err("Black generation must be between 0 and 1".to_string());
+        }
+    }
+
+    if depth::is_16bit(img) {
+        Ok(DynamicImage::ImageRgba16(halftone_buffer(&depth::rgba16(img), cell_size, shape, separation)))
+    } else {
+        Ok(DynamicImage::ImageRgba8(halftone_buffer(&parallel::rgba(img), cell_size, shape, separation)))
+    }
+}
+
+// Ink coverages of a color on the 0-1 scale, in the order of the screens
+fn separate(rgb: [f32; 3], separation: Separation) -> [f32; 4] {
+    let [r, g, b] = rgb.map(|c| (c / 255.0).clamp(0.0, 1.0));
+    match separation {
+        Separation::Cmyk { black_generation, .. } => {
+            let k = black_generation * (1.0 - r.max(g).max(b));
+            if k >= 1.0 {
+                return [0.0, 0.0, 0.0, 1.0];
+            }
+            [(1.0 - r - k) / (1.0 - k), (1.0 - g - k) / (1.0 - k), (1.0 - b - k) / (1.0 - k), k]
+        },
+        Separation::Monochrome { .. } => [1.0 - (0.2126 * r + 0.7152 * g + 0.0722 * b), 0.0, 0.0, 0.0],
+    }
+}
+
+fn halftone_buffer<S: Sample>(source: &RgbaBuffer<S>, cell_size: f32, shape: DotShape, separation: Separation) -> RgbaBuffer<S> {
+    let (width, height) = source.dimensions();
+    let screens: Vec<Screen> = match separation {
+        Separation::Cmyk { angles, .. } => angles.iter().map(|&a| Screen::new(a)).collect(),
+        Separation::Monochrome { angle } => vec![Screen::new(angle)],
+    };
+    let thresholds = shape.thresholds();
+    let inks: Vec<[f32; 4]> = source.pixels()
+        .map(|p| separate([p[0].to_f32(), p[1].to_f32(), p[2].to_f32()], separation))
+        .collect();
+
+    // Every dot takes the ink of its cell's center, so it stays whole
+    let ink_at = |screen: &Screen, index: usize, x: f32, y: f32| {
+        let u = (x * screen.cos + y * screen.sin) / cell_size;
+        let v = (y * screen.cos - x * screen.sin) / cell_size;
+        let (center_u, center_v) = (u.floor() + 0.5, v.floor() + 0.5);
+        let cx = ((center_u * screen.cos - center_v * screen.sin) * cell_size).floor().clamp(0.0, width as f32 - 1.0) as usize;
+        let cy = ((center_u * screen.sin + center_v * screen.cos) * cell_size).floor().clamp(0.0, height as f32 - 1.0) as usize;
+        let ink = inks[cy * width as usize + cx][index];
+        let threshold = thresholds[(ink * COVERAGE_STEPS as f32).round() as usize];
+        shape.spot(u - center_u, v - center_v) < threshold
+    };
+
+    let samples = (SUPERSAMPLING * SUPERSAMPLING) as f32;
+    parallel::from_fn(width, height, |x, y| {
+        let mut coverage = [0.0f32; 4];
+        for sy in 0..SUPERSAMPLING {
+            for sx in 0..SUPERSAMPLING {
+                let px = x as f32 + (sx as f32 + 0.5) / SUPERSAMPLING as f32;
+                let py = y as f32 + (sy as f32 + 0.5) / SUPERSAMPLING as f32;
+                for (index, screen) in screens.iter().enumerate() {
+                    if ink_at(screen, index, px, py) {
+                        coverage[index] += 1.0 / samples;
+                    }
+                }
+            }
+        }
+
+        // Inks filter the light of the paper, so overprints multiply
+        let [c, m, y_ink, k] = coverage;
+        let rgb = match separation {
+            Separation::Cmyk { .. } => [(1.0 - c) * (1.0 - k), (1.0 - m) * (1.0 - k), (1.0 - y_ink) * (1.0 - k)],
+            Separation::Monochrome { .. } => [1.0 - c; 3],
+        };
+        let alpha = source.get_pixel(x, y)[3];
+        Rgba([S::from_f32(rgb[0] * 255.0), S::from_f32(rgb[1] * 255.0), S::from_f32(rgb[2] * 255.0), alpha])
+    })
+}
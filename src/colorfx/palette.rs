@@ -0,0 +1,309 @@
+use image::{DynamicImage, Rgb};
+
+use crate::colorfx::utils::{lab_distance_squared, lab_to_rgb, rgb_to_lab};
+use crate::colorfx::{self, DiffusionKernel, ThresholdMap};
+use crate::depth::{self, RgbaBuffer, Sample};
+use crate::parallel;
+
+/// Names of the built-in palettes.
+pub const BUILTIN_NAMES: &[&str] = &["gameboy", "cga", "cga-mode4", "pico-8", "c64", "monochrome"];
+
+fn builtin_colors(name: &str) -> Option<&'static [u32]> {
+    match name {
+        "gameboy" => Some(&[0x0f380f, 0x306230, 0x8bac0f, 0x9bbc0f]),
+        "cga" => Some(&[
+            0x000000, 0x0000aa, 0x00aa00, 0x00aaaa, 0xaa0000, 0xaa00aa, 0xaa5500, 0xaaaaaa,
+            0x555555, 0x5555ff, 0x55ff55, 0x55ffff, 0xff5555, 0xff55ff, 0xffff55, 0xffffff,
+        ]),
+        // Mode 4, palette 1 in high intensity
+        "cga-mode4" => Some(&[0x000000, 0x55ffff, 0xff55ff, 0xffffff]),
+        "pico-8" => Some(&[
+            0x000000, 0x1d2b53, 0x7e2553, 0x008751, 0xab5236, 0x5f574f, 0xc2c3c7, 0xfff1e8,
+            0xff004d, 0xffa300, 0xffec27, 0x00e436, 0x29adff, 0x83769c, 0xff77a8, 0xffccaa,
+        ]),
+        // Pepto's measured VIC-II colors
+        "c64" => Some(&[
+            0x000000, 0xffffff, 0x68372b, 0x70a4b2, 0x6f3d86, 0x588d43, 0x352879, 0xb8c76f,
+            0x6f4f25, 0x433900, 0x9a6759, 0x444444, 0x6c6c6c, 0x9ad284, 0x6c5eb5, 0x959595,
+        ]),
+        "monochrome" => Some(&[0x000000, 0xffffff]),
+        _ => None,
+    }
+}
+
+/// A fixed set of colors to quantize to, matched by perceptual distance.
+#[derive(Debug, Clone)]
+pub struct Palette {
+    colors: Vec<Rgb<u8>>,
+    lab: Vec<[f32; 3]>,
+}
+
+impl Palette {
+    pub fn new(colors: Vec<Rgb<u8>>) -> Result<Palette, String> {
+        if colors.is_empty() {
+            return Err("A palette needs at least one color".to_string());
+        }
+        let lab = colors.iter().map(|c| rgb_to_lab(c[0] as f32, c[1] as f32, c[2] as f32)).collect();
+        Ok(Palette { colors, lab })
+    }
+
+    /// One of `BUILTIN_NAMES`.
+    pub fn builtin(name: &str) -> Result<Palette, String> {
+        let colors = builtin_colors(name)
+            .ok_or_else(|| format!("Unknown palette: {} (use {})", name, BUILTIN_NAMES.join(", ")))?;
+        Palette::new(colors.iter().map(|&c| Rgb([(c >> 16) as u8, (c >> 8) as u8, c as u8])).collect())
+    }
+
+    pub fn colors(&self) -> &[Rgb<u8>] {
+        &self.colors
+    }
+
+    /// Index of the color closest in Lab to `rgb`, given on the 0-255 scale.
+    pub fn nearest(&self, rgb: [f32; 3]) -> usize {
+        let lab = rgb_to_lab(rgb[0], rgb[1], rgb[2]);
+        nearest_lab(&self.lab, lab)
+    }
+
+    // Mean RGB distance from each color to its closest neighbor: about how far
+    // ordered dithering has to push a pixel to reach the next color
+    fn spacing(&self) -> f32 {
+        if self.colors.len() < 2 {
+            return 0.0;
+        }
+        let distance = |a: &Rgb<u8>, b: &Rgb<u8>| {
+            (0..3).map(|c| (a[c] as f32 - b[c] as f32).powi(2)).sum::<f32>().sqrt()
+        };
+        let total: f32 = self.colors.iter().enumerate()
+            .map(|(i, a)| self.colors.iter().enumerate()
+                .filter(|&(j, _)| j != i)
+                .map(|(_, b)| distance(a, b))
+                .fold(f32::INFINITY, f32::min))
+            .sum();
+        total / self.colors.len() as f32
+    }
+}
+
+fn nearest_lab(palette: &[[f32; 3]], lab: [f32; 3]) -> usize {
+    palette.iter()
+        .enumerate()
+        .min_by(|(_, a), (_, b)| lab_distance_squared(**a, lab).total_cmp(&lab_distance_squared(**b, lab)))
+        .map(|(i, _)| i)
+        .unwrap()
+}
+
+// Pixels sampled for palette extraction; enough for a stable palette while
+// keeping k-means quick on large images
+const MAX_SAMPLES: usize = 32768;
+
+/// Extracts a palette of up to `size` colors from `img` with "median-cut" or
+/// "k-means". Fully transparent pixels are ignored.
+pub fn extract(img: &DynamicImage, size: usize, method: &str) -> Result<Palette, String> {
+    let samples = sample(img, size)?;
+    let colors = match method {
+        "median-cut" => median_cut(samples, size),
+        "k-means" => k_means(&samples, median_cut(samples.clone(), size)).0,
+        other => return Err(format!("Invalid palette extraction: {} (use median-cut or k-means)", other)),
+    };
+    Palette::new(colors)
+}
+
+/// The `count` dominant colors of `img` found by k-means in Lab, with the
+/// share of the visible pixels closest to each, most common first.
+pub fn dominant_colors(img: &DynamicImage, count: usize) -> Result<Vec<(Rgb<u8>, f32)>, String> {
+    let samples = sample(img, count)?;
+    let (colors, assignment) = k_means(&samples, median_cut(samples.clone(), count));
+    let mut members = vec![0usize; colors.len()];
+    for &assigned in &assignment {
+        members[assigned] += 1;
+    }
+    let mut dominant: Vec<(Rgb<u8>, f32)> = colors.into_iter()
+        .zip(members)
+        .filter(|&(_, n)| n > 0)
+        .map(|(color, n)| (color, n as f32 / samples.len() as f32))
+        .collect();
+    dominant.sort_by(|a, b| b.1.total_cmp(&a.1));
+    Ok(dominant)
+}
+
+// Up to MAX_SAMPLES visible pixels, evenly spread over the image
+fn sample(img: &DynamicImage, size: usize) -> Result<Vec<[u8; 3]>, String> {
+    if size == 0 {
+        return Err("Palette size must be at least 1".to_string());
+    }
+    let rgba = parallel::rgba(img);
+    let visible = rgba.pixels().filter(|p| p[3] > 0).count();
+    let stride = (visible / MAX_SAMPLES).max(1);
+    let samples: Vec<[u8; 3]> = rgba.pixels()
+        .filter(|p| p[3] > 0)
+        .step_by(stride)
+        .map(|p| [p[0], p[1], p[2]])
+        .collect();
+    if samples.is_empty() {
+        return Err("The image has no visible pixels".to_string());
+    }
+    Ok(samples)
+}
+
+// Repeatedly splits the box of colors with the widest channel at its median
+fn median_cut(samples: Vec<[u8; 3]>, size: usize) -> Vec<Rgb<u8>> {
+    let widest = |colors: &[[u8; 3]]| {
+        (0..3)
+            .map(|c| {
+                let (min, max) = colors.iter().fold((255, 0), |(min, max), p| (p[c].min(min), p[c].max(max)));
+                (c, max - min)
+            })
+            .max_by_key(|&(_, range)| range)
+            .unwrap()
+    };
+
+    let mut boxes = vec![samples];
+    while boxes.len() < size {
+        let (index, (channel, range)) = boxes.iter()
+            .map(|colors| widest(colors))
+            .enumerate()
+            .max_by_key(|&(_, (_, range))| range)
+            .unwrap();
+        if range == 0 {
+            break;
+        }
+        let mut lower = boxes.swap_remove(index);
+        lower.sort_unstable_by_key(|p| p[channel]);
+        let upper = lower.split_off(lower.len() / 2);
+        boxes.push(lower);
+        boxes.push(upper);
+    }
+    boxes.iter().map(|colors| average(colors.iter())).collect()
+}
+
+fn average<'a>(colors: impl Iterator<Item = &'a [u8; 3]>) -> Rgb<u8> {
+    let (mut sum, mut count) = ([0u64; 3], 0u64);
+    for color in colors {
+        for c in 0..3 {
+            sum[c] += color[c] as u64;
+        }
+        count += 1;
+    }
+    let count = count.max(1);
+    Rgb(sum.map(|s| ((s + count / 2) / count) as u8))
+}
+
+// Lloyd's algorithm in Lab, starting from the median-cut colors. Returns the
+// centers and the center each sample belongs to.
+fn k_means(samples: &[[u8; 3]], initial: Vec<Rgb<u8>>) -> (Vec<Rgb<u8>>, Vec<usize>) {
+    const MAX_ITERATIONS: usize = 20;
+    let labs: Vec<[f32; 3]> = samples.iter().map(|p| rgb_to_lab(p[0] as f32, p[1] as f32, p[2] as f32)).collect();
+    let mut centers: Vec<[f32; 3]> = initial.iter().map(|c| rgb_to_lab(c[0] as f32, c[1] as f32, c[2] as f32)).collect();
+    let mut assignment = vec![usize::MAX; samples.len()];
+
+    for _ in 0..MAX_ITERATIONS {
+        let mut changed = false;
+        for (lab, assigned) in labs.iter().zip(assignment.iter_mut()) {
+            let nearest = nearest_lab(&centers, *lab);
+            changed |= nearest != *assigned;
+            *assigned = nearest;
+        }
+        if !changed {
+            break;
+        }
+        let mut sums = vec![([0.0f64; 3], 0usize); centers.len()];
+        for (lab, &assigned) in labs.iter().zip(&assignment) {
+            let (sum, count) = &mut sums[assigned];
+            for c in 0..3 {
+                sum[c] += lab[c] as f64;
+            }
+            *count += 1;
+        }
+        // A center that lost all its members stays where it is
+        for (center, (sum, count)) in centers.iter_mut().zip(sums) {
+            if count > 0 {
+                *center = sum.map(|s| (s / count as f64) as f32);
+            }
+        }
+    }
+    let colors = centers.into_iter().map(|lab| Rgb(lab_to_rgb(lab).map(|v| v.round() as u8))).collect();
+    (colors, assignment)
+}
+
+/// How `quantize` spreads the difference between pixels and palette colors.
+pub enum PaletteDither<'a> {
+    /// Every pixel takes its closest color
+    None,
+    /// Pixels are nudged by a tiled threshold map before matching
+    Ordered { map: ThresholdMap<'a>, matrix_size: Option<u32> },
+    /// The matching error is pushed onto the neighbors still to be visited
+    Diffusion { kernel: &'static DiffusionKernel, serpentine: bool },
+}
+
+/// Replaces every pixel with a color of `palette`. `strength` scales the
+/// dithering, 0 turning it off and 1 being the usual amount. Alpha is left
+/// untouched.
+pub fn quantize(img: &DynamicImage, palette: &Palette, dither: PaletteDither, strength: f32) -> Result<DynamicImage, String> {
+    if depth::is_16bit(img) {
+        let mut output = img.to_rgba16();
+        quantize_buffer(&mut output, palette, dither, strength)?;
+        Ok(DynamicImage::ImageRgba16(output))
+    } else {
+        let mut output = img.to_rgba8();
+        quantize_buffer(&mut output, palette, dither, strength)?;
+        Ok(DynamicImage::ImageRgba8(output))
+    }
+}
+
+fn quantize_buffer<S: Sample>(output: &mut RgbaBuffer<S>, palette: &Palette, dither: PaletteDither, strength: f32) -> Result<(), String> {
+    let set = |pixel: &mut [S], index: usize| {
+        for (value, &color) in pixel.iter_mut().zip(&palette.colors[index].0) {
+            *value = S::from_f32(color as f32);
+        }
+    };
+    let rgb = |pixel: &[S]| [pixel[0].to_f32(), pixel[1].to_f32(), pixel[2].to_f32()];
+
+    match dither {
+        PaletteDither::None => parallel::for_each_row(output, |_, row| {
+            for pixel in row.chunks_exact_mut(4) {
+                set(pixel, palette.nearest(rgb(pixel)));
+            }
+        }),
+        PaletteDither::Ordered { map, matrix_size } => {
+            let thresholds = colorfx::threshold_matrix(map, matrix_size)?;
+            let spread = palette.spacing() * strength;
+            parallel::for_each_row(output, |y, row| {
+                let thresholds = &thresholds[y as usize % thresholds.len()];
+                for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
+                    let offset = (thresholds[x % thresholds.len()] - 0.5) * spread;
+                    set(pixel, palette.nearest(rgb(pixel).map(|v| v + offset)));
+                }
+            });
+        },
+        PaletteDither::Diffusion { kernel, serpentine } => {
+            let (width, height) = output.dimensions();
+            let (width, height) = (width as usize, height as usize);
+            let taps = kernel.normalized();
+            let mut values: Vec<[f32; 3]> = output.pixels().map(|p| rgb(&p.0)).collect();
+            let pixels: &mut [S] = output;
+
+            // Row by row, since every pixel depends on the error of those before it
+            for y in 0..height {
+                let reverse = serpentine && y % 2 == 1;
+                for i in 0..width {
+                    let x = if reverse { width - 1 - i } else { i };
+                    let index = y * width + x;
+                    let old = values[index];
+                    let chosen = palette.nearest(old);
+                    set(&mut pixels[index * 4..index * 4 + 4], chosen);
+                    let error: [f32; 3] = std::array::from_fn(|c| (old[c] - palette.colors[chosen][c] as f32) * strength);
+                    for &(dx, dy, weight) in &taps {
+                        let nx = x as i32 + if reverse { -dx } else { dx };
+                        let ny = y + dy as usize;
+                        if nx >= 0 && (nx as usize) < width && ny < height {
+                            let target = &mut values[ny * width + nx as usize];
+                            for c in 0..3 {
+                                target[c] += error[c] * weight;
+                            }
+                        }
+                    }
+                }
+            }
+        },
+    }
+    Ok(())
+}
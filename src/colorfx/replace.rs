@@ -0,0 +1,145 @@
+use std::f32::consts::PI;
+
+use image::{DynamicImage, Pixel, Rgb, Rgba};
+
+use crate::colorfx::utils::{delta_e_2000, oklab_to_rgb, parse_color, rgb_to_lab, rgb_to_oklab};
+use crate::depth::{self, RgbaBuffer, Sample};
+use crate::parallel;
+
+/// Names of the color difference formulas.
+pub const METRICS: &[&str] = &["ciede2000", "oklab"];
+
+/// Names of the replacement modes.
+pub const MODES: &[&str] = &["color", "hue"];
+
+/// How the distance from a pixel to the target color is measured. Both give
+/// roughly 1 for a just noticeable difference and 100 for black to white.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Metric {
+    /// CIE delta E 2000 on Lab, the industry standard
+    Ciede2000,
+    /// Euclidean OKLab distance times 100, cheaper and nearly as even
+    Oklab,
+}
+
+impl Metric {
+    /// One of `METRICS`.
+    pub fn named(name: &str) -> Result<Metric, String> {
+        match name {
+            "ciede2000" => Ok(Metric::Ciede2000),
+            "oklab" => Ok(Metric::Oklab),
+            _ => Err(format!("Unknown color metric: {} (use {})", name, METRICS.join(", "))),
+        }
+    }
+
+    fn distance(self, target: Rgb<u8>) -> impl Fn([f32; 3]) -> f32 + Sync {
+        let [r, g, b] = target.0.map(|c| c as f32);
+        let (lab, oklab) = (rgb_to_lab(r, g, b), rgb_to_oklab(r, g, b));
+        move |[r, g, b]: [f32; 3]| match self {
+            Metric::Ciede2000 => delta_e_2000(rgb_to_lab(r, g, b), lab),
+            Metric::Oklab => {
+                let pixel = rgb_to_oklab(r, g, b);
+                100.0 * (0..3).map(|c| (pixel[c] - oklab[c]).powi(2)).sum::<f32>().sqrt()
+            },
+        }
+    }
+}
+
+/// What happens to the pixels that match the target color.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplaceMode {
+    /// Paints them with the replacement color
+    Color,
+    /// Turns their hue by the angle from the target to the replacement hue,
+    /// keeping their lightness and chroma so shading and texture survive
+    Hue,
+}
+
+impl ReplaceMode {
+    /// One of `MODES`.
+    pub fn named(name: &str) -> Result<ReplaceMode, String> {
+        match name {
+            "color" => Ok(ReplaceMode::Color),
+            "hue" => Ok(ReplaceMode::Hue),
+            _ => Err(format!("Unknown replacement mode: {} (use {})", name, MODES.join(", "))),
+        }
+    }
+}
+
+/// Replaces the colors within `tolerance` of `target_color` by
+/// `replacement_color`. Past the tolerance the replacement fades out over
+/// `softness` more units of distance, so edges and gradients blend instead of
+/// breaking into hard patches. Colors take any syntax `parse_color` accepts.
+/// Alpha is left untouched.
+pub fn color_replacer(
+    img: &DynamicImage,
+    target_color: &str,
+    replacement_color: &str,
+    tolerance: f32,
+    softness: f32,
+    metric: Metric,
+    mode: ReplaceMode,
+) -> Result<DynamicImage, String> {
+    if tolerance < 0.0 || softness < 0.0 {
+        return Err("The tolerance and softness must not be negative".to_string());
+    }
+    let target = parse_color(target_color)?.to_rgb();
+    let replacement = parse_color(replacement_color)?.to_rgb();
+    let replacer = Replacer { target, replacement, tolerance, softness, metric, mode };
+    Ok(if depth::is_16bit(img) {
+        DynamicImage::ImageRgba16(replacer.apply(&depth::rgba16(img)))
+    } else {
+        DynamicImage::ImageRgba8(replacer.apply(&parallel::rgba(img)))
+    })
+}
+
+struct Replacer {
+    target: Rgb<u8>,
+    replacement: Rgb<u8>,
+    tolerance: f32,
+    softness: f32,
+    metric: Metric,
+    mode: ReplaceMode,
+}
+
+impl Replacer {
+    // How much of the replacement a color at `distance` gets, easing from 1
+    // at the tolerance to 0 at the end of the soft edge
+    fn weight(&self, distance: f32) -> f32 {
+        if distance <= self.tolerance {
+            1.0
+        } else if distance >= self.tolerance + self.softness {
+            0.0
+        } else {
+            let t = (distance - self.tolerance) / self.softness;
+            1.0 - t * t * (3.0 - 2.0 * t)
+        }
+    }
+
+    fn apply<S: Sample>(&self, source: &RgbaBuffer<S>) -> RgbaBuffer<S> {
+        let distance = self.metric.distance(self.target);
+        let oklab = |color: Rgb<u8>| rgb_to_oklab(color[0] as f32, color[1] as f32, color[2] as f32);
+        let (target, replacement) = (oklab(self.target), oklab(self.replacement));
+        let hue = |lab: [f32; 3]| lab[2].atan2(lab[1]);
+        // Shortest turn from the target hue to the replacement hue
+        let turn = (hue(replacement) - hue(target) + PI).rem_euclid(2.0 * PI) - PI;
+
+        parallel::map_pixels(source, |pixel| {
+            let rgb = [pixel[0].to_f32(), pixel[1].to_f32(), pixel[2].to_f32()];
+            let weight = self.weight(distance(rgb));
+            if weight == 0.0 {
+                return *pixel;
+            }
+            let lab = rgb_to_oklab(rgb[0], rgb[1], rgb[2]);
+            let replaced = match self.mode {
+                ReplaceMode::Color => [0, 1, 2].map(|c| lab[c] + (replacement[c] - lab[c]) * weight),
+                ReplaceMode::Hue => {
+                    let (chroma, angle) = (lab[1].hypot(lab[2]), hue(lab) + turn * weight);
+                    [lab[0], chroma * angle.cos(), chroma * angle.sin()]
+                },
+            };
+            let [r, g, b] = oklab_to_rgb(replaced).map(S::from_f32_rounded);
+            Rgba([r, g, b, pixel[3]])
+        })
+    }
+}
@@ -0,0 +1,116 @@
+use image::{DynamicImage, Rgba};
+
+use crate::depth::{self, RgbaBuffer, Sample};
+use crate::parallel;
+
+/// A curve control point as (input, output) on the 0-255 scale.
+pub type ControlPoint = (f32, f32);
+
+/// A tone curve through control points on the 0-255 scale, interpolated with
+/// a monotone cubic spline: between two points it never overshoots, so a
+/// rising curve cannot fold tones back. Inputs beyond the first and last
+/// points keep those points' outputs.
+#[derive(Debug, Clone)]
+pub struct Curve {
+    xs: Vec<f32>,
+    ys: Vec<f32>,
+    slopes: Vec<f32>,
+}
+
+impl Curve {
+    /// A curve through `points`, in any order.
+    pub fn new(points: &[ControlPoint]) -> Result<Curve, String> {
+        if points.len() < 2 {
+            return Err("A curve needs at least two control points".to_string());
+        }
+        let mut points = points.to_vec();
+        points.sort_by(|a, b| a.0.total_cmp(&b.0));
+        if let Some(pair) = points.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+            return Err(format!("Two control points share the input {}", pair[0].0));
+        }
+        let (xs, ys): (Vec<f32>, Vec<f32>) = points.into_iter().unzip();
+
+        // Fritsch-Butland tangents: a weighted harmonic mean of the secants
+        // around each point, flat where the curve turns
+        let secants: Vec<f32> = (0..xs.len() - 1).map(|i| (ys[i + 1] - ys[i]) / (xs[i + 1] - xs[i])).collect();
+        let mut slopes = vec![0.0; xs.len()];
+        slopes[0] = secants[0];
+        slopes[xs.len() - 1] = secants[secants.len() - 1];
+        for i in 1..xs.len() - 1 {
+            let (before, after) = (secants[i - 1], secants[i]);
+            if before * after > 0.0 {
+                let (h0, h1) = (xs[i] - xs[i - 1], xs[i + 1] - xs[i]);
+                slopes[i] = 3.0 * (h0 + h1) / ((2.0 * h1 + h0) / before + (h1 + 2.0 * h0) / after);
+            }
+        }
+        Ok(Curve { xs, ys, slopes })
+    }
+
+    /// Straight line from black to white, which changes nothing.
+    pub fn identity() -> Curve {
+        Curve::new(&[(0.0, 0.0), (255.0, 255.0)]).unwrap()
+    }
+
+    pub fn eval(&self, x: f32) -> f32 {
+        let last = self.xs.len() - 1;
+        if x <= self.xs[0] {
+            return self.ys[0];
+        }
+        if x >= self.xs[last] {
+            return self.ys[last];
+        }
+        let i = self.xs.partition_point(|&p| p <= x) - 1;
+        let h = self.xs[i + 1] - self.xs[i];
+        let t = (x - self.xs[i]) / h;
+        let (t2, t3) = (t * t, t * t * t);
+        (2.0 * t3 - 3.0 * t2 + 1.0) * self.ys[i]
+            + (t3 - 2.0 * t2 + t) * h * self.slopes[i]
+            + (-2.0 * t3 + 3.0 * t2) * self.ys[i + 1]
+            + (t3 - t2) * h * self.slopes[i + 1]
+    }
+}
+
+/// Runs every channel through its own curve of `channels` (red, green, blue)
+/// and then through `master`. Alpha is left untouched.
+pub fn curves(img: &DynamicImage, master: &Curve, channels: &[Curve; 3]) -> DynamicImage {
+    map_tones(img, |channel, value| master.eval(channels[channel].eval(value)))
+}
+
+/// Levels on the 0-255 scale: the input range `in_black..in_white` is
+/// stretched over `out_black..out_white`, with `gamma` above 1 brightening
+/// the midtones and below 1 darkening them. Values outside the input range
+/// are clipped. Alpha is left untouched.
+pub fn levels(img: &DynamicImage, in_black: f32, in_white: f32, gamma: f32, out_black: f32, out_white: f32) -> Result<DynamicImage, String> {
+    if in_white <= in_black {
+        return Err(format!("The input white point ({}) must be above the black point ({})", in_white, in_black));
+    }
+    if gamma <= 0.0 {
+        return Err("Gamma must be positive".to_string());
+    }
+    let level = |c: f32| {
+        let t = ((c - in_black) / (in_white - in_black)).clamp(0.0, 1.0);
+        out_black + t.powf(1.0 / gamma) * (out_white - out_black)
+    };
+    Ok(map_tones(img, |_, value| level(value)))
+}
+
+// Maps the color channels at the image's own depth, rounding to the nearest
+// value so that curves through the identity give back the input exactly
+fn map_tones<F>(img: &DynamicImage, f: F) -> DynamicImage
+where
+    F: Fn(usize, f32) -> f32 + Sync,
+{
+    if depth::is_16bit(img) {
+        DynamicImage::ImageRgba16(map_buffer(&depth::rgba16(img), f))
+    } else {
+        DynamicImage::ImageRgba8(map_buffer(&parallel::rgba(img), f))
+    }
+}
+
+fn map_buffer<S: Sample, F>(source: &RgbaBuffer<S>, f: F) -> RgbaBuffer<S>
+where
+    F: Fn(usize, f32) -> f32 + Sync,
+{
+    let tone = |channel: usize, value: S| S::from_f32_rounded(f(channel, value.to_f32()).clamp(0.0, 255.0));
+    parallel::map_pixels(source, |pixel| Rgba([tone(0, pixel[0]), tone(1, pixel[1]), tone(2, pixel[2]), pixel[3]]))
+}
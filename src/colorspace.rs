@@ -0,0 +1,92 @@
+use std::sync::OnceLock;
+
+use image::DynamicImage;
+use serde::{Deserialize, Serialize};
+
+use crate::depth::{self, RgbaBuffer};
+use crate::parallel;
+
+/// Color space an effect does its arithmetic in.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorSpace {
+    /// Gamma-encoded sRGB values, as stored in the file
+    #[default]
+    Srgb,
+    /// Linear light, where averaging and scaling match physical light mixing
+    Linear,
+}
+
+/// sRGB transfer function decoding a 0-1 value to linear light.
+pub fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Inverse of `srgb_to_linear`.
+pub fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+// Lookup tables over every 16-bit value, built on first use
+fn table(cell: &'static OnceLock<Vec<u16>>, transfer: fn(f32) -> f32) -> &'static [u16] {
+    cell.get_or_init(|| {
+        (0..=u16::MAX)
+            .map(|v| (transfer(v as f32 / 65535.0) * 65535.0).round() as u16)
+            .collect()
+    })
+}
+
+fn convert(img: &DynamicImage, table: &[u16]) -> RgbaBuffer<u16> {
+    let mut output = depth::to_rgba16(img);
+    parallel::for_each_row(&mut output, |_, row| {
+        for pixel in row.chunks_exact_mut(4) {
+            // Alpha is not light, so it is left alone
+            for value in pixel.iter_mut().take(3) {
+                *value = table[*value as usize];
+            }
+        }
+    });
+    output
+}
+
+/// Decodes sRGB to linear light. The result is always 16-bit, since linear
+/// values stored in 8 bits would band badly in the shadows.
+pub fn to_linear(img: &DynamicImage) -> DynamicImage {
+    static TABLE: OnceLock<Vec<u16>> = OnceLock::new();
+    DynamicImage::ImageRgba16(convert(img, table(&TABLE, srgb_to_linear)))
+}
+
+/// Encodes linear light back to sRGB, at 16 bits or reduced to 8.
+pub fn to_srgb(img: &DynamicImage, sixteen_bit: bool) -> DynamicImage {
+    static TABLE: OnceLock<Vec<u16>> = OnceLock::new();
+    let output = DynamicImage::ImageRgba16(convert(img, table(&TABLE, linear_to_srgb)));
+    if sixteen_bit {
+        output
+    } else {
+        DynamicImage::ImageRgba8(depth::to_rgba8(&output))
+    }
+}
+
+/// Runs `apply` on `img` in `space`, converting there and back when needed.
+/// The result keeps the bit depth of the input.
+pub fn apply_in<F, E>(img: DynamicImage, space: ColorSpace, apply: F) -> Result<DynamicImage, E>
+where
+    F: FnOnce(DynamicImage) -> Result<DynamicImage, E>,
+{
+    match space {
+        ColorSpace::Srgb => apply(img),
+        ColorSpace::Linear => {
+            let sixteen_bit = depth::is_16bit(&img);
+            let output = apply(to_linear(&img))?;
+            Ok(to_srgb(&output, sixteen_bit))
+        },
+    }
+}
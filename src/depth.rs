@@ -0,0 +1,152 @@
+use std::borrow::Cow;
+
+use image::{ColorType, DynamicImage, ImageBuffer, Primitive, Rgba, RgbaImage};
+
+use crate::parallel;
+
+pub type RgbaBuffer<S> = ImageBuffer<Rgba<S>, Vec<S>>;
+
+/// Channel type an effect can work in. Values are exchanged as floats on the
+/// 0-255 scale whatever the depth, so formulas written for 8-bit images carry
+/// over unchanged and only the final rounding differs.
+pub trait Sample: Primitive + Send + Sync + 'static {
+    fn to_f32(self) -> f32;
+    fn from_f32(value: f32) -> Self;
+    /// Raw channel value, for effects that sum and average channels exactly.
+    fn to_u32(self) -> u32;
+    fn from_u32(value: u32) -> Self;
+
+    /// Like `from_f32`, for effects that rounded to the nearest 8-bit value.
+    fn from_f32_rounded(value: f32) -> Self {
+        Self::from_f32(value)
+    }
+}
+
+impl Sample for u8 {
+    fn to_f32(self) -> f32 {
+        self as f32
+    }
+
+    // Truncating and saturating, like the `as u8` the effects always used
+    fn from_f32(value: f32) -> Self {
+        value as u8
+    }
+
+    fn from_f32_rounded(value: f32) -> Self {
+        value.round() as u8
+    }
+
+    fn to_u32(self) -> u32 {
+        self as u32
+    }
+
+    fn from_u32(value: u32) -> Self {
+        value as u8
+    }
+}
+
+impl Sample for u16 {
+    fn to_f32(self) -> f32 {
+        self as f32 / 257.0
+    }
+
+    fn from_f32(value: f32) -> Self {
+        (value * 257.0).round() as u16
+    }
+
+    fn to_u32(self) -> u32 {
+        self as u32
+    }
+
+    fn from_u32(value: u32) -> Self {
+        value as u16
+    }
+}
+
+/// Working depth of the pipeline, picked with `--depth`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Depth {
+    /// 16 bits when the input has more than 8 bits per channel
+    Auto,
+    Eight,
+    Sixteen,
+}
+
+impl Depth {
+    pub fn parse(depth: &str) -> Result<Depth, String> {
+        match depth {
+            "auto" => Ok(Depth::Auto),
+            "8" => Ok(Depth::Eight),
+            "16" => Ok(Depth::Sixteen),
+            other => Err(format!("Invalid depth: {} (use auto, 8 or 16)", other)),
+        }
+    }
+
+    /// Converts a freshly decoded image into the RGBA buffer the pipeline runs on.
+    pub fn standardize(self, img: &DynamicImage) -> DynamicImage {
+        match self {
+            Depth::Sixteen => DynamicImage::ImageRgba16(to_rgba16(img)),
+            Depth::Auto if is_16bit(img) => DynamicImage::ImageRgba16(to_rgba16(img)),
+            _ => DynamicImage::ImageRgba8(img.to_rgba8()),
+        }
+    }
+}
+
+pub fn is_16bit(img: &DynamicImage) -> bool {
+    matches!(img.color(), ColorType::L16 | ColorType::La16 | ColorType::Rgb16 | ColorType::Rgba16)
+}
+
+/// 16-bit RGBA copy of `img`. Unlike `DynamicImage::to_rgba16`, which
+/// shifts 8-bit values up, this scales them so 255 becomes 65535.
+pub fn to_rgba16(img: &DynamicImage) -> RgbaBuffer<u16> {
+    if is_16bit(img) {
+        return img.to_rgba16();
+    }
+    let rgba = img.to_rgba8();
+    let (width, height) = rgba.dimensions();
+    let raw = rgba.into_raw().into_iter().map(|v| v as u16 * 257).collect();
+    ImageBuffer::from_raw(width, height, raw).unwrap()
+}
+
+/// 8-bit RGBA copy of `img`, rounding 16-bit values to the nearest step.
+pub fn to_rgba8(img: &DynamicImage) -> RgbaImage {
+    if !is_16bit(img) {
+        return img.to_rgba8();
+    }
+    let rgba = img.to_rgba16();
+    let (width, height) = rgba.dimensions();
+    let raw = rgba.into_raw().into_iter().map(|v| ((v as u32 * 255 + 32767) / 65535) as u8).collect();
+    ImageBuffer::from_raw(width, height, raw).unwrap()
+}
+
+/// Like `parallel::rgba`, for the 16-bit RGBA buffer.
+pub fn rgba16(img: &DynamicImage) -> Cow<'_, RgbaBuffer<u16>> {
+    match img {
+        DynamicImage::ImageRgba16(buffer) => Cow::Borrowed(buffer),
+        other => Cow::Owned(to_rgba16(other)),
+    }
+}
+
+/// Point operation at the image's own depth: 16-bit images stay 16-bit and
+/// everything else runs on the 8-bit RGBA buffer. `f` gets and returns RGBA
+/// on the 0-255 scale.
+pub fn map_rgba<F>(img: &DynamicImage, f: F) -> DynamicImage
+where
+    F: Fn([f32; 4]) -> [f32; 4] + Sync,
+{
+    if is_16bit(img) {
+        DynamicImage::ImageRgba16(map_buffer(&rgba16(img), f))
+    } else {
+        DynamicImage::ImageRgba8(map_buffer(&parallel::rgba(img), f))
+    }
+}
+
+fn map_buffer<S: Sample, F>(source: &RgbaBuffer<S>, f: F) -> RgbaBuffer<S>
+where
+    F: Fn([f32; 4]) -> [f32; 4] + Sync,
+{
+    parallel::map_pixels(source, |pixel| {
+        let result = f([pixel[0].to_f32(), pixel[1].to_f32(), pixel[2].to_f32(), pixel[3].to_f32()]);
+        Rgba(result.map(S::from_f32))
+    })
+}
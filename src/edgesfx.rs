@@ -1,31 +1,46 @@
-use image::{DynamicImage, GenericImageView, ImageBuffer, Rgb,Rgba, Luma};
+use image::{DynamicImage, ImageBuffer, Luma, Pixel, Rgb, RgbImage, Rgba};
 use std::f32::consts::PI;
 
-// Helper function to apply convolution
-fn convolve(img: &DynamicImage, kernel: &[[f32; 3]; 3]) -> DynamicImage {
-    let (width, height) = img.dimensions();
-    let mut output = ImageBuffer::new(width, height);
+use crate::parallel;
 
-    for y in 1..height-1 {
-        for x in 1..width-1 {
+// Helper function to apply a 3x3 convolution to the RGB channels. The one
+// pixel border stays black.
+fn convolve<P: Pixel<Subpixel = u8> + 'static>(img: &ImageBuffer<P, Vec<u8>>, kernel: &[[f32; 3]; 3]) -> RgbImage {
+    let (width, height) = img.dimensions();
+    let channels = P::CHANNEL_COUNT as usize;
+    let row_len = width as usize * channels;
+    let data = img.as_raw();
+    let mut output = RgbImage::new(width, height);
+
+    parallel::for_each_row(&mut output, |y, out| {
+        let y = y as usize;
+        if y == 0 || y + 1 >= height as usize {
+            return;
+        }
+        let rows = [
+            &data[(y - 1) * row_len..y * row_len],
+            &data[y * row_len..(y + 1) * row_len],
+            &data[(y + 1) * row_len..(y + 2) * row_len],
+        ];
+        for x in 1..(width as usize).saturating_sub(1) {
             let mut sum = (0.0, 0.0, 0.0);
-            for ky in 0..3 {
-                for kx in 0..3 {
-                    let pixel = img.get_pixel(x + kx - 1, y + ky - 1);
-                    sum.0 += pixel[0] as f32 * kernel[ky as usize][kx as usize];
-                    sum.1 += pixel[1] as f32 * kernel[ky as usize][kx as usize];
-                    sum.2 += pixel[2] as f32 * kernel[ky as usize][kx as usize];
+            for (row, weights) in rows.iter().zip(kernel) {
+                for (kx, weight) in weights.iter().enumerate() {
+                    let i = (x + kx - 1) * channels;
+                    sum.0 += row[i] as f32 * weight;
+                    sum.1 += row[i + 1] as f32 * weight;
+                    sum.2 += row[i + 2] as f32 * weight;
                 }
             }
-            output.put_pixel(x, y, Rgb([
+            out[x * 3..x * 3 + 3].copy_from_slice(&[
                 sum.0.clamp(0.0, 255.0) as u8,
                 sum.1.clamp(0.0, 255.0) as u8,
-                sum.2.clamp(0.0, 255.0) as u8
-            ]));
+                sum.2.clamp(0.0, 255.0) as u8,
+            ]);
         }
-    }
+    });
 
-    DynamicImage::ImageRgb8(output)
+    output
 }
 
 pub fn neon_edge(img: &DynamicImage, strength: f32, color_shift: f32, brightness: f32) -> Result<DynamicImage, String> {
@@ -33,16 +48,17 @@ pub fn neon_edge(img: &DynamicImage, strength: f32, color_shift: f32, brightness
     let sobel_x = [[-1.0, 0.0, 1.0], [-2.0, 0.0, 2.0], [-1.0, 0.0, 1.0]];
     let sobel_y = [[-1.0, -2.0, -1.0], [0.0, 0.0, 0.0], [1.0, 2.0, 1.0]];
 
-    let edges_x = convolve(img, &sobel_x);
-    let edges_y = convolve(img, &sobel_y);
-
-    let (width, height) = img.dimensions();
-    let mut output = ImageBuffer::new(width, height);
-
-    for y in 0..height {
-        for x in 0..width {
-            let px_x = edges_x.get_pixel(x, y);
-            let px_y = edges_y.get_pixel(x, y);
+    let source = parallel::rgba(img);
+    let edges_x = convolve(&source, &sobel_x);
+    let edges_y = convolve(&source, &sobel_y);
+
+    let mut output = RgbImage::new(edges_x.width(), edges_x.height());
+    let row_len = edges_x.width() as usize * 3;
+    parallel::for_each_row(&mut output, |y, row| {
+        let start = y as usize * row_len;
+        let rows_x = &edges_x.as_raw()[start..start + row_len];
+        let rows_y = &edges_y.as_raw()[start..start + row_len];
+        for ((out, px_x), px_y) in row.chunks_exact_mut(3).zip(rows_x.chunks_exact(3)).zip(rows_y.chunks_exact(3)) {
             let edge_strength = (
                 (px_x[0] as f32).hypot(px_y[0] as f32),
                 (px_x[1] as f32).hypot(px_y[1] as f32),
@@ -50,13 +66,13 @@ pub fn neon_edge(img: &DynamicImage, strength: f32, color_shift: f32, brightness
             );
 
             // Apply customizable neon effect
-            output.put_pixel(x, y, Rgb([
+            out.copy_from_slice(&[
                 ((edge_strength.0 * strength + color_shift) * brightness).clamp(0.0, 255.0) as u8,
                 ((edge_strength.1 * strength) * brightness).clamp(0.0, 255.0) as u8,
                 ((edge_strength.2 * strength - color_shift) * brightness).clamp(0.0, 255.0) as u8
-            ]));
+            ]);
         }
-    }
+    });
 
     Ok(DynamicImage::ImageRgb8(output))
 }
@@ -65,28 +81,22 @@ pub fn sketch(img: &DynamicImage, intensity: f32, contrast: f32, invert: bool) -
     
     let laplacian = [[0.0, 1.0, 0.0], [1.0, -4.0, 1.0], [0.0, 1.0, 0.0]];
 
-    let edges = convolve(img, &laplacian);
-    let (width, height) = img.dimensions();
-    let mut output = ImageBuffer::new(width, height);
-
-    for y in 0..height {
-        for x in 0..width {
-            let edge_px = edges.get_pixel(x, y);
-            let mut sketch_value = (edge_px[0] as f32 * 0.3 + edge_px[1] as f32 * 0.59 + edge_px[2] as f32 * 0.11) as f32;
-            
-            // Apply intensity and contrast
-            sketch_value = ((sketch_value - 128.0) * contrast + 128.0) * intensity;
-            sketch_value = sketch_value.clamp(0.0, 255.0);
-            
-            // Invert if requested
-            if invert {
-                sketch_value = 255.0 - sketch_value;
-            }
+    let edges = convolve(&parallel::rgba(img), &laplacian);
+    let output = parallel::map_pixels(&edges, |edge_px| {
+        let mut sketch_value = edge_px[0] as f32 * 0.3 + edge_px[1] as f32 * 0.59 + edge_px[2] as f32 * 0.11;
+
+        // Apply intensity and contrast
+        sketch_value = ((sketch_value - 128.0) * contrast + 128.0) * intensity;
+        sketch_value = sketch_value.clamp(0.0, 255.0);
 
-            let final_value = sketch_value as u8;
-            output.put_pixel(x, y, Rgb([final_value, final_value, final_value]));
+        // Invert if requested
+        if invert {
+            sketch_value = 255.0 - sketch_value;
         }
-    }
+
+        let final_value = sketch_value as u8;
+        Rgb([final_value, final_value, final_value])
+    });
 
     Ok(DynamicImage::ImageRgb8(output))
 }
@@ -96,21 +106,18 @@ pub fn emboss(img: &DynamicImage, strength: f32, angle: f32) -> Result<DynamicIm
     // Calculate kernel based on angle and strength
     let (dx, dy) = angle.to_radians().sin_cos();
     let kernel = [
-        [-1.0 * strength * dx, -1.0 * strength * dy, 0.0],
-        [-1.0 * strength * dy, 0.0, 1.0 * strength * dy],
+        [-strength * dx, -strength * dy, 0.0],
+        [-strength * dy, 0.0, 1.0 * strength * dy],
         [0.0, 1.0 * strength * dx, 1.0 * strength * dy],
     ];
 
-    let embossed = convolve(img, &kernel);
+    let embossed = convolve(&parallel::rgba(img), &kernel);
 
     // Normalize the result
-    let (width, height) = embossed.dimensions();
-    let mut normalized = ImageBuffer::new(width, height);
-
-    for (x, y, pixel) in embossed.to_rgb8().enumerate_pixels() {
+    let normalized = parallel::map_pixels(&embossed, |pixel| {
         let normalized_value = (pixel[0] as f32 / 2.0 + 128.0).clamp(0.0, 255.0) as u8;
-        normalized.put_pixel(x, y, Rgb([normalized_value, normalized_value, normalized_value]));
-    }
+        Rgb([normalized_value, normalized_value, normalized_value])
+    });
 
     Ok(DynamicImage::ImageRgb8(normalized))
 }
@@ -130,7 +137,7 @@ pub fn canny_edge_detection(img: &DynamicImage, threshold: f32) -> ImageBuffer<L
         [2.0 / 16.0, 4.0 / 16.0, 2.0 / 16.0],
         [1.0 / 16.0, 2.0 / 16.0, 1.0 / 16.0],
     ];
-    let blurred = convolve(img, &gaussian_kernel);
+    let blurred = convolve(&parallel::rgba(img), &gaussian_kernel);
 
     // Step 2: Gradient calculation
     let sobel_x = [[-1.0, 0.0, 1.0], [-2.0, 0.0, 2.0], [-1.0, 0.0, 1.0]];
@@ -139,52 +146,44 @@ pub fn canny_edge_detection(img: &DynamicImage, threshold: f32) -> ImageBuffer<L
     let gy = convolve(&blurred, &sobel_y);
 
     // Step 3-4: Non-maximum suppression and thresholding
-    let (width, height) = img.dimensions();
-    let mut edges = ImageBuffer::new(width, height);
-
-    for y in 1..height-1 {
-        for x in 1..width-1 {
-            let gx_val = gx.get_pixel(x, y)[0] as f32;
-            let gy_val = gy.get_pixel(x, y)[0] as f32;
-            
-            let magnitude = (gx_val * gx_val + gy_val * gy_val).sqrt();
-            let angle = gy_val.atan2(gx_val);
-
-            // Non-maximum suppression
-            let direction = (((angle * 4.0 / PI) + 4.5).floor() % 4.0) as i32;
-            let (dx, dy) = match direction {
-                0 => (1i32, 0i32),   // 0 degrees
-                1 => (1i32, 1i32),   // 45 degrees
-                2 => (0i32, 1i32),   // 90 degrees
-                _ => (-1i32, 1i32),  // 135 degrees
-            };
-
-            if magnitude > gx.get_pixel((x as i32 + dx) as u32, (y as i32 + dy) as u32)[0] as f32 &&
-               magnitude > gx.get_pixel((x as i32 - dx) as u32, (y as i32 - dy) as u32)[0] as f32 &&
-               magnitude >= threshold {
-                edges.put_pixel(x, y, Luma([255]));
-            } else {
-                edges.put_pixel(x, y, Luma([0]));
-            }
+    let (width, height) = blurred.dimensions();
+    parallel::from_fn(width, height, |x, y| {
+        if x == 0 || y == 0 || x + 1 >= width || y + 1 >= height {
+            return Luma([0]);
         }
-    }
-
-    edges
+        let gx_val = gx.get_pixel(x, y)[0] as f32;
+        let gy_val = gy.get_pixel(x, y)[0] as f32;
+
+        let magnitude = (gx_val * gx_val + gy_val * gy_val).sqrt();
+        let angle = gy_val.atan2(gx_val);
+
+        // Non-maximum suppression
+        let direction = (((angle * 4.0 / PI) + 4.5).floor() % 4.0) as i32;
+        let (dx, dy) = match direction {
+            0 => (1i32, 0i32),   // 0 degrees
+            1 => (1i32, 1i32),   // 45 degrees
+            2 => (0i32, 1i32),   // 90 degrees
+            _ => (-1i32, 1i32),  // 135 degrees
+        };
+
+        if magnitude > gx.get_pixel((x as i32 + dx) as u32, (y as i32 + dy) as u32)[0] as f32 &&
+           magnitude > gx.get_pixel((x as i32 - dx) as u32, (y as i32 - dy) as u32)[0] as f32 &&
+           magnitude >= threshold {
+            Luma([255])
+        } else {
+            Luma([0])
+        }
+    })
 }
 
 
 fn quantize_image(img: &ImageBuffer<Luma<u8>, Vec<u8>>, levels: u8) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
-    let (width, height) = img.dimensions();
-    let mut quantized = ImageBuffer::new(width, height);
-
-    for (x, y, pixel) in img.enumerate_pixels() {
+    parallel::map_pixels(img, |pixel| {
         let value = pixel[0] as f32 / 255.0;
         let quantized_value = (value * levels as f32).round() / levels as f32;
         let rgb_value = (quantized_value * 255.0) as u8;
-        quantized.put_pixel(x, y, Rgb([rgb_value, rgb_value, rgb_value]));
-    }
-
-    quantized
+        Rgb([rgb_value, rgb_value, rgb_value])
+    })
 }
 
 pub fn edge_extrusion(img: &DynamicImage, strength: f32, depth: u32, threshold: f32) -> Result<DynamicImage, String> {
@@ -0,0 +1,1254 @@
+use std::error::Error;
+use std::fmt;
+
+use image::{DynamicImage, GenericImageView, Pixel, Rgba};
+use serde_json::{json, Map, Value};
+
+use crate::alpha::AlphaPolicy;
+use crate::colorfx;
+use crate::colorfx::utils::{parse_color, split_color_list};
+use crate::colorfx::gradient::{self, Gradient, Interpolation, Stop};
+use crate::colorfx::halftone::{self, DotShape, Separation};
+use crate::colorfx::palette::{self, Palette, PaletteDither};
+use crate::colorfx::replace::{self, Metric, ReplaceMode};
+use crate::colorfx::tone::{self, ControlPoint, Curve};
+use crate::colorfx::{DiffusionKernel, ThresholdMap};
+use crate::edgesfx;
+use crate::geometryfx;
+use crate::glitchfx;
+use crate::lut;
+
+pub type EffectResult = Result<DynamicImage, Box<dyn Error>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+    Color,
+    Glitch,
+    Edges,
+    Geometry,
+}
+
+impl Category {
+    pub const ALL: &'static [Category] = &[Category::Color, Category::Glitch, Category::Edges, Category::Geometry];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Category::Color => "color",
+            Category::Glitch => "glitch",
+            Category::Edges => "edges",
+            Category::Geometry => "geometry",
+        }
+    }
+}
+
+impl fmt::Display for Category {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ParamKind {
+    Float,
+    Int,
+    Bool,
+    Str,
+    Choice(&'static [&'static str]),
+    /// Pixels as a number, or a share of the image side as a "N%" string.
+    Length,
+    /// Colors as an array of strings, or one comma-separated string.
+    ColorList,
+    /// Curve control points as [input, output] pairs on the 0-255 scale.
+    Points,
+    /// Gradient stops: colors, each optionally followed by its position, as
+    /// an array of strings or one comma-separated string.
+    Stops,
+}
+
+impl fmt::Display for ParamKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParamKind::Float => f.write_str("float"),
+            ParamKind::Int => f.write_str("integer"),
+            ParamKind::Bool => f.write_str("boolean"),
+            ParamKind::Str => f.write_str("string"),
+            ParamKind::Choice(choices) => write!(f, "one of {}", choices.join("|")),
+            ParamKind::Length => f.write_str("pixels or percentage (e.g. 120 or \"25%\")"),
+            ParamKind::ColorList => f.write_str("list of colors (e.g. [\"#0f380f\", \"#9bbc0f\"])"),
+            ParamKind::Points => f.write_str("list of [input, output] points (e.g. [[0, 0], [128, 150], [255, 255]])"),
+            ParamKind::Stops => f.write_str("list of colors with optional positions (e.g. [\"#000000\", \"#ff0000 30%\", \"#ffffff\"])"),
+        }
+    }
+}
+
+/// Value used when a param is missing from the config. `Unset` leaves the
+/// choice to the effect itself, and `Required` params must always be given.
+#[derive(Debug, Clone, Copy)]
+pub enum ParamDefault {
+    Float(f64),
+    Int(i64),
+    Bool(bool),
+    Str(&'static str),
+    Unset,
+    Required,
+}
+
+impl ParamDefault {
+    pub fn to_json(&self) -> Value {
+        match *self {
+            ParamDefault::Float(v) => json!(v),
+            ParamDefault::Int(v) => json!(v),
+            ParamDefault::Bool(v) => json!(v),
+            ParamDefault::Str(v) => json!(v),
+            ParamDefault::Unset | ParamDefault::Required => Value::Null,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ParamSpec {
+    pub name: &'static str,
+    pub kind: ParamKind,
+    pub default: ParamDefault,
+    /// Inclusive valid range for numeric params; either end may be infinite.
+    pub range: Option<(f64, f64)>,
+    /// Measured in pixels, so it is scaled with the image for previews.
+    pub pixels: bool,
+    /// Names a file on disk, which the API only opens inside its files
+    /// directory.
+    pub file: bool,
+    pub description: &'static str,
+}
+
+impl ParamSpec {
+    /// Machine-readable description; infinite range ends become `null`.
+    pub fn to_json(&self) -> Value {
+        let choices = match self.kind {
+            ParamKind::Choice(choices) => json!(choices),
+            _ => Value::Null,
+        };
+        let kind = match self.kind {
+            ParamKind::Float => "float",
+            ParamKind::Int => "integer",
+            ParamKind::Bool => "boolean",
+            ParamKind::Str => "string",
+            ParamKind::Choice(_) => "choice",
+            ParamKind::Length => "length",
+            ParamKind::ColorList => "colors",
+            ParamKind::Points => "points",
+            ParamKind::Stops => "stops",
+        };
+        json!({
+            "name": self.name,
+            "type": kind,
+            "choices": choices,
+            "default": self.default.to_json(),
+            "required": matches!(self.default, ParamDefault::Required),
+            "min": self.range.map(|(min, _)| min),
+            "max": self.range.map(|(_, max)| max),
+            "pixels": self.pixels,
+            "file": self.file,
+            "description": self.description,
+        })
+    }
+
+    // `value` scaled by `factor` if it is a size in pixels. Percentages already
+    // follow the image, and whole-pixel sizes that were set stay at least 1.
+    fn scale(&self, value: &Value, factor: f64) -> Option<Value> {
+        let pixels = match value {
+            Value::Number(n) => n.as_f64()?,
+            Value::String(s) => s.trim().parse::<f64>().ok()?,
+            _ => return None,
+        };
+        let mut scaled = pixels * factor;
+        if !matches!(self.kind, ParamKind::Float) {
+            scaled = scaled.round();
+            if scaled == 0.0 && pixels != 0.0 {
+                scaled = pixels.signum();
+            }
+        }
+        if let Some((min, max)) = self.range {
+            scaled = scaled.clamp(min, max);
+        }
+        Some(match self.kind {
+            ParamKind::Float => json!(scaled),
+            _ => json!(scaled as i64),
+        })
+    }
+
+    pub fn range_string(&self) -> Option<String> {
+        self.range.map(|(min, max)| match (min.is_finite(), max.is_finite()) {
+            (true, true) => format!("{}..={}", min, max),
+            (true, false) => format!(">= {}", min),
+            (false, true) => format!("<= {}", max),
+            (false, false) => "any".to_string(),
+        })
+    }
+}
+
+/// How far around a pixel an effect looks, which decides whether it can run
+/// on overlapping tiles instead of the whole image.
+#[derive(Clone, Copy)]
+pub enum Neighborhood {
+    /// Each output pixel only depends on the input pixel at the same place.
+    Point,
+    /// Output pixels depend on input pixels up to this many pixels away.
+    Radius(fn(&Params) -> Result<u32, Box<dyn Error>>),
+    /// Needs the whole image: geometry, patterns tied to absolute positions,
+    /// or effects that reach arbitrarily far.
+    Frame,
+}
+
+pub struct EffectSpec {
+    pub name: &'static str,
+    pub category: Category,
+    pub description: &'static str,
+    pub params: &'static [ParamSpec],
+    pub neighborhood: Neighborhood,
+    /// Averages or scales light, so it runs in linear light when the
+    /// pipeline's `color_space` is "linear".
+    pub linear_light: bool,
+    /// Runs at 16 bits per channel on 16-bit images. The others work in 8
+    /// bits, and their output is widened back.
+    pub full_depth: bool,
+    /// Default handling of the alpha channel, overridable per pipeline or step.
+    pub alpha: AlphaPolicy,
+    pub apply: fn(DynamicImage, &Params) -> EffectResult,
+}
+
+impl EffectSpec {
+    pub fn param(&self, name: &str) -> Option<&'static ParamSpec> {
+        self.params.iter().find(|p| p.name == name)
+    }
+
+    /// Whether some param has no default, so the effect cannot run on
+    /// `default_params` alone.
+    pub fn has_required(&self) -> bool {
+        self.params.iter().any(|p| matches!(p.default, ParamDefault::Required))
+    }
+
+    /// Params filled with their defaults, as they would appear in a config.
+    /// Required params are left out.
+    pub fn default_params(&self) -> Value {
+        let params: Map<String, Value> = self.params.iter()
+            .filter(|p| !matches!(p.default, ParamDefault::Unset | ParamDefault::Required))
+            .map(|p| (p.name.to_string(), p.default.to_json()))
+            .collect();
+        Value::Object(params)
+    }
+
+    /// `values` with every pixel-sized param, given or defaulted, scaled by
+    /// `factor`, so the effect looks the same on an image resized by it.
+    pub fn scale_params(&self, values: &Value, factor: f64) -> Value {
+        let mut scaled = values.as_object().cloned().unwrap_or_default();
+        for spec in self.params.iter().filter(|p| p.pixels) {
+            let value = match values.get(spec.name).filter(|v| !v.is_null()) {
+                Some(value) => value.clone(),
+                None => spec.default.to_json(),
+            };
+            if let Some(value) = spec.scale(&value, factor) {
+                scaled.insert(spec.name.to_string(), value);
+            }
+        }
+        Value::Object(scaled)
+    }
+
+    /// Tile overlap needed for `params`, or `None` when the effect has to see
+    /// the whole image.
+    pub fn radius(&self, params: &Params) -> Result<Option<u32>, Box<dyn Error>> {
+        match self.neighborhood {
+            Neighborhood::Point => Ok(Some(0)),
+            Neighborhood::Radius(radius) => radius(params).map(Some),
+            Neighborhood::Frame => Ok(None),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "name": self.name,
+            "category": self.category.as_str(),
+            "description": self.description,
+            "params": self.params.iter().map(ParamSpec::to_json).collect::<Vec<_>>(),
+            "linear_light": self.linear_light,
+            "full_depth": self.full_depth,
+            "alpha": self.alpha.as_str(),
+        })
+    }
+
+    /// A pipeline step using this effect with default params, and a
+    /// placeholder for each required one.
+    pub fn example(&self) -> Value {
+        let mut params = self.default_params();
+        for spec in self.params.iter().filter(|p| matches!(p.default, ParamDefault::Required)) {
+            params[spec.name] = json!(format!("<{}>", spec.name));
+        }
+        json!({ "name": self.name, "params": params })
+    }
+}
+
+impl fmt::Debug for EffectSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EffectSpec")
+            .field("name", &self.name)
+            .field("category", &self.category)
+            .field("params", &self.params)
+            .finish()
+    }
+}
+
+/// Typed access to a step's JSON params, falling back to the defaults in the
+/// effect's spec and checking kinds and ranges.
+pub struct Params<'a> {
+    effect: &'static EffectSpec,
+    values: &'a Value,
+}
+
+impl<'a> Params<'a> {
+    pub fn new(effect: &'static EffectSpec, values: &'a Value) -> Self {
+        Params { effect, values }
+    }
+
+    fn spec(&self, name: &str) -> &'static ParamSpec {
+        self.effect.param(name)
+            .unwrap_or_else(|| panic!("{} has no param {}", self.effect.name, name))
+    }
+
+    fn given(&self, name: &str) -> Option<&'a Value> {
+        self.values.get(name).filter(|v| !v.is_null())
+    }
+
+    fn error(&self, name: &str, message: &str) -> Box<dyn Error> {
+        format!("{}: param '{}' {}", self.effect.name, name, message).into()
+    }
+
+    fn check_range(&self, spec: &ParamSpec, value: f64) -> Result<f64, Box<dyn Error>> {
+        match spec.range {
+            Some((min, max)) if value < min || value > max => {
+                Err(self.error(spec.name, &format!("must be in {}, got {}", spec.range_string().unwrap(), value)))
+            },
+            _ => Ok(value),
+        }
+    }
+
+    fn opt_number(&self, name: &str) -> Result<Option<f64>, Box<dyn Error>> {
+        let spec = self.spec(name);
+        let value = match self.given(name) {
+            Some(v) => Some(v.as_f64().ok_or_else(|| self.error(name, "must be a number"))?),
+            None => match spec.default {
+                ParamDefault::Float(v) => Some(v),
+                ParamDefault::Int(v) => Some(v as f64),
+                _ => None,
+            },
+        };
+        value.map(|v| self.check_range(spec, v)).transpose()
+    }
+
+    fn number(&self, name: &str) -> Result<f64, Box<dyn Error>> {
+        self.opt_number(name)?.ok_or_else(|| self.error(name, "is required"))
+    }
+
+    pub fn f32(&self, name: &str) -> Result<f32, Box<dyn Error>> {
+        Ok(self.number(name)? as f32)
+    }
+
+    pub fn opt_f32(&self, name: &str) -> Result<Option<f32>, Box<dyn Error>> {
+        Ok(self.opt_number(name)?.map(|v| v as f32))
+    }
+
+    pub fn i32(&self, name: &str) -> Result<i32, Box<dyn Error>> {
+        Ok(self.number(name)? as i32)
+    }
+
+    pub fn u32(&self, name: &str) -> Result<u32, Box<dyn Error>> {
+        let value = self.number(name)?;
+        if value < 0.0 {
+            return Err(self.error(name, "must not be negative"));
+        }
+        Ok(value as u32)
+    }
+
+    pub fn opt_u32(&self, name: &str) -> Result<Option<u32>, Box<dyn Error>> {
+        match self.opt_number(name)? {
+            Some(v) if v < 0.0 => Err(self.error(name, "must not be negative")),
+            value => Ok(value.map(|v| v as u32)),
+        }
+    }
+
+    pub fn u8(&self, name: &str) -> Result<u8, Box<dyn Error>> {
+        let value = self.u32(name)?;
+        u8::try_from(value).map_err(|_| self.error(name, "must be at most 255"))
+    }
+
+    pub fn bool(&self, name: &str) -> Result<bool, Box<dyn Error>> {
+        match self.given(name) {
+            Some(v) => v.as_bool().ok_or_else(|| self.error(name, "must be a boolean")),
+            None => match self.spec(name).default {
+                ParamDefault::Bool(v) => Ok(v),
+                _ => Err(self.error(name, "is required")),
+            },
+        }
+    }
+
+    pub fn str(&self, name: &str) -> Result<&'a str, Box<dyn Error>> {
+        let spec = self.spec(name);
+        let value = match self.given(name) {
+            Some(v) => v.as_str().ok_or_else(|| self.error(name, "must be a string"))?,
+            None => match spec.default {
+                ParamDefault::Str(v) => v,
+                _ => return Err(self.error(name, "is required")),
+            },
+        };
+        if let ParamKind::Choice(choices) = spec.kind {
+            if !choices.contains(&value) {
+                return Err(self.error(name, &format!("must be one of {}, got '{}'", choices.join(", "), value)));
+            }
+        }
+        Ok(value)
+    }
+
+    /// A length in pixels; percentages are taken of `full`.
+    pub fn opt_length(&self, name: &str, full: u32) -> Result<Option<u32>, Box<dyn Error>> {
+        let spec = self.spec(name);
+        let given = match self.given(name) {
+            Some(v) => Some(v.clone()),
+            None => match spec.default {
+                ParamDefault::Unset | ParamDefault::Required => None,
+                default => Some(default.to_json()),
+            },
+        };
+        let pixels = match given {
+            None => return Ok(None),
+            Some(Value::Number(n)) => n.as_f64().unwrap(),
+            Some(Value::String(s)) => match s.trim().strip_suffix('%') {
+                Some(percent) => percent.trim().parse::<f64>()
+                    .map_err(|_| self.error(name, &format!("has an invalid percentage '{}'", s)))?
+                    / 100.0 * full as f64,
+                None => s.trim().parse::<f64>()
+                    .map_err(|_| self.error(name, &format!("must be pixels or a percentage, got '{}'", s)))?,
+            },
+            Some(_) => return Err(self.error(name, "must be pixels or a percentage")),
+        };
+        if pixels < 0.0 {
+            return Err(self.error(name, "must not be negative"));
+        }
+        Ok(Some(pixels.round() as u32))
+    }
+
+    pub fn length(&self, name: &str, full: u32) -> Result<u32, Box<dyn Error>> {
+        self.opt_length(name, full)?.ok_or_else(|| self.error(name, "is required"))
+    }
+
+    pub fn color(&self, name: &str) -> Result<Rgba<u8>, Box<dyn Error>> {
+        let value = self.str(name)?;
+        parse_color(value).map_err(|e| self.error(name, &e))
+    }
+
+    /// A list of colors, or `None` when the param is not given.
+    pub fn opt_colors(&self, name: &str) -> Result<Option<Vec<Rgba<u8>>>, Box<dyn Error>> {
+        let colors: Vec<&str> = match self.given(name) {
+            None => return Ok(None),
+            Some(Value::String(s)) => split_color_list(s),
+            Some(Value::Array(items)) => items.iter()
+                .map(|v| v.as_str().ok_or_else(|| self.error(name, "must list colors as strings")))
+                .collect::<Result<_, _>>()?,
+            Some(_) => return Err(self.error(name, "must be a list of colors")),
+        };
+        let colors = colors.into_iter()
+            .map(|c| parse_color(c).map_err(|e| self.error(name, &e)))
+            .collect::<Result<_, _>>()?;
+        Ok(Some(colors))
+    }
+
+    /// Control points as (input, output) pairs, or `None` when the param is
+    /// not given.
+    pub fn opt_points(&self, name: &str) -> Result<Option<Vec<ControlPoint>>, Box<dyn Error>> {
+        let points = match self.given(name) {
+            None => return Ok(None),
+            Some(Value::Array(points)) => points,
+            Some(_) => return Err(self.error(name, "must be a list of [input, output] points")),
+        };
+        let points = points.iter()
+            .map(|point| {
+                let pair = point.as_array()
+                    .filter(|pair| pair.len() == 2)
+                    .and_then(|pair| Some((pair[0].as_f64()?, pair[1].as_f64()?)))
+                    .ok_or_else(|| self.error(name, &format!("has an invalid point {}, expected [input, output]", point)))?;
+                if !(0.0..=255.0).contains(&pair.0) || !(0.0..=255.0).contains(&pair.1) {
+                    return Err(self.error(name, &format!("has a point {} outside 0..=255", point)));
+                }
+                Ok((pair.0 as f32, pair.1 as f32))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Some(points))
+    }
+
+    /// Gradient stops as colors and their positions in 0-1, or `None` when
+    /// the param is not given. Positions are percentages or plain fractions.
+    pub fn opt_stops(&self, name: &str) -> Result<Option<Vec<Stop>>, Box<dyn Error>> {
+        let stops: Vec<&str> = match self.given(name) {
+            None => return Ok(None),
+            Some(Value::String(s)) => split_color_list(s),
+            Some(Value::Array(items)) => items.iter()
+                .map(|v| v.as_str().ok_or_else(|| self.error(name, "must list stops as strings")))
+                .collect::<Result<_, _>>()?,
+            Some(_) => return Err(self.error(name, "must be a list of gradient stops")),
+        };
+        let stops = stops.into_iter()
+            .map(|stop| {
+                let stop = stop.trim();
+                // Positions follow the color, outside any rgb() or hsl()
+                let split = if stop.ends_with(')') { None } else { stop.rsplit_once(char::is_whitespace) };
+                let (color, position) = match split {
+                    Some((color, position)) => {
+                        let position = match position.strip_suffix('%') {
+                            Some(percent) => percent.parse::<f32>().map(|p| p / 100.0),
+                            None => position.parse::<f32>(),
+                        };
+                        (color, Some(position.map_err(|_| self.error(name, &format!("has an invalid stop '{}'", stop)))?))
+                    },
+                    None => (stop, None),
+                };
+                let color = parse_color(color).map_err(|e| self.error(name, &e))?;
+                Ok::<_, Box<dyn Error>>((color.to_rgb(), position))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Some(stops))
+    }
+
+    /// Fails on the first required param that the config leaves out.
+    pub fn check_required(&self) -> Result<(), Box<dyn Error>> {
+        match self.effect.params.iter().find(|p| matches!(p.default, ParamDefault::Required) && self.given(p.name).is_none()) {
+            Some(spec) => Err(self.error(spec.name, "is required")),
+            None => Ok(()),
+        }
+    }
+
+    /// Keys present in the config that the effect does not know about.
+    pub fn unknown(&self) -> Vec<&'a str> {
+        self.values.as_object()
+            .map(|params| params.keys()
+                .filter(|k| self.effect.param(k).is_none())
+                .map(|k| k.as_str())
+                .collect())
+            .unwrap_or_default()
+    }
+}
+
+const fn float(name: &'static str, default: f64, range: Option<(f64, f64)>, description: &'static str) -> ParamSpec {
+    ParamSpec { name, kind: ParamKind::Float, default: ParamDefault::Float(default), range, pixels: false, file: false, description }
+}
+
+const fn int(name: &'static str, default: i64, range: Option<(f64, f64)>, description: &'static str) -> ParamSpec {
+    ParamSpec { name, kind: ParamKind::Int, default: ParamDefault::Int(default), range, pixels: false, file: false, description }
+}
+
+const fn boolean(name: &'static str, default: bool, description: &'static str) -> ParamSpec {
+    ParamSpec { name, kind: ParamKind::Bool, default: ParamDefault::Bool(default), range: None, pixels: false, file: false, description }
+}
+
+const fn string(name: &'static str, default: &'static str, description: &'static str) -> ParamSpec {
+    ParamSpec { name, kind: ParamKind::Str, default: ParamDefault::Str(default), range: None, pixels: false, file: false, description }
+}
+
+const fn choice(name: &'static str, choices: &'static [&'static str], default: &'static str, description: &'static str) -> ParamSpec {
+    ParamSpec { name, kind: ParamKind::Choice(choices), default: ParamDefault::Str(default), range: None, pixels: false, file: false, description }
+}
+
+const fn length(name: &'static str, default: &'static str, description: &'static str) -> ParamSpec {
+    ParamSpec { name, kind: ParamKind::Length, default: ParamDefault::Str(default), range: None, pixels: true, file: false, description }
+}
+
+const fn optional(name: &'static str, kind: ParamKind, range: Option<(f64, f64)>, description: &'static str) -> ParamSpec {
+    ParamSpec { name, kind, default: ParamDefault::Unset, range, pixels: false, file: false, description }
+}
+
+const fn required(name: &'static str, kind: ParamKind, range: Option<(f64, f64)>, description: &'static str) -> ParamSpec {
+    ParamSpec { name, kind, default: ParamDefault::Required, range, pixels: false, file: false, description }
+}
+
+// Marks a param as a size in pixels
+const fn px(spec: ParamSpec) -> ParamSpec {
+    ParamSpec { pixels: true, ..spec }
+}
+
+// Marks a param as the path of a file
+const fn file(spec: ParamSpec) -> ParamSpec {
+    ParamSpec { file: true, ..spec }
+}
+
+// The Gaussian kernel of `image`'s blur reaches 2 sigma, plus one pixel for rounding
+fn blur_radius(sigma: f32) -> u32 {
+    let sigma = if sigma <= 0.0 { 1.0 } else { sigma };
+    (2.0 * sigma).ceil() as u32 + 1
+}
+
+// Canny chains three 3x3 passes: smoothing, gradients and non-maximum suppression
+const CANNY_RADIUS: u32 = 3;
+
+const INTERPOLATIONS: &[&str] = &["nearest", "bilinear", "bicubic"];
+
+const PALETTE_DITHERS: &[&str] = &["none", "bayer", "blue-noise", "clustered-dot", "lines", "floyd-steinberg", "atkinson", "jarvis-judice-ninke", "stucki", "burkes", "sierra"];
+
+const DITHER_METHODS: &[&str] = &["bayer", "blue-noise", "clustered-dot", "lines", "custom", "floyd-steinberg", "atkinson", "jarvis-judice-ninke", "stucki", "burkes", "sierra"];
+
+const INF: f64 = f64::INFINITY;
+const BYTE: Option<(f64, f64)> = Some((0.0, 255.0));
+const NON_NEGATIVE: Option<(f64, f64)> = Some((0.0, INF));
+const POSITIVE_INT: Option<(f64, f64)> = Some((1.0, INF));
+const UNIT: Option<(f64, f64)> = Some((0.0, 1.0));
+
+fn apply_palette(img: DynamicImage, p: &Params) -> EffectResult {
+    let palette = if let Some(colors) = p.opt_colors("colors")? {
+        Palette::new(colors.into_iter().map(|c| c.to_rgb()).collect())?
+    } else if p.given("reference").is_some() {
+        let path = p.str("reference")?;
+        let reference = image::open(path).map_err(|e| format!("Failed to read palette reference {}: {}", path, e))?;
+        palette::extract(&reference, p.u32("palette_size")? as usize, p.str("extraction")?)?
+    } else {
+        Palette::builtin(p.str("palette")?)?
+    };
+    let dither = match p.str("dither")? {
+        "none" => PaletteDither::None,
+        "bayer" => PaletteDither::Ordered { map: ThresholdMap::Bayer, matrix_size: p.opt_u32("matrix_size")? },
+        "blue-noise" => PaletteDither::Ordered { map: ThresholdMap::BlueNoise, matrix_size: p.opt_u32("matrix_size")? },
+        "clustered-dot" => PaletteDither::Ordered { map: ThresholdMap::ClusteredDot, matrix_size: p.opt_u32("matrix_size")? },
+        "lines" => PaletteDither::Ordered { map: ThresholdMap::Lines, matrix_size: p.opt_u32("matrix_size")? },
+        method => PaletteDither::Diffusion { kernel: DiffusionKernel::named(method)?, serpentine: p.bool("serpentine")? },
+    };
+    Ok(palette::quantize(&img, &palette, dither, p.f32("dither_strength")?)?)
+}
+
+fn apply_curves(img: DynamicImage, p: &Params) -> EffectResult {
+    let curve = |name: &str| -> Result<Curve, Box<dyn Error>> {
+        match p.opt_points(name)? {
+            Some(points) => Curve::new(&points).map_err(|e| p.error(name, &format!("is not a valid curve: {}", e))),
+            None => Ok(Curve::identity()),
+        }
+    };
+    Ok(tone::curves(&img, &curve("master")?, &[curve("red")?, curve("green")?, curve("blue")?]))
+}
+
+fn apply_color_replacer(img: DynamicImage, p: &Params) -> EffectResult {
+    Ok(replace::color_replacer(
+        &img,
+        p.str("target_color")?,
+        p.str("replacement_color")?,
+        p.f32("tolerance")?,
+        p.f32("softness")?,
+        Metric::named(p.str("metric")?)?,
+        ReplaceMode::named(p.str("mode")?)?,
+    )?)
+}
+
+fn apply_gradient_map(img: DynamicImage, p: &Params) -> EffectResult {
+    let interpolation = Interpolation::named(p.str("interpolation")?)?;
+    let gradient = match p.opt_stops("stops")? {
+        Some(stops) => Gradient::new(&stops, interpolation).map_err(|e| p.error("stops", &format!("is not a valid gradient: {}", e)))?,
+        None => Gradient::preset(p.str("preset")?, interpolation)?,
+    };
+    Ok(gradient::gradient_map(&img, &gradient, p.bool("reverse")?, p.f32("strength")?))
+}
+
+fn apply_halftone(img: DynamicImage, p: &Params) -> EffectResult {
+    let separation = match p.str("mode")? {
+        "monochrome" => Separation::Monochrome { angle: p.f32("black_angle")? },
+        _ => Separation::Cmyk {
+            angles: [p.f32("cyan_angle")?, p.f32("magenta_angle")?, p.f32("yellow_angle")?, p.f32("black_angle")?],
+            black_generation: p.f32("black_generation")?,
+        },
+    };
+    Ok(halftone::halftone(&img, p.f32("cell_size")?, DotShape::named(p.str("shape")?)?, separation)?)
+}
+
+/// Every effect usable as a pipeline step, in the order they are listed.
+pub static EFFECTS: &[EffectSpec] = &[
+    // Color
+    EffectSpec {
+        name: "grayscale",
+        category: Category::Color,
+        description: "Converts the image to grayscale",
+        params: &[],
+        neighborhood: Neighborhood::Point,
+        linear_light: false,
+        full_depth: true,
+        alpha: AlphaPolicy::Preserve,
+        apply: |img, _| Ok(img.grayscale()),
+    },
+    EffectSpec {
+        name: "invert",
+        category: Category::Color,
+        description: "Inverts the colors of the image",
+        params: &[],
+        neighborhood: Neighborhood::Point,
+        linear_light: false,
+        full_depth: true,
+        alpha: AlphaPolicy::Preserve,
+        apply: |mut img, _| {
+            img.invert();
+            Ok(img)
+        },
+    },
+    EffectSpec {
+        name: "brightness",
+        category: Category::Color,
+        description: "Multiplies the RGB channels by a factor",
+        params: &[float("factor", 1.0, Some((0.0, 2.0)), "Brightness multiplier")],
+        neighborhood: Neighborhood::Point,
+        linear_light: true,
+        full_depth: true,
+        alpha: AlphaPolicy::Preserve,
+        apply: |img, p| Ok(colorfx::brightness(&img, p.f32("factor")?)?),
+    },
+    EffectSpec {
+        name: "sepia",
+        category: Category::Color,
+        description: "Applies a sepia tone",
+        params: &[],
+        neighborhood: Neighborhood::Point,
+        linear_light: false,
+        full_depth: true,
+        alpha: AlphaPolicy::Preserve,
+        apply: |img, _| Ok(colorfx::sepia(&img)?),
+    },
+    EffectSpec {
+        name: "contrast",
+        category: Category::Color,
+        description: "Scales the distance of each channel from mid-gray",
+        params: &[float("factor", 1.0, None, "Contrast multiplier")],
+        neighborhood: Neighborhood::Point,
+        linear_light: true,
+        full_depth: true,
+        alpha: AlphaPolicy::Preserve,
+        apply: |img, p| Ok(colorfx::contrast(&img, p.f32("factor")?)),
+    },
+    EffectSpec {
+        name: "curves",
+        category: Category::Color,
+        description: "Tone curves through control points, per channel and for all of them",
+        params: &[
+            optional("master", ParamKind::Points, None, "Curve applied to every channel, after the channel's own"),
+            optional("red", ParamKind::Points, None, "Curve of the red channel"),
+            optional("green", ParamKind::Points, None, "Curve of the green channel"),
+            optional("blue", ParamKind::Points, None, "Curve of the blue channel"),
+        ],
+        neighborhood: Neighborhood::Point,
+        linear_light: false,
+        full_depth: true,
+        alpha: AlphaPolicy::Preserve,
+        apply: apply_curves,
+    },
+    EffectSpec {
+        name: "levels",
+        category: Category::Color,
+        description: "Stretches an input tone range over an output range, with a midtone gamma",
+        params: &[
+            float("in_black", 0.0, BYTE, "Input value that becomes the output black"),
+            float("in_white", 255.0, BYTE, "Input value that becomes the output white"),
+            float("gamma", 1.0, Some((0.01, 10.0)), "Midtone gamma, above 1 brightens"),
+            float("out_black", 0.0, BYTE, "Darkest output value"),
+            float("out_white", 255.0, BYTE, "Brightest output value"),
+        ],
+        neighborhood: Neighborhood::Point,
+        linear_light: false,
+        full_depth: true,
+        alpha: AlphaPolicy::Preserve,
+        apply: |img, p| Ok(tone::levels(&img, p.f32("in_black")?, p.f32("in_white")?, p.f32("gamma")?, p.f32("out_black")?, p.f32("out_white")?)?),
+    },
+    EffectSpec {
+        name: "saturation",
+        category: Category::Color,
+        description: "Scales the color saturation",
+        params: &[float("factor", 1.0, NON_NEGATIVE, "Saturation multiplier")],
+        neighborhood: Neighborhood::Point,
+        linear_light: false,
+        full_depth: true,
+        alpha: AlphaPolicy::Preserve,
+        apply: |img, p| Ok(colorfx::saturation(&img, p.f32("factor")?)?),
+    },
+    EffectSpec {
+        name: "add_noise",
+        category: Category::Color,
+        description: "Randomly offsets the color of a share of the pixels",
+        params: &[float("intensity", 0.1, UNIT, "Share of pixels that receive noise")],
+        neighborhood: Neighborhood::Point,
+        linear_light: false,
+        full_depth: true,
+        alpha: AlphaPolicy::Preserve,
+        apply: |img, p| Ok(colorfx::add_noise(&img, p.f32("intensity")?)?),
+    },
+    EffectSpec {
+        name: "deepfry",
+        category: Category::Color,
+        description: "Over-the-top contrast, brightness, saturation and noise",
+        params: &[float("factor", 1.0, NON_NEGATIVE, "Intensity of the effect")],
+        neighborhood: Neighborhood::Point,
+        linear_light: false,
+        full_depth: true,
+        alpha: AlphaPolicy::Preserve,
+        apply: |img, p| Ok(colorfx::deep_fry(&img, p.f32("factor")?)?),
+    },
+    EffectSpec {
+        name: "hue_rotate",
+        category: Category::Color,
+        description: "Rotates every hue around the color wheel",
+        params: &[float("angle", 90.0, None, "Rotation in degrees")],
+        neighborhood: Neighborhood::Point,
+        linear_light: false,
+        full_depth: true,
+        alpha: AlphaPolicy::Preserve,
+        apply: |img, p| Ok(colorfx::hue_rotate(&img, p.f32("angle")?)?),
+    },
+    EffectSpec {
+        name: "color_replacer",
+        category: Category::Color,
+        description: "Replaces colors perceptually close to a target color, with a soft edge",
+        params: &[
+            string("target_color", "red", "Color to replace (hex, rgb(), hsl() or a CSS name)"),
+            string("replacement_color", "blue", "Color to replace it with"),
+            float("tolerance", 20.0, Some((0.0, 100.0)), "Largest perceptual distance (delta E) from the target that is fully replaced"),
+            float("softness", 10.0, Some((0.0, 100.0)), "Distance beyond the tolerance over which the replacement fades out"),
+            choice("metric", replace::METRICS, "ciede2000", "Color difference formula"),
+            choice("mode", replace::MODES, "color", "color paints the replacement, hue only turns the hue and keeps the shading"),
+        ],
+        neighborhood: Neighborhood::Point,
+        linear_light: false,
+        full_depth: true,
+        alpha: AlphaPolicy::Preserve,
+        apply: apply_color_replacer,
+    },
+    EffectSpec {
+        name: "vaporwave",
+        category: Category::Color,
+        description: "Pink and purple vaporwave tint",
+        params: &[],
+        neighborhood: Neighborhood::Point,
+        linear_light: false,
+        full_depth: true,
+        alpha: AlphaPolicy::Preserve,
+        apply: |img, _| Ok(colorfx::vaporwave(&img)?),
+    },
+    EffectSpec {
+        name: "apply_lut",
+        category: Category::Color,
+        description: "Applies a 1D or 3D color lookup table from a .cube file",
+        params: &[
+            file(required("path", ParamKind::Str, None, "The .cube file")),
+            choice("interpolation", lut::INTERPOLATIONS, "tetrahedral", "How 3D LUTs are read between their lattice points"),
+            float("strength", 1.0, UNIT, "Mix between the original colors and the LUT's"),
+        ],
+        neighborhood: Neighborhood::Point,
+        linear_light: false,
+        full_depth: true,
+        alpha: AlphaPolicy::Preserve,
+        apply: |img, p| {
+            let lut = lut::load_cached(p.str("path")?)?;
+            Ok(lut::apply(&img, &lut, lut::Interpolation::named(p.str("interpolation")?)?, p.f32("strength")?))
+        },
+    },
+    EffectSpec {
+        name: "dither",
+        category: Category::Color,
+        description: "Ordered dithering with a threshold map, or error diffusion",
+        params: &[
+            int("levels", 4, Some((2.0, 255.0)), "Quantization levels per channel"),
+            choice("method", DITHER_METHODS, "bayer", "Ordered threshold map, or the error-diffusion kernel to use"),
+            choice("mode", &["channel", "luminance"], "channel", "Dither each channel, or the grayscale luminance only"),
+            optional("matrix_size", ParamKind::Int, POSITIVE_INT, "Size of the generated map: Bayer (a power of two up to 256, default 4), blue noise (up to 128, default 64), dot or line cell (up to 256, default 8)"),
+            file(optional("threshold_map", ParamKind::Str, None, "Grayscale image tiled as the thresholds of the custom method")),
+            px(optional("point_size", ParamKind::Int, POSITIVE_INT, "Size in pixels of each threshold map cell (default 1)")),
+            optional("threshold_bias", ParamKind::Float, None, "Offset added to every ordered threshold (default 0.0)"),
+            boolean("serpentine", true, "Scan every other row right to left when diffusing error"),
+        ],
+        neighborhood: Neighborhood::Frame,
+        linear_light: false,
+        full_depth: true,
+        alpha: AlphaPolicy::Preserve,
+        apply: |img, p| {
+            let img = if p.str("mode")? == "luminance" { img.grayscale() } else { img };
+            let tile;
+            let map = match p.str("method")? {
+                "bayer" => ThresholdMap::Bayer,
+                "blue-noise" => ThresholdMap::BlueNoise,
+                "clustered-dot" => ThresholdMap::ClusteredDot,
+                "lines" => ThresholdMap::Lines,
+                "custom" => {
+                    let path = p.str("threshold_map")?;
+                    tile = image::open(path).map_err(|e| format!("Failed to read threshold map {}: {}", path, e))?.to_luma8();
+                    ThresholdMap::Image(&tile)
+                },
+                method => return Ok(colorfx::error_diffusion(&img, p.u8("levels")?, method, p.bool("serpentine")?)?),
+            };
+            Ok(colorfx::dither(&img, p.u8("levels")?, map, p.opt_u32("matrix_size")?, p.opt_u32("point_size")?, p.opt_f32("threshold_bias")?)?)
+        },
+    },
+    EffectSpec {
+        name: "palette",
+        category: Category::Color,
+        description: "Maps every pixel to the perceptually closest color of a palette",
+        params: &[
+            choice("palette", palette::BUILTIN_NAMES, "pico-8", "Built-in palette, used unless colors or reference is given"),
+            optional("colors", ParamKind::ColorList, None, "Custom palette"),
+            file(optional("reference", ParamKind::Str, None, "Image to extract the palette from")),
+            int("palette_size", 16, Some((1.0, 256.0)), "Number of colors extracted from the reference"),
+            choice("extraction", &["median-cut", "k-means"], "median-cut", "How colors are extracted from the reference"),
+            choice("dither", PALETTE_DITHERS, "none", "Ordered threshold map or error-diffusion kernel, as for dither"),
+            optional("matrix_size", ParamKind::Int, POSITIVE_INT, "Size of the ordered threshold map, as for dither"),
+            boolean("serpentine", true, "Scan every other row right to left when diffusing error"),
+            float("dither_strength", 1.0, NON_NEGATIVE, "Amount of dithering, 0 turns it off"),
+        ],
+        neighborhood: Neighborhood::Frame,
+        linear_light: false,
+        full_depth: true,
+        alpha: AlphaPolicy::Preserve,
+        apply: apply_palette,
+    },
+    EffectSpec {
+        name: "gradient_map",
+        category: Category::Color,
+        description: "Maps luminance to the colors of a gradient: duotones, colormaps or custom stops",
+        params: &[
+            choice("preset", gradient::PRESET_NAMES, "viridis", "Built-in gradient, used unless stops are given"),
+            optional("stops", ParamKind::Stops, None, "Custom gradient from black to white"),
+            choice("interpolation", gradient::INTERPOLATIONS, "oklab", "Color space the stops are blended in"),
+            boolean("reverse", false, "Map black to the end of the gradient and white to its start"),
+            float("strength", 1.0, UNIT, "Mix between the original colors and the mapped ones"),
+        ],
+        neighborhood: Neighborhood::Point,
+        linear_light: false,
+        full_depth: true,
+        alpha: AlphaPolicy::Preserve,
+        apply: apply_gradient_map,
+    },
+    EffectSpec {
+        name: "halftone",
+        category: Category::Color,
+        description: "Print-style halftone: CMYK or black dot screens at classic angles",
+        params: &[
+            choice("mode", &["cmyk", "monochrome"], "cmyk", "Four rotated ink screens, or a single black one"),
+            px(float("cell_size", 6.0, Some((1.0, INF)), "Distance between dots in pixels, the resolution divided by the lines per inch")),
+            choice("shape", halftone::SHAPE_NAMES, "round", "Shape of the dots"),
+            // The classic angles, with yellow where its moiré shows least
+            float("cyan_angle", 15.0, None, "Angle of the cyan screen in degrees"),
+            float("magenta_angle", 75.0, None, "Angle of the magenta screen in degrees"),
+            float("yellow_angle", 0.0, None, "Angle of the yellow screen in degrees"),
+            float("black_angle", 45.0, None, "Angle of the black screen in degrees, also used in monochrome mode"),
+            float("black_generation", 1.0, UNIT, "Share of the gray printed in black rather than the colored inks"),
+        ],
+        neighborhood: Neighborhood::Frame,
+        linear_light: false,
+        full_depth: true,
+        alpha: AlphaPolicy::Preserve,
+        apply: apply_halftone,
+    },
+    // Glitch
+    EffectSpec {
+        name: "blur",
+        category: Category::Glitch,
+        description: "Gaussian blur",
+        params: &[px(float("sigma", 2.0, NON_NEGATIVE, "Blur radius"))],
+        neighborhood: Neighborhood::Radius(|p| Ok(blur_radius(p.f32("sigma")?))),
+        linear_light: true,
+        full_depth: true,
+        alpha: AlphaPolicy::Premultiply,
+        apply: |img, p| Ok(img.blur(p.f32("sigma")?)),
+    },
+    EffectSpec {
+        name: "pixelate",
+        category: Category::Glitch,
+        description: "Replaces square blocks with a single color",
+        params: &[px(int("block_size", 10, POSITIVE_INT, "Size of the blocks in pixels"))],
+        neighborhood: Neighborhood::Frame,
+        linear_light: false,
+        full_depth: true,
+        alpha: AlphaPolicy::Process,
+        apply: |img, p| Ok(glitchfx::pixelate(&img, p.u32("block_size")?)),
+    },
+    EffectSpec {
+        name: "oil_painting",
+        category: Category::Glitch,
+        description: "Paints each pixel with the dominant intensity of its neighborhood",
+        params: &[
+            px(int("radius", 4, NON_NEGATIVE, "Neighborhood radius in pixels")),
+            int("intensity", 30, Some((1.0, 255.0)), "Number of intensity buckets"),
+        ],
+        neighborhood: Neighborhood::Radius(|p| p.u32("radius")),
+        linear_light: true,
+        full_depth: true,
+        alpha: AlphaPolicy::Preserve,
+        apply: |img, p| Ok(glitchfx::oil_painting(&img, p.u32("radius")?, p.u8("intensity")?)),
+    },
+    EffectSpec {
+        name: "glitch",
+        category: Category::Glitch,
+        description: "Shifts random bands of rows or columns",
+        params: &[
+            int("amount", 50, NON_NEGATIVE, "Number of shifted bands"),
+            px(int("max_offset", 10, NON_NEGATIVE, "Maximum shift in pixels")),
+            px(int("max_band", 19, POSITIVE_INT, "Maximum thickness of a band in pixels")),
+            choice("direction", &["horizontal", "vertical"], "horizontal", "Orientation of the bands"),
+            boolean("noisy", false, "Fill shifted bands with random pixels"),
+            optional("seed", ParamKind::Int, NON_NEGATIVE, "Seed that picks the same bands at any resolution (default random)"),
+        ],
+        neighborhood: Neighborhood::Frame,
+        linear_light: false,
+        full_depth: false,
+        alpha: AlphaPolicy::Process,
+        apply: |img, p| {
+            Ok(glitchfx::glitch(&img, p.u32("amount")?, p.i32("max_offset")?, p.u32("max_band")?, p.str("direction")?, p.bool("noisy")?, p.opt_u32("seed")?))
+        },
+    },
+    EffectSpec {
+        name: "pixel_sort",
+        category: Category::Glitch,
+        description: "Sorts runs of pixels by brightness",
+        params: &[
+            int("low-threshold", 150, BYTE, "Pixels darker than this are not sorted"),
+            int("high-threshold", 200, BYTE, "Pixels brighter than this are not sorted"),
+            choice("direction", &["row", "column", "both"], "row", "Sort along rows, columns or both"),
+            px(int("window_size", 100, NON_NEGATIVE, "Length of the sorted runs, 0 sorts whole lines")),
+        ],
+        neighborhood: Neighborhood::Frame,
+        linear_light: false,
+        full_depth: false,
+        alpha: AlphaPolicy::Preserve,
+        apply: |img, p| Ok(glitchfx::pixel_sort(&img, p.str("direction")?, p.u8("low-threshold")?, p.u8("high-threshold")?, p.u32("window_size")? as usize)?),
+    },
+    EffectSpec {
+        name: "desync",
+        category: Category::Glitch,
+        description: "Shifts the red and blue channels in opposite directions",
+        params: &[
+            px(int("x_shift", 10, None, "Horizontal shift in pixels")),
+            px(int("y_shift", 10, None, "Vertical shift in pixels")),
+        ],
+        neighborhood: Neighborhood::Frame,
+        linear_light: false,
+        full_depth: true,
+        alpha: AlphaPolicy::Preserve,
+        apply: |img, p| Ok(glitchfx::desync(&img, p.i32("x_shift")?, p.i32("y_shift")?)?),
+    },
+    EffectSpec {
+        name: "wind",
+        category: Category::Glitch,
+        description: "Smears pixels in one direction",
+        params: &[
+            choice("direction", &["left", "right", "up", "down"], "right", "Direction of the wind"),
+            px(int("strength", 10, NON_NEGATIVE, "Length of the smear in pixels")),
+        ],
+        neighborhood: Neighborhood::Radius(|p| p.u32("strength")),
+        linear_light: true,
+        full_depth: true,
+        alpha: AlphaPolicy::Premultiply,
+        apply: |img, p| Ok(glitchfx::wind(&img, p.str("direction")?, p.u32("strength")?)?),
+    },
+    EffectSpec {
+        name: "scan_lines",
+        category: Category::Glitch,
+        description: "Darkens evenly spaced lines",
+        params: &[
+            px(int("line_thickness", 2, NON_NEGATIVE, "Thickness of the lines in pixels")),
+            px(int("line_spacing", 10, POSITIVE_INT, "Gap between lines in pixels")),
+            float("opacity", 0.5, UNIT, "How much the lines darken the image"),
+            float("angle", 0.0, None, "Angle of the lines in radians"),
+        ],
+        neighborhood: Neighborhood::Frame,
+        linear_light: false,
+        full_depth: true,
+        alpha: AlphaPolicy::Preserve,
+        apply: |img, p| Ok(glitchfx::scan_lines(&img, Some(p.u32("line_thickness")?), Some(p.u32("line_spacing")?), Some(p.f32("angle")?), Some(p.f32("opacity")?))?),
+    },
+    // Edges
+    EffectSpec {
+        name: "neon_edge",
+        category: Category::Edges,
+        description: "Glowing Sobel edges on black",
+        params: &[
+            float("strength", 1.0, None, "Edge strength multiplier"),
+            float("color_shift", 0.0, None, "Shifts edges towards red (positive) or blue (negative)"),
+            float("brightness", 1.0, None, "Overall brightness"),
+        ],
+        neighborhood: Neighborhood::Radius(|_| Ok(1)),
+        linear_light: false,
+        full_depth: false,
+        alpha: AlphaPolicy::Preserve,
+        apply: |img, p| Ok(edgesfx::neon_edge(&img, p.f32("strength")?, p.f32("color_shift")?, p.f32("brightness")?)?),
+    },
+    EffectSpec {
+        name: "sketch",
+        category: Category::Edges,
+        description: "Grayscale Laplacian line drawing",
+        params: &[
+            float("intensity", 10.0, None, "Line intensity"),
+            float("contrast", 1.0, None, "Contrast adjustment"),
+            boolean("invert", false, "Dark lines on white instead of white on black"),
+        ],
+        neighborhood: Neighborhood::Radius(|_| Ok(1)),
+        linear_light: false,
+        full_depth: false,
+        alpha: AlphaPolicy::Preserve,
+        apply: |img, p| Ok(edgesfx::sketch(&img, p.f32("intensity")?, p.f32("contrast")?, p.bool("invert")?)?),
+    },
+    EffectSpec {
+        name: "emboss",
+        category: Category::Edges,
+        description: "Grayscale relief lit from an angle",
+        params: &[
+            float("strength", 100000.0, None, "Relief strength"),
+            float("angle", 45.0, None, "Light angle in degrees"),
+        ],
+        neighborhood: Neighborhood::Radius(|_| Ok(1)),
+        linear_light: false,
+        full_depth: false,
+        alpha: AlphaPolicy::Preserve,
+        apply: |img, p| Ok(edgesfx::emboss(&img, p.f32("strength")?, p.f32("angle")?)?),
+    },
+    EffectSpec {
+        name: "quantized_edge",
+        category: Category::Edges,
+        description: "Canny edges quantized to a few gray levels",
+        params: &[
+            int("threshold", 80, NON_NEGATIVE, "Minimum gradient magnitude of an edge"),
+            int("level", 1, Some((1.0, 255.0)), "Quantization levels"),
+        ],
+        neighborhood: Neighborhood::Radius(|_| Ok(CANNY_RADIUS)),
+        linear_light: false,
+        full_depth: false,
+        alpha: AlphaPolicy::Preserve,
+        apply: |img, p| Ok(edgesfx::quantized_edge(&img, p.u8("level")?, p.u32("threshold")? as f32)?),
+    },
+    EffectSpec {
+        name: "extrusion_edge",
+        category: Category::Edges,
+        description: "Extrudes Canny edges away from the center",
+        params: &[
+            int("threshold", 100, NON_NEGATIVE, "Minimum gradient magnitude of an edge"),
+            float("strength", 2.0, None, "Extrusion distance multiplier"),
+            int("depth", 15, NON_NEGATIVE, "Number of extrusion layers"),
+        ],
+        neighborhood: Neighborhood::Frame,
+        linear_light: false,
+        full_depth: false,
+        alpha: AlphaPolicy::Preserve,
+        apply: |img, p| Ok(edgesfx::edge_extrusion(&img, p.f32("strength")?, p.u32("depth")?, p.u32("threshold")? as f32)?),
+    },
+    // Geometry
+    EffectSpec {
+        name: "rotate",
+        category: Category::Geometry,
+        description: "Rotates the image clockwise by any angle",
+        params: &[
+            float("angle", 90.0, None, "Rotation in degrees"),
+            choice("interpolation", INTERPOLATIONS, "bilinear", "Resampling used for angles that are not multiples of 90"),
+            choice("canvas", &["expand", "crop"], "expand", "Grow the canvas to fit the rotated image, or keep the input size"),
+            string("fill", "#00000000", "Color of the uncovered corners (hex, rgb(), hsl() or a CSS name)"),
+        ],
+        neighborhood: Neighborhood::Frame,
+        linear_light: true,
+        full_depth: true,
+        alpha: AlphaPolicy::Process,
+        apply: |img, p| Ok(geometryfx::rotate(&img, p.f32("angle")?, p.str("interpolation")?, p.str("canvas")? == "expand", p.color("fill")?)?),
+    },
+    EffectSpec {
+        name: "crop",
+        category: Category::Geometry,
+        description: "Keeps a rectangle of the image",
+        params: &[
+            length("x", "0", "Left edge"),
+            length("y", "0", "Top edge"),
+            length("width", "100%", "Width of the kept area"),
+            length("height", "100%", "Height of the kept area"),
+        ],
+        neighborhood: Neighborhood::Frame,
+        linear_light: false,
+        full_depth: true,
+        alpha: AlphaPolicy::Process,
+        apply: |img, p| {
+            let (width, height) = img.dimensions();
+            Ok(geometryfx::crop(&img, p.length("x", width)?, p.length("y", height)?, p.length("width", width)?, p.length("height", height)?)?)
+        },
+    },
+    EffectSpec {
+        name: "resize",
+        category: Category::Geometry,
+        description: "Scales the image; a missing side keeps the aspect ratio",
+        params: &[
+            ParamSpec { name: "width", kind: ParamKind::Length, default: ParamDefault::Unset, range: None, pixels: true, file: false, description: "New width (default follows height)" },
+            ParamSpec { name: "height", kind: ParamKind::Length, default: ParamDefault::Unset, range: None, pixels: true, file: false, description: "New height (default follows width)" },
+            choice("filter", &["nearest", "triangle", "catmullrom", "gaussian", "lanczos3"], "lanczos3", "Resampling filter"),
+        ],
+        neighborhood: Neighborhood::Frame,
+        linear_light: true,
+        full_depth: true,
+        alpha: AlphaPolicy::Premultiply,
+        apply: |img, p| {
+            let (width, height) = img.dimensions();
+            Ok(geometryfx::resize(&img, p.opt_length("width", width)?, p.opt_length("height", height)?, p.str("filter")?)?)
+        },
+    },
+    EffectSpec {
+        name: "flip",
+        category: Category::Geometry,
+        description: "Flips the image",
+        params: &[choice("direction", &["horizontal", "vertical", "both"], "horizontal", "Horizontal mirrors left and right")],
+        neighborhood: Neighborhood::Frame,
+        linear_light: false,
+        full_depth: true,
+        alpha: AlphaPolicy::Process,
+        apply: |img, p| Ok(geometryfx::flip(&img, p.str("direction")?)?),
+    },
+    EffectSpec {
+        name: "mirror",
+        category: Category::Geometry,
+        description: "Reflects one half of the image onto the other",
+        params: &[choice("side", &["left", "right", "top", "bottom"], "left", "Half that is kept and reflected")],
+        neighborhood: Neighborhood::Frame,
+        linear_light: false,
+        full_depth: true,
+        alpha: AlphaPolicy::Process,
+        apply: |img, p| Ok(geometryfx::mirror(&img, p.str("side")?)?),
+    },
+    EffectSpec {
+        name: "pad",
+        category: Category::Geometry,
+        description: "Adds a border around the image",
+        params: &[
+            length("top", "0", "Border above, percentages of the height"),
+            length("right", "0", "Border to the right, percentages of the width"),
+            length("bottom", "0", "Border below, percentages of the height"),
+            length("left", "0", "Border to the left, percentages of the width"),
+            string("color", "#00000000", "Border color (hex, rgb(), hsl() or a CSS name)"),
+        ],
+        neighborhood: Neighborhood::Frame,
+        linear_light: false,
+        full_depth: true,
+        alpha: AlphaPolicy::Process,
+        apply: |img, p| {
+            let (width, height) = img.dimensions();
+            Ok(geometryfx::pad(&img, p.length("top", height)?, p.length("right", width)?, p.length("bottom", height)?, p.length("left", width)?, p.color("color")?)?)
+        },
+    },
+];
+
+pub fn find(name: &str) -> Option<&'static EffectSpec> {
+    EFFECTS.iter().find(|e| e.name == name)
+}
+
+pub fn by_category(category: Category) -> impl Iterator<Item = &'static EffectSpec> {
+    EFFECTS.iter().filter(move |e| e.category == category)
+}
+
+/// Size of the image `effect` makes from a `width` by `height` one, worked
+/// out from the params without running it. Only the geometry effects change
+/// the size.
+pub fn output_size(effect: &EffectSpec, width: u32, height: u32, p: &Params) -> Result<(u64, u64), Box<dyn Error>> {
+    Ok(match effect.name {
+        "rotate" => geometryfx::rotated_size(width, height, p.f32("angle")?, p.str("canvas")? == "expand"),
+        "crop" => {
+            let (x, y) = (p.length("x", width)?.min(width), p.length("y", height)?.min(height));
+            (p.length("width", width)?.min(width - x) as u64, p.length("height", height)?.min(height - y) as u64)
+        },
+        "resize" => geometryfx::resized_size(width, height, p.opt_length("width", width)?, p.opt_length("height", height)?),
+        "pad" => geometryfx::padded_size(width, height, p.length("top", height)?, p.length("right", width)?, p.length("bottom", height)?, p.length("left", width)?),
+        _ => (width as u64, height as u64),
+    })
+}
@@ -1,23 +1,21 @@
 // File: src/fx_json_generator.rs
 
 use rand::prelude::*;
-use serde_json::{json, Value};
+use serde_json::{json, Map, Value};
+
+use crate::effects::{self, EffectSpec, ParamKind};
 
 pub fn generate_random_pipeline(num_effects: usize) -> Value {
     let mut rng = rand::thread_rng();
-    let effects = vec![
-        "grayscale", "invert", "brightness", "sepia", "contrast", "saturation",
-        "add_noise", "deepfry", "hue_rotate", "vaporwave",
-        "blur", "pixelate", "oil_painting", "glitch", "pixel_sort", "rotate",
-        "desync", "wind", "scan_lines", "neon_edge", "sketch", "emboss",
-        "quantized_edge", "extrusion_edge"
-    ];
+    let candidates: Vec<&EffectSpec> = effects::EFFECTS.iter()
+        .filter(|effect| !effect.has_required())
+        .collect();
 
     let transformations: Vec<Value> = (0..num_effects)
         .map(|_| {
-            let effect = effects.choose(&mut rng).unwrap();
+            let effect = candidates.choose(&mut rng).unwrap();
             json!({
-                "name": effect,
+                "name": effect.name,
                 "params": generate_params(effect, &mut rng)
             })
         })
@@ -26,8 +24,8 @@ pub fn generate_random_pipeline(num_effects: usize) -> Value {
     json!({ "transformations": transformations })
 }
 
-fn generate_params(effect: &str, rng: &mut impl Rng) -> Value {
-    match effect {
+fn generate_params(effect: &EffectSpec, rng: &mut impl Rng) -> Value {
+    match effect.name {
         "grayscale" | "invert" | "sepia" | "vaporwave" => json!({}),
         "brightness" | "contrast" | "saturation" => {
             json!({ "factor": rng.gen_range(0.5..2.0) })
@@ -50,7 +48,7 @@ fn generate_params(effect: &str, rng: &mut impl Rng) -> Value {
         "pixel_sort" => json!({
             "low-threshold": rng.gen_range(0..100),
             "high-threshold": rng.gen_range(100..255),
-            "direction": random_choice(rng, &["horizontal", "vertical"]),
+            "direction": random_choice(rng, &["row", "column", "both"]),
             "window_size": rng.gen_range(0..50)
         }),
         "rotate" => json!({ "angle": rng.gen_range(0.0..360.0) }),
@@ -83,16 +81,49 @@ fn generate_params(effect: &str, rng: &mut impl Rng) -> Value {
             "angle": rng.gen_range(0.0..360.0)
         }),
         "quantized_edge" => json!({
-            "threshold": rng.gen_range(50.0..150.0),
+            "threshold": rng.gen_range(50..150),
             "level": rng.gen_range(1..5)
         }),
         "extrusion_edge" => json!({
-            "threshold": rng.gen_range(50.0..150.0),
+            "threshold": rng.gen_range(50..150),
             "strength": rng.gen_range(1.0..5.0),
             "depth": rng.gen_range(5..30)
         }),
-        _ => json!({})
+        // The white point has to stay above the black point
+        "levels" => json!({
+            "in_black": rng.gen_range(0.0..64.0),
+            "in_white": rng.gen_range(192.0..255.0),
+            "gamma": rng.gen_range(0.5..2.0),
+            "out_black": rng.gen_range(0.0..64.0),
+            "out_white": rng.gen_range(192.0..255.0)
+        }),
+        // Every method but "custom", which needs a threshold image
+        "dither" => json!({
+            "levels": rng.gen_range(2..8),
+            "method": random_choice(rng, &["bayer", "blue-noise", "clustered-dot", "lines", "floyd-steinberg", "atkinson", "jarvis-judice-ninke", "stucki", "burkes", "sierra"]),
+            "mode": random_choice(rng, &["channel", "luminance"])
+        }),
+        _ => generate_from_spec(effect, rng)
+    }
+}
+
+// Params for effects without hand-tuned ranges: choices are picked at random,
+// bounded numbers drawn from their valid range and everything else defaulted.
+fn generate_from_spec(effect: &EffectSpec, rng: &mut impl Rng) -> Value {
+    let mut params = Map::new();
+    for param in effect.params {
+        let value = match (param.kind, param.range) {
+            (ParamKind::Choice(choices), _) => json!(random_choice(rng, choices)),
+            (ParamKind::Bool, _) => json!(rng.gen_bool(0.5)),
+            (ParamKind::Float, Some((min, max))) if min.is_finite() && max.is_finite() => json!(rng.gen_range(min..=max)),
+            (ParamKind::Int, Some((min, max))) if min.is_finite() && max.is_finite() => json!(rng.gen_range(min as i64..=max as i64)),
+            _ => param.default.to_json(),
+        };
+        if !value.is_null() {
+            params.insert(param.name.to_string(), value);
+        }
     }
+    Value::Object(params)
 }
 
 fn random_choice<T>(rng: &mut impl Rng, choices: &[T]) -> T 
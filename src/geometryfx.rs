@@ -0,0 +1,276 @@
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView, Rgba, RgbaImage};
+
+use crate::depth::{self, RgbaBuffer, Sample};
+use crate::parallel;
+
+// Reads a pixel as premultiplied floats, treating anything outside the
+// image as the fill color so edges blend into it.
+fn premultiplied<S: Sample>(img: &RgbaBuffer<S>, x: i64, y: i64, fill: Rgba<S>) -> [f32; 4] {
+    let pixel = if x >= 0 && y >= 0 && x < img.width() as i64 && y < img.height() as i64 {
+        *img.get_pixel(x as u32, y as u32)
+    } else {
+        fill
+    };
+    let alpha = pixel[3].to_f32() / 255.0;
+    [pixel[0].to_f32() * alpha, pixel[1].to_f32() * alpha, pixel[2].to_f32() * alpha, pixel[3].to_f32()]
+}
+
+fn unpremultiply<S: Sample>(sum: [f32; 4]) -> Rgba<S> {
+    let alpha = sum[3].clamp(0.0, 255.0);
+    if alpha <= 0.0 {
+        return Rgba([S::from_f32(0.0); 4]);
+    }
+    let scale = 255.0 / alpha;
+    Rgba([
+        S::from_f32_rounded((sum[0] * scale).clamp(0.0, 255.0)),
+        S::from_f32_rounded((sum[1] * scale).clamp(0.0, 255.0)),
+        S::from_f32_rounded((sum[2] * scale).clamp(0.0, 255.0)),
+        S::from_f32_rounded(alpha),
+    ])
+}
+
+// The fill color at the depth of the buffer it is painted into
+fn fill_color<S: Sample>(fill: Rgba<u8>) -> Rgba<S> {
+    Rgba(fill.0.map(|c| S::from_f32(c as f32)))
+}
+
+// Catmull-Rom weights for the 4 taps around a sample
+fn cubic_weights(t: f32) -> [f32; 4] {
+    let t2 = t * t;
+    let t3 = t2 * t;
+    [
+        -0.5 * t3 + t2 - 0.5 * t,
+        1.5 * t3 - 2.5 * t2 + 1.0,
+        -1.5 * t3 + 2.0 * t2 + 0.5 * t,
+        0.5 * t3 - 0.5 * t2,
+    ]
+}
+
+/// Samples `img` at continuous pixel coordinates (pixel centers at +0.5).
+fn sample<S: Sample>(img: &RgbaBuffer<S>, x: f32, y: f32, interpolation: &str, fill: Rgba<S>) -> Rgba<S> {
+    match interpolation {
+        "nearest" => {
+            let sum = premultiplied(img, x.floor() as i64, y.floor() as i64, fill);
+            unpremultiply(sum)
+        },
+        "bilinear" => {
+            let (fx, fy) = (x - 0.5, y - 0.5);
+            let (x0, y0) = (fx.floor(), fy.floor());
+            let (tx, ty) = (fx - x0, fy - y0);
+            let mut sum = [0.0; 4];
+            for (dy, wy) in [(0, 1.0 - ty), (1, ty)] {
+                for (dx, wx) in [(0, 1.0 - tx), (1, tx)] {
+                    let p = premultiplied(img, x0 as i64 + dx, y0 as i64 + dy, fill);
+                    for c in 0..4 {
+                        sum[c] += p[c] * wx * wy;
+                    }
+                }
+            }
+            unpremultiply(sum)
+        },
+        _ => {
+            let (fx, fy) = (x - 0.5, y - 0.5);
+            let (x0, y0) = (fx.floor(), fy.floor());
+            let wx = cubic_weights(fx - x0);
+            let wy = cubic_weights(fy - y0);
+            let mut sum = [0.0; 4];
+            for (j, wy) in wy.iter().enumerate() {
+                for (i, wx) in wx.iter().enumerate() {
+                    let p = premultiplied(img, x0 as i64 + i as i64 - 1, y0 as i64 + j as i64 - 1, fill);
+                    for c in 0..4 {
+                        sum[c] += p[c] * wx * wy;
+                    }
+                }
+            }
+            unpremultiply(sum)
+        },
+    }
+}
+
+/// Largest canvas, in pixels, that the geometry effects will allocate. 16384
+/// by 16384 is 1 GiB at 8 bits per channel.
+pub const MAX_CANVAS_PIXELS: u64 = 1 << 28;
+
+// Checks an output size before anything is allocated for it
+fn canvas(width: u64, height: u64) -> Result<(u32, u32), String> {
+    match (u32::try_from(width), u32::try_from(height)) {
+        (Ok(w), Ok(h)) if width * height <= MAX_CANVAS_PIXELS => Ok((w, h)),
+        _ => Err(format!("Output size {}x{} is larger than the {} pixel limit", width, height, MAX_CANVAS_PIXELS)),
+    }
+}
+
+// Rotation: arbitrary angles, clockwise in degrees. With `expand` the canvas
+// grows to fit the whole rotated image, otherwise it keeps the input size and
+// the corners are cropped. Uncovered areas are filled with `fill`.
+pub fn rotate(img: &DynamicImage, angle: f32, interpolation: &str, expand: bool, fill: Rgba<u8>) -> Result<DynamicImage, String> {
+    if !matches!(interpolation, "nearest" | "bilinear" | "bicubic") {
+        return Err(format!("Invalid interpolation: {}", interpolation));
+    }
+    let (width, height) = img.dimensions();
+    let angle = angle.rem_euclid(360.0);
+
+    // Exact multiples of 90 are lossless when the canvas can follow the image
+    if expand || width == height {
+        match angle {
+            0.0 => return Ok(img.clone()),
+            90.0 => return Ok(img.rotate90()),
+            180.0 => return Ok(img.rotate180()),
+            270.0 => return Ok(img.rotate270()),
+            _ => {},
+        }
+    }
+
+    let (sin, cos) = angle.to_radians().sin_cos();
+    let (out_width, out_height) = rotated_size(width, height, angle, expand);
+    let (out_width, out_height) = canvas(out_width, out_height)?;
+
+    let rotation = Rotation { sin, cos, out_width, out_height, interpolation };
+    if depth::is_16bit(img) {
+        Ok(DynamicImage::ImageRgba16(rotation.apply(&depth::rgba16(img), fill_color(fill))))
+    } else {
+        Ok(DynamicImage::ImageRgba8(rotation.apply(&parallel::rgba(img), fill)))
+    }
+}
+
+/// Size of a `width` by `height` image rotated by `angle` degrees.
+pub fn rotated_size(width: u32, height: u32, angle: f32, expand: bool) -> (u64, u64) {
+    if !expand {
+        return (width as u64, height as u64);
+    }
+    let (sin, cos) = angle.rem_euclid(360.0).to_radians().sin_cos();
+    (
+        (width as f32 * cos.abs() + height as f32 * sin.abs()).round().max(1.0) as u64,
+        (width as f32 * sin.abs() + height as f32 * cos.abs()).round().max(1.0) as u64,
+    )
+}
+
+struct Rotation<'a> {
+    sin: f32,
+    cos: f32,
+    out_width: u32,
+    out_height: u32,
+    interpolation: &'a str,
+}
+
+impl Rotation<'_> {
+    fn apply<S: Sample>(&self, source: &RgbaBuffer<S>, fill: Rgba<S>) -> RgbaBuffer<S> {
+        let (sin, cos) = (self.sin, self.cos);
+        let (in_cx, in_cy) = (source.width() as f32 / 2.0, source.height() as f32 / 2.0);
+        let (out_cx, out_cy) = (self.out_width as f32 / 2.0, self.out_height as f32 / 2.0);
+
+        parallel::from_fn(self.out_width, self.out_height, |x, y| {
+            // Map each output pixel back into the source with the inverse rotation
+            let dx = x as f32 + 0.5 - out_cx;
+            let dy = y as f32 + 0.5 - out_cy;
+            let sx = dx * cos + dy * sin + in_cx;
+            let sy = -dx * sin + dy * cos + in_cy;
+            sample(source, sx, sy, self.interpolation, fill)
+        })
+    }
+}
+
+pub fn crop(img: &DynamicImage, x: u32, y: u32, width: u32, height: u32) -> Result<DynamicImage, String> {
+    let (img_width, img_height) = img.dimensions();
+    if x >= img_width || y >= img_height {
+        return Err(format!("Crop origin {}x{} is outside the {}x{} image", x, y, img_width, img_height));
+    }
+    // Clamp the size so crops that overshoot the edge keep what is there
+    let width = width.min(img_width - x);
+    let height = height.min(img_height - y);
+    if width == 0 || height == 0 {
+        return Err("Crop size must be at least 1x1".into());
+    }
+    Ok(img.crop_imm(x, y, width, height))
+}
+
+// Resize to the given size. When only one side is given the other follows the
+// aspect ratio.
+pub fn resize(img: &DynamicImage, width: Option<u32>, height: Option<u32>, filter: &str) -> Result<DynamicImage, String> {
+    let filter = match filter {
+        "nearest" => FilterType::Nearest,
+        "triangle" => FilterType::Triangle,
+        "catmullrom" => FilterType::CatmullRom,
+        "gaussian" => FilterType::Gaussian,
+        "lanczos3" => FilterType::Lanczos3,
+        _ => return Err(format!("Invalid resize filter: {}", filter)),
+    };
+    if width.is_none() && height.is_none() {
+        return Ok(img.clone());
+    }
+    let (img_width, img_height) = img.dimensions();
+    let (width, height) = resized_size(img_width, img_height, width, height);
+    if width == 0 || height == 0 {
+        return Err("Resize dimensions must be at least 1x1".into());
+    }
+    let (width, height) = canvas(width, height)?;
+    Ok(img.resize_exact(width, height, filter))
+}
+
+/// Size `resize` gives a `width` by `height` image.
+pub fn resized_size(width: u32, height: u32, new_width: Option<u32>, new_height: Option<u32>) -> (u64, u64) {
+    let aspect = width as f64 / height as f64;
+    match (new_width, new_height) {
+        (Some(w), Some(h)) => (w as u64, h as u64),
+        (Some(w), None) => (w as u64, (w as f64 / aspect).round() as u64),
+        (None, Some(h)) => ((h as f64 * aspect).round() as u64, h as u64),
+        (None, None) => (width as u64, height as u64),
+    }
+}
+
+pub fn flip(img: &DynamicImage, direction: &str) -> Result<DynamicImage, String> {
+    match direction {
+        "horizontal" => Ok(img.fliph()),
+        "vertical" => Ok(img.flipv()),
+        "both" => Ok(img.rotate180()),
+        _ => Err(format!("Invalid flip direction: {}", direction)),
+    }
+}
+
+// Mirror: reflects one half of the image onto the other half.
+pub fn mirror(img: &DynamicImage, side: &str) -> Result<DynamicImage, String> {
+    if !matches!(side, "left" | "right" | "top" | "bottom") {
+        return Err(format!("Invalid mirror side: {}", side));
+    }
+    if depth::is_16bit(img) {
+        Ok(DynamicImage::ImageRgba16(mirror_buffer(&depth::rgba16(img), side)))
+    } else {
+        Ok(DynamicImage::ImageRgba8(mirror_buffer(&parallel::rgba(img), side)))
+    }
+}
+
+fn mirror_buffer<S: Sample>(source: &RgbaBuffer<S>, side: &str) -> RgbaBuffer<S> {
+    let (width, height) = source.dimensions();
+
+    parallel::from_fn(width, height, |x, y| {
+        let (mirror_x, mirror_y) = (width - 1 - x, height - 1 - y);
+        let (sx, sy) = match side {
+            "left" if x >= width / 2 => (mirror_x, y),
+            "right" if x < width / 2 => (mirror_x, y),
+            "top" if y >= height / 2 => (x, mirror_y),
+            "bottom" if y < height / 2 => (x, mirror_y),
+            _ => (x, y),
+        };
+        *source.get_pixel(sx, sy)
+    })
+}
+
+pub fn pad(img: &DynamicImage, top: u32, right: u32, bottom: u32, left: u32, fill: Rgba<u8>) -> Result<DynamicImage, String> {
+    let (width, height) = img.dimensions();
+    let (width, height) = padded_size(width, height, top, right, bottom, left);
+    let (width, height) = canvas(width, height)?;
+    if depth::is_16bit(img) {
+        let mut output = RgbaBuffer::from_pixel(width, height, fill_color(fill));
+        image::imageops::replace(&mut output, &img.to_rgba16(), left, top);
+        Ok(DynamicImage::ImageRgba16(output))
+    } else {
+        let mut output = RgbaImage::from_pixel(width, height, fill);
+        image::imageops::replace(&mut output, &img.to_rgba8(), left, top);
+        Ok(DynamicImage::ImageRgba8(output))
+    }
+}
+
+/// Size of a `width` by `height` image with the given borders added.
+pub fn padded_size(width: u32, height: u32, top: u32, right: u32, bottom: u32, left: u32) -> (u64, u64) {
+    (width as u64 + left as u64 + right as u64, height as u64 + top as u64 + bottom as u64)
+}
@@ -1,7 +1,12 @@
-use image::{DynamicImage, GenericImage, GenericImageView, ImageBuffer, Pixel, Rgb, Rgba, RgbaImage};
-use rand::Rng;
+use image::{DynamicImage, GenericImageView, ImageBuffer, Rgb, Rgba, RgbaImage};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 
-pub fn pixel_sort(img: &DynamicImage, direction: &str, low_threshold:u8, high_threshold:u8, window_size:usize) -> DynamicImage {
+use crate::depth::{self, RgbaBuffer, Sample};
+use crate::geometryfx;
+use crate::parallel;
+
+pub fn pixel_sort(img: &DynamicImage, direction: &str, low_threshold:u8, high_threshold:u8, window_size:usize) -> Result<DynamicImage, String> {
     let (width, height) = img.dimensions();
     let mut output = ImageBuffer::new(width, height);
     let clean_direction = direction.trim().trim_matches('"').to_lowercase();
@@ -21,21 +26,22 @@ pub fn pixel_sort(img: &DynamicImage, direction: &str, low_threshold:u8, high_th
             sort_pixels(&rotated, &mut rotated_output, low_threshold, high_threshold, window_size);
             output = DynamicImage::ImageRgb8(rotated_output).rotate270().to_rgb8();
         },
-        _ => {
-            println!("Unrecognized direction: {}, defaulting to row", direction);
-            sort_pixels(img, &mut output, low_threshold, high_threshold, window_size);
-        }
+        _ => return Err(format!("Invalid pixel sort direction: {} (use row, column or both)", direction)),
     }
 
-    DynamicImage::ImageRgb8(output)
+    Ok(DynamicImage::ImageRgb8(output))
 }
 pub fn sort_pixels(img: &DynamicImage, output: &mut ImageBuffer<Rgb<u8>, Vec<u8>>, low_threshold: u8, high_threshold: u8, window_size: usize) {
-    for y in 0..img.height() {
-        let mut row: Vec<(u8, Rgb<u8>)> = (0..img.width())
-            .map(|x| {
-                let pixel = img.get_pixel(x, y).to_rgb();
+    let source = parallel::rgba(img);
+    let row_len = source.width() as usize * 4;
+    // Rows are sorted independently, so each one can go to its own thread
+    parallel::for_each_row(output, |y, output_row| {
+        let start = y as usize * row_len;
+        let mut row: Vec<(u8, Rgb<u8>)> = source.as_raw()[start..start + row_len]
+            .chunks_exact(4)
+            .map(|pixel| {
                 let brightness = ((pixel[0] as u32 + pixel[1] as u32 + pixel[2] as u32) / 3) as u8;
-                (brightness, pixel)
+                (brightness, Rgb([pixel[0], pixel[1], pixel[2]]))
             })
             .collect();
 
@@ -69,141 +75,177 @@ pub fn sort_pixels(img: &DynamicImage, output: &mut ImageBuffer<Rgb<u8>, Vec<u8>
             row = sorted_row;
         }
 
-        for (x, &(_, pixel)) in row.iter().enumerate() {
-            output.put_pixel(x as u32, y, pixel);
+        for (out, &(_, pixel)) in output_row.chunks_exact_mut(3).zip(row.iter()) {
+            out.copy_from_slice(&pixel.0);
         }
-    }
+    });
 }
 
 pub fn rotate(img: &DynamicImage, angle: f32) -> Result<DynamicImage, String> {
-
-    match angle.rem_euclid(360.0) {
-        0.0 => Ok(img.clone()),
-        90.0 => Ok(img.rotate90()),
-        180.0 => Ok(img.rotate180()),
-        270.0 => Ok(img.rotate270()),
-        _ => Err("Invalid angle specified".into()),
-    }
+    geometryfx::rotate(img, angle, "bilinear", true, Rgba([0, 0, 0, 0]))
 }
 
 pub fn desync(img: &DynamicImage, x_shift: i32, y_shift: i32) -> Result<DynamicImage, String> {
-
-    let (width, height) = img.dimensions();
-    let mut output = RgbaImage::new(width, height);
-
-    for y in 0..height {
-        for x in 0..width {
-            let pixel = img.get_pixel(x, y);
-            
-            // Shift red channel
-            let red_x = (x as i32 + x_shift).rem_euclid(width as i32) as u32;
-            let red_y = (y as i32 + y_shift).rem_euclid(height as i32) as u32;
-            output.put_pixel(red_x, red_y, Rgba([pixel[0], 0, 0, 255]));
-
-            // Keep green channel in place
-            output.get_pixel_mut(x, y)[1] = pixel[1];
-
-            // Shift blue channel in opposite direction
-            let blue_x = (x as i32 - x_shift).rem_euclid(width as i32) as u32;
-            let blue_y = (y as i32 - y_shift).rem_euclid(height as i32) as u32;
-            output.get_pixel_mut(blue_x, blue_y)[2] = pixel[2];
-        }
+    if depth::is_16bit(img) {
+        Ok(DynamicImage::ImageRgba16(desync_buffer(&depth::rgba16(img), x_shift, y_shift)))
+    } else {
+        Ok(DynamicImage::ImageRgba8(desync_buffer(&parallel::rgba(img), x_shift, y_shift)))
     }
+}
 
-    Ok(DynamicImage::ImageRgba8(output))
+fn desync_buffer<S: Sample>(source: &RgbaBuffer<S>, x_shift: i32, y_shift: i32) -> RgbaBuffer<S> {
+    let (width, height) = source.dimensions();
+    let (zero, opaque) = (S::from_u32(0), S::from_f32(255.0));
+    let shifted = |x: u32, y: u32, sign: i32| {
+        ((x as i32 + sign * x_shift).rem_euclid(width as i32) as u32, (y as i32 + sign * y_shift).rem_euclid(height as i32) as u32)
+    };
+    // Position in scan order, which decides which channels survive below
+    let order = |(x, y): (u32, u32)| y as u64 * width as u64 + x as u64;
+
+    // Red comes from the pixel shifted onto this one, blue from the pixel this
+    // one is shifted onto, and green stays in place. The shifted red is laid
+    // down as a whole pixel in scan order, so green and blue only survive when
+    // their source comes no earlier than the red one's.
+    parallel::from_fn(width, height, |x, y| {
+        let red = shifted(x, y, -1);
+        let blue = shifted(x, y, 1);
+        let green = if order((x, y)) >= order(red) { source.get_pixel(x, y)[1] } else { zero };
+        let blue = if order(blue) >= order(red) { source.get_pixel(blue.0, blue.1)[2] } else { zero };
+        Rgba([source.get_pixel(red.0, red.1)[0], green, blue, opaque])
+    })
 }
 
 pub fn wind(img: &DynamicImage, direction: &str, strength: u32) -> Result<DynamicImage, String> {
+    if depth::is_16bit(img) {
+        Ok(DynamicImage::ImageRgba16(wind_buffer(&depth::rgba16(img), direction, strength)))
+    } else {
+        Ok(DynamicImage::ImageRgba8(wind_buffer(&parallel::rgba(img), direction, strength)))
+    }
+}
 
-    let (width, height) = img.dimensions();
-    let mut output = RgbaImage::new(width, height);
-
-    for y in 0..height {
-        for x in 0..width {
-            let mut accumulator = [0u32; 4];
-            let mut count = 0;
-            for i in 0..strength {
-                let (sample_x, sample_y) = match direction {
-                    "up" => (x, (y + i).min(height - 1)),
-                    "down" => (x, y.saturating_sub(i)),
-                    "left" => ((x + i).min(width - 1), y),
-                    "right" => (x.saturating_sub(i), y),
-                    _ => (x, y), // Default case if direction is unrecognized
-                };
-
-                let pixel = img.get_pixel(sample_x, sample_y);
+fn wind_buffer<S: Sample>(source: &RgbaBuffer<S>, direction: &str, strength: u32) -> RgbaBuffer<S> {
+    if strength == 0 || !matches!(direction, "up" | "down" | "left" | "right") {
+        return source.clone();
+    }
+    let (width, height) = source.dimensions();
+    let row_len = width as usize * 4;
+    let data = source.as_raw();
+    let strength_usize = strength as usize;
+    let strength = strength as u64;
+    let mut output = RgbaBuffer::new(width, height);
+
+    // Each pixel is the average of `strength` pixels upwind of it, clamped at the edge
+    parallel::for_each_row(&mut output, |y, out| {
+        match direction {
+            "left" | "right" => {
+                let start = y as usize * row_len;
+                let row = &data[start..start + row_len];
+                let width = width as usize;
+                // Walk downwind so each sum reuses the previous one
+                let at = |k: usize| if direction == "right" { k } else { width - 1 - k };
+                let mut sum = [0u64; 4];
                 for c in 0..4 {
-                    accumulator[c] += pixel[c] as u32;
+                    sum[c] = row[at(0) * 4 + c].to_u32() as u64 * strength;
                 }
-                count += 1;
-            }
-
-            let final_pixel = Rgba([
-                (accumulator[0] / count) as u8,
-                (accumulator[1] / count) as u8,
-                (accumulator[2] / count) as u8,
-                (accumulator[3] / count) as u8,
-            ]);
-
-            output.put_pixel(x, y, final_pixel);
+                for k in 0..width {
+                    if k > 0 {
+                        let (added, removed) = (at(k), at(k.saturating_sub(strength_usize)));
+                        for c in 0..4 {
+                            sum[c] = sum[c] + row[added * 4 + c].to_u32() as u64 - row[removed * 4 + c].to_u32() as u64;
+                        }
+                    }
+                    let x = at(k);
+                    for c in 0..4 {
+                        out[x * 4 + c] = S::from_u32((sum[c] / strength) as u32);
+                    }
+                }
+            },
+            _ => {
+                let mut sum = vec![0u64; row_len];
+                for i in 0..strength_usize as u32 {
+                    let sample_y = match direction {
+                        "up" => (y + i).min(height - 1),
+                        _ => y.saturating_sub(i),
+                    } as usize;
+                    for (total, &value) in sum.iter_mut().zip(&data[sample_y * row_len..(sample_y + 1) * row_len]) {
+                        *total += value.to_u32() as u64;
+                    }
+                }
+                for (value, total) in out.iter_mut().zip(sum) {
+                    *value = S::from_u32((total / strength) as u32);
+                }
+            },
         }
-    }
+    });
 
-    Ok(DynamicImage::ImageRgba8(output))
+    output
 }
 
 pub fn pixelate(img: &DynamicImage, block_size: u32) -> DynamicImage {
-    let (width, height) = img.dimensions();
-    let mut output = DynamicImage::new_rgba8(width, height);
-
-    for y in (0..height).step_by(block_size as usize) {
-        for x in (0..width).step_by(block_size as usize) {
-            let pixel = img.get_pixel(x, y);
-            for by in 0..block_size {
-                for bx in 0..block_size {
-                    if x + bx < width && y + by < height {
-                        output.put_pixel(x + bx, y + by, pixel);
-                    }
-                }
-            }
-        }
+    if depth::is_16bit(img) {
+        DynamicImage::ImageRgba16(pixelate_buffer(&depth::rgba16(img), block_size))
+    } else {
+        DynamicImage::ImageRgba8(pixelate_buffer(&parallel::rgba(img), block_size))
     }
-    output
+}
+
+fn pixelate_buffer<S: Sample>(source: &RgbaBuffer<S>, block_size: u32) -> RgbaBuffer<S> {
+    let (width, height) = source.dimensions();
+    // Every pixel takes the color of the top-left corner of its block
+    parallel::from_fn(width, height, |x, y| {
+        *source.get_pixel(x - x % block_size, y - y % block_size)
+    })
 }
 
 pub fn oil_painting(img: &DynamicImage, radius: u32, intensity_levels: u8) -> DynamicImage {
-    let (width, height) = img.dimensions();
-    let mut output = DynamicImage::new_rgba8(width, height);
-
-    for y in 0..height {
-        for x in 0..width {
-            let mut intensity_count = vec![0; intensity_levels as usize];
-            let mut avg_r = vec![0; intensity_levels as usize];
-            let mut avg_g = vec![0; intensity_levels as usize];
-            let mut avg_b = vec![0; intensity_levels as usize];
-
-            for dy in y.saturating_sub(radius)..=y.saturating_add(radius) {
-                for dx in x.saturating_sub(radius)..=x.saturating_add(radius) {
-                    if dx < width && dy < height {
-                        let pixel = img.get_pixel(dx, dy);
-                        let intensity = ((pixel[0] as u16 + pixel[1] as u16 + pixel[2] as u16) / 3 * intensity_levels as u16 / 256) as usize;
-                        intensity_count[intensity] += 1;
-                        avg_r[intensity] += pixel[0] as u32;
-                        avg_g[intensity] += pixel[1] as u32;
-                        avg_b[intensity] += pixel[2] as u32;
-                    }
+    if depth::is_16bit(img) {
+        DynamicImage::ImageRgba16(oil_painting_buffer(&depth::rgba16(img), radius, intensity_levels))
+    } else {
+        DynamicImage::ImageRgba8(oil_painting_buffer(&parallel::rgba(img), radius, intensity_levels))
+    }
+}
+
+fn oil_painting_buffer<S: Sample>(source: &RgbaBuffer<S>, radius: u32, intensity_levels: u8) -> RgbaBuffer<S> {
+    let (width, height) = source.dimensions();
+    let mut output = RgbaBuffer::new(width, height);
+
+    parallel::for_each_row(&mut output, |y, row| {
+        // Buckets are reused for every pixel of the row
+        let mut intensity_count = vec![0; intensity_levels as usize];
+        let mut avg_r = vec![0u64; intensity_levels as usize];
+        let mut avg_g = vec![0u64; intensity_levels as usize];
+        let mut avg_b = vec![0u64; intensity_levels as usize];
+
+        for (x, out) in row.chunks_exact_mut(4).enumerate() {
+            let x = x as u32;
+            intensity_count.fill(0);
+            avg_r.fill(0);
+            avg_g.fill(0);
+            avg_b.fill(0);
+
+            for dy in y.saturating_sub(radius)..=y.saturating_add(radius).min(height - 1) {
+                for dx in x.saturating_sub(radius)..=x.saturating_add(radius).min(width - 1) {
+                    let pixel = source.get_pixel(dx, dy);
+                    // Buckets are picked on the 8-bit scale whatever the depth
+                    let level = ((pixel[0].to_f32() + pixel[1].to_f32() + pixel[2].to_f32()) / 3.0).floor();
+                    let intensity = (level * intensity_levels as f32 / 256.0) as usize;
+                    intensity_count[intensity] += 1;
+                    avg_r[intensity] += pixel[0].to_u32() as u64;
+                    avg_g[intensity] += pixel[1].to_u32() as u64;
+                    avg_b[intensity] += pixel[2].to_u32() as u64;
                 }
             }
 
             let max_intensity = intensity_count.iter().enumerate().max_by_key(|&(_, &count)| count).unwrap().0;
             let count = intensity_count[max_intensity];
-            let r = (avg_r[max_intensity] / count) as u8;
-            let g = (avg_g[max_intensity] / count) as u8;
-            let b = (avg_b[max_intensity] / count) as u8;
+            let r = S::from_u32((avg_r[max_intensity] / count) as u32);
+            let g = S::from_u32((avg_g[max_intensity] / count) as u32);
+            let b = S::from_u32((avg_b[max_intensity] / count) as u32);
 
-            output.put_pixel(x, y, Rgba([r, g, b, 255]));
+            // Alpha stays that of the pixel itself
+            out.copy_from_slice(&[r, g, b, source.get_pixel(x, y)[3]]);
         }
-    }
+    });
     output
 }
 
@@ -214,63 +256,84 @@ pub fn scan_lines(img: &DynamicImage,line_thickness: Option<u32>,line_spacing: O
     let spacing = line_spacing.unwrap_or_else(|| rng.gen_range(5..=20));
     let angle_rad = angle.unwrap_or_else(|| rng.gen_range(0.0..std::f32::consts::PI));
     let opacity = opacity.unwrap_or_else(|| rng.gen_range(0.3..=0.7));
+    let period = thickness.saturating_add(spacing).min(i32::MAX as u32) as i32;
+    if period == 0 {
+        return Err("The line thickness and spacing must not both be 0".to_string());
+    }
 
-    let (width, height) = img.dimensions();
-    let mut output = img.to_rgba8();
+    if depth::is_16bit(img) {
+        Ok(DynamicImage::ImageRgba16(scan_lines_buffer(depth::rgba16(img).into_owned(), thickness, period, angle_rad, opacity)))
+    } else {
+        Ok(DynamicImage::ImageRgba8(scan_lines_buffer(img.to_rgba8(), thickness, period, angle_rad, opacity)))
+    }
+}
 
+fn scan_lines_buffer<S: Sample>(mut output: RgbaBuffer<S>, thickness: u32, period: i32, angle_rad: f32, opacity: f32) -> RgbaBuffer<S> {
     let sin_angle = angle_rad.sin();
     let cos_angle = angle_rad.cos();
 
-    for y in 0..height {
-        for x in 0..width {
+    parallel::for_each_row(&mut output, |y, row| {
+        for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
             // Calculate the position along the scan line direction
             let pos = (x as f32 * cos_angle + y as f32 * sin_angle) as i32;
-            
+
             // Determine if this pixel is part of a scan line
-            if pos.rem_euclid((thickness + spacing) as i32) < thickness as i32 {
-                let pixel = output.get_pixel_mut(x, y);
-                
+            if pos.rem_euclid(period) < thickness as i32 {
                 // Adjust pixel color to create scan line effect
-                for c in 0..3 {
-                    pixel[c] = ((1.0 - opacity) * pixel[c] as f32) as u8;
+                for c in pixel.iter_mut().take(3) {
+                    *c = S::from_f32((1.0 - opacity) * c.to_f32());
                 }
             }
         }
-    }
+    });
 
-    Ok(DynamicImage::ImageRgba8(output))
+    output
 }
 
-pub fn glitch(img: &DynamicImage, num_glitches: u32, max_offset: i32,direction: &str, noisy: bool) -> DynamicImage {
-    let (width, height) = img.dimensions();
-    let mut output = img.clone();
-    let mut rng: rand::prelude::ThreadRng = rand::thread_rng();
+/// Shifts `num_glitches` random bands of rows or columns. With a `seed` the
+/// bands sit at the same relative places whatever the image size, as long as
+/// `max_offset` and `max_band` are scaled along with it.
+pub fn glitch(img: &DynamicImage, num_glitches: u32, max_offset: i32, max_band: u32, direction: &str, noisy: bool, seed: Option<u32>) -> DynamicImage {
+    match seed {
+        Some(seed) => glitch_with(img, num_glitches, max_offset, max_band, direction, noisy, &mut StdRng::seed_from_u64(seed as u64)),
+        None => glitch_with(img, num_glitches, max_offset, max_band, direction, noisy, &mut rand::thread_rng()),
+    }
+}
 
-    for _ in 0..num_glitches {
-        let (start, length, is_vertical) = match direction.trim().trim_matches('"').to_lowercase().as_str() {
-            "vertical" => (rng.gen_range(0..width), rng.gen_range(1..20), true),
-            "horizontal" => (rng.gen_range(0..height), rng.gen_range(1..20), false),
-            _ => panic!("Invalid direction. Use 'vertical', 'horizontal'."),
-        };
+fn glitch_with(img: &DynamicImage, num_glitches: u32, max_offset: i32, max_band: u32, direction: &str, noisy: bool, rng: &mut impl Rng) -> DynamicImage {
+    let source = parallel::rgba(img);
+    let (width, height) = source.dimensions();
+    let mut output = source.as_ref().clone();
+    let is_vertical = match direction.trim().trim_matches('"').to_lowercase().as_str() {
+        "vertical" => true,
+        "horizontal" => false,
+        _ => panic!("Invalid direction. Use 'vertical', 'horizontal'."),
+    };
+    let across = if is_vertical { width } else { height };
 
-        let offset = rng.gen_range(-max_offset..=max_offset);
+    for _ in 0..num_glitches {
+        // Drawn as fractions, so the same seed scales with the image
+        let start = ((rng.gen::<f64>() * across as f64) as u32).min(across - 1);
+        let length = 1 + (rng.gen::<f64>() * max_band as f64) as u32;
+        let offset = ((rng.gen::<f64>() * 2.0 - 1.0) * max_offset as f64).round() as i32;
 
         if is_vertical {
-            apply_vertical_glitch(&mut output, img, start, length, offset, width, height, noisy);
+            apply_vertical_glitch(&mut output, &source, start, length.min(max_band), offset, width, height, noisy);
         } else {
-            apply_horizontal_glitch(&mut output, img, start, length, offset, width, height, noisy);
+            apply_horizontal_glitch(&mut output, &source, start, length.min(max_band), offset, width, height, noisy);
         }
     }
 
-    output
+    DynamicImage::ImageRgba8(output)
 }
 
-pub fn apply_vertical_glitch(output: &mut DynamicImage, img: &DynamicImage, x: u32, glitch_width: u32, offset: i32, width: u32, height: u32, noisy: bool) {
+#[allow(clippy::too_many_arguments)]
+pub fn apply_vertical_glitch(output: &mut RgbaImage, img: &RgbaImage, x: u32, glitch_width: u32, offset: i32, width: u32, height: u32, noisy: bool) {
     for dx in x..x + glitch_width {
         if dx < width {
             for y in 0..height {
                 let source_y = (y as i32 + offset).rem_euclid(height as i32) as u32;
-                let mut pixel = img.get_pixel(dx, source_y);
+                let mut pixel = *img.get_pixel(dx, source_y);
                 if noisy {
                     pixel = noisy_pixels(pixel);
                 }
@@ -280,12 +343,13 @@ pub fn apply_vertical_glitch(output: &mut DynamicImage, img: &DynamicImage, x: u
     }
 }
 
-pub fn apply_horizontal_glitch( output: &mut DynamicImage, img: &DynamicImage, y: u32, glitch_height: u32, offset: i32, width: u32, height: u32, noisy: bool) {
+#[allow(clippy::too_many_arguments)]
+pub fn apply_horizontal_glitch( output: &mut RgbaImage, img: &RgbaImage, y: u32, glitch_height: u32, offset: i32, width: u32, height: u32, noisy: bool) {
     for dy in y..y + glitch_height {
         if dy < height {
             for x in 0..width {
                 let source_x = (x as i32 + offset).rem_euclid(width as i32) as u32;
-                let mut pixel = img.get_pixel(source_x, dy);
+                let mut pixel = *img.get_pixel(source_x, dy);
                 if noisy {
                     pixel = noisy_pixels(pixel);
                 }
@@ -295,12 +359,13 @@ pub fn apply_horizontal_glitch( output: &mut DynamicImage, img: &DynamicImage, y
     }
 }
 
-pub fn noisy_pixels(_pixel: Rgba<u8>) -> Rgba<u8> {
+// Random color, keeping the alpha of the pixel it replaces
+pub fn noisy_pixels(pixel: Rgba<u8>) -> Rgba<u8> {
     let mut rng = rand::thread_rng();
     Rgba([
         rng.gen_range(0..=255),
         rng.gen_range(0..=255),
         rng.gen_range(0..=255),
-        255,
+        pixel[3],
     ])
 }
\ No newline at end of file
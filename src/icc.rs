@@ -0,0 +1,391 @@
+use image::DynamicImage;
+
+use crate::depth::{self, RgbaBuffer, Sample};
+use crate::parallel;
+
+// PCS white, D50
+const D50: [f32; 3] = [0.9642, 1.0, 0.8249];
+
+// Bradford adaptation from D65 to D50, shared by the built-in spaces
+const D65_TO_D50: [[f32; 3]; 3] = [
+    [1.0478112, 0.0228866, -0.0501270],
+    [0.0295424, 0.9904844, -0.0170491],
+    [-0.0092345, 0.0150436, 0.7521316],
+];
+
+// Parametric curve of the sRGB and Display P3 transfer functions
+const SRGB_CURVE: Curve = Curve::Parametric([2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.04045, 0.0, 0.0]);
+
+/// Tone curve from encoded values to linear light, both on 0-1.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Curve {
+    /// ICC parametric curve, normalized to `[g, a, b, c, d, e, f]`:
+    /// `(a*x + b)^g + e` for `x >= d`, `c*x + f` below
+    Parametric([f32; 7]),
+    /// Sampled curve, interpolated linearly
+    Table(Vec<f32>),
+}
+
+impl Curve {
+    fn gamma(gamma: f32) -> Curve {
+        Curve::Parametric([gamma, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0])
+    }
+
+    pub fn eval(&self, x: f32) -> f32 {
+        let x = x.clamp(0.0, 1.0);
+        match self {
+            Curve::Parametric([g, a, b, c, d, e, f]) => {
+                if x >= *d {
+                    (a * x + b).max(0.0).powf(*g) + e
+                } else {
+                    c * x + f
+                }
+            },
+            Curve::Table(table) => {
+                let pos = x * (table.len() - 1) as f32;
+                let i = (pos as usize).min(table.len() - 2);
+                let t = pos - i as f32;
+                table[i] * (1.0 - t) + table[i + 1] * t
+            },
+        }
+    }
+
+    pub fn inverse(&self, y: f32) -> f32 {
+        let y = y.clamp(0.0, 1.0);
+        let x = match self {
+            Curve::Parametric([g, a, b, c, d, e, f]) => {
+                if *c > 0.0 && y < c * d + f {
+                    (y - f) / c
+                } else {
+                    ((y - e).max(0.0).powf(1.0 / g) - b) / a
+                }
+            },
+            Curve::Table(table) => {
+                // Tables are monotonic, so search for the enclosing segment
+                let i = table.partition_point(|&v| v < y).clamp(1, table.len() - 1);
+                let (lo, hi) = (table[i - 1], table[i]);
+                let t = if hi > lo { (y - lo) / (hi - lo) } else { 0.0 };
+                (i - 1) as f32 / (table.len() - 1) as f32 + t / (table.len() - 1) as f32
+            },
+        };
+        x.clamp(0.0, 1.0)
+    }
+}
+
+/// RGB matrix/shaper profile: per-channel tone curves, then a matrix to
+/// D50 XYZ. This covers sRGB, Display P3, Adobe RGB and most camera and
+/// monitor profiles; LUT-based profiles are not supported.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profile {
+    pub name: String,
+    /// Columns are the XYZ of the red, green and blue primaries
+    pub to_xyz: [[f32; 3]; 3],
+    pub curves: [Curve; 3],
+}
+
+fn columns(r: [f32; 3], g: [f32; 3], b: [f32; 3]) -> [[f32; 3]; 3] {
+    [[r[0], g[0], b[0]], [r[1], g[1], b[1]], [r[2], g[2], b[2]]]
+}
+
+impl Profile {
+    pub fn srgb() -> Profile {
+        Profile {
+            name: "sRGB".into(),
+            to_xyz: columns([0.4360747, 0.2225045, 0.0139322], [0.3850649, 0.7168786, 0.0971045], [0.1430804, 0.0606169, 0.7141733]),
+            curves: [SRGB_CURVE, SRGB_CURVE, SRGB_CURVE],
+        }
+    }
+
+    pub fn display_p3() -> Profile {
+        Profile {
+            name: "Display P3".into(),
+            to_xyz: columns([0.51512, 0.24119, -0.00105], [0.29198, 0.69225, 0.04189], [0.15710, 0.06657, 0.78407]),
+            curves: [SRGB_CURVE, SRGB_CURVE, SRGB_CURVE],
+        }
+    }
+
+    pub fn adobe_rgb() -> Profile {
+        let curve = Curve::gamma(563.0 / 256.0);
+        Profile {
+            name: "Adobe RGB (1998)".into(),
+            to_xyz: columns([0.6097559, 0.3111242, 0.0194811], [0.2052401, 0.625656, 0.0608902], [0.149224, 0.0632197, 0.7448387]),
+            curves: [curve.clone(), curve.clone(), curve],
+        }
+    }
+
+    /// Built-in profile by name: "srgb", "display-p3" or "adobe-rgb".
+    pub fn named(name: &str) -> Option<Profile> {
+        match name {
+            "srgb" => Some(Profile::srgb()),
+            "display-p3" => Some(Profile::display_p3()),
+            "adobe-rgb" => Some(Profile::adobe_rgb()),
+            _ => None,
+        }
+    }
+
+    /// Whether converting between the two profiles would change nothing
+    /// visible, as for the many slightly different sRGB profiles around.
+    pub fn same_as(&self, other: &Profile) -> bool {
+        let matrices = self.to_xyz.iter().flatten().zip(other.to_xyz.iter().flatten()).all(|(a, b)| (a - b).abs() < 2e-3);
+        let curves = self.curves.iter().zip(&other.curves)
+            .all(|(a, b)| (0..=16).all(|i| (a.eval(i as f32 / 16.0) - b.eval(i as f32 / 16.0)).abs() < 2e-3));
+        matrices && curves
+    }
+
+    /// Reads an RGB matrix/shaper ICC profile.
+    pub fn parse(bytes: &[u8]) -> Result<Profile, String> {
+        let u32_at = |offset: usize| -> Result<u32, String> {
+            bytes.get(offset..offset + 4)
+                .map(|b| u32::from_be_bytes(b.try_into().unwrap()))
+                .ok_or_else(|| "Truncated ICC profile".to_string())
+        };
+        if bytes.len() < 132 || &bytes[36..40] != b"acsp" {
+            return Err("Not an ICC profile".into());
+        }
+        if &bytes[16..20] != b"RGB " {
+            return Err(format!("Unsupported ICC color space: {}", String::from_utf8_lossy(&bytes[16..20]).trim()));
+        }
+
+        let count = u32_at(128)? as usize;
+        // Checked before allocating, as the count comes straight from the file
+        if count > (bytes.len() - 132) / 12 {
+            return Err("Truncated ICC tag table".into());
+        }
+        let mut tags = Vec::with_capacity(count);
+        for i in 0..count {
+            let entry = 132 + i * 12;
+            let signature = bytes.get(entry..entry + 4).ok_or("Truncated ICC tag table")?;
+            let (offset, size) = (u32_at(entry + 4)? as usize, u32_at(entry + 8)? as usize);
+            let data = bytes.get(offset..offset + size).ok_or("ICC tag outside the profile")?;
+            tags.push((signature, data));
+        }
+        let tag = |signature: &[u8]| {
+            tags.iter().find(|(s, _)| *s == signature).map(|(_, d)| *d)
+                .ok_or_else(|| format!("ICC profile has no {} tag (only matrix/shaper profiles are supported)", String::from_utf8_lossy(signature)))
+        };
+
+        let name = tag(b"desc").ok().and_then(parse_text).unwrap_or_else(|| "embedded".into());
+        let to_xyz = columns(parse_xyz(tag(b"rXYZ")?)?, parse_xyz(tag(b"gXYZ")?)?, parse_xyz(tag(b"bXYZ")?)?);
+        let curves = [parse_curve(tag(b"rTRC")?)?, parse_curve(tag(b"gTRC")?)?, parse_curve(tag(b"bTRC")?)?];
+        Ok(Profile { name, to_xyz, curves })
+    }
+
+    /// Serializes the profile as an ICC v4 display profile.
+    pub fn to_icc(&self) -> Vec<u8> {
+        let m = &self.to_xyz;
+        let mut tags: Vec<(&[u8; 4], Vec<u8>)> = vec![
+            (b"desc", mluc(&self.name)),
+            (b"cprt", mluc("No copyright, use freely")),
+            (b"wtpt", fixed_tag(b"XYZ ", D50)),
+            (b"chad", fixed_tag(b"sf32", D65_TO_D50.iter().flatten().copied())),
+            (b"rXYZ", fixed_tag(b"XYZ ", [m[0][0], m[1][0], m[2][0]])),
+            (b"gXYZ", fixed_tag(b"XYZ ", [m[0][1], m[1][1], m[2][1]])),
+            (b"bXYZ", fixed_tag(b"XYZ ", [m[0][2], m[1][2], m[2][2]])),
+        ];
+        for (signature, curve) in [(b"rTRC", &self.curves[0]), (b"gTRC", &self.curves[1]), (b"bTRC", &self.curves[2])] {
+            tags.push((signature, curve_tag(curve)));
+        }
+
+        // Header, tag table, then the tag data at 4-byte aligned offsets
+        let mut data = Vec::new();
+        let mut table = Vec::new();
+        let data_start = 128 + 4 + tags.len() * 12;
+        for (signature, tag) in &tags {
+            table.extend_from_slice(*signature);
+            table.extend_from_slice(&((data_start + data.len()) as u32).to_be_bytes());
+            table.extend_from_slice(&(tag.len() as u32).to_be_bytes());
+            data.extend_from_slice(tag);
+            while data.len() % 4 != 0 {
+                data.push(0);
+            }
+        }
+
+        let mut profile = vec![0u8; 128];
+        profile[8..12].copy_from_slice(&0x0430_0000u32.to_be_bytes());
+        profile[12..16].copy_from_slice(b"mntr");
+        profile[16..20].copy_from_slice(b"RGB ");
+        profile[20..24].copy_from_slice(b"XYZ ");
+        profile[36..40].copy_from_slice(b"acsp");
+        for (i, v) in D50.iter().enumerate() {
+            profile[68 + i * 4..72 + i * 4].copy_from_slice(&s15_fixed16(*v));
+        }
+        profile.extend_from_slice(&(tags.len() as u32).to_be_bytes());
+        profile.extend(table);
+        profile.extend(data);
+        let size = profile.len() as u32;
+        profile[0..4].copy_from_slice(&size.to_be_bytes());
+        profile
+    }
+}
+
+fn read_s15_fixed16(bytes: &[u8]) -> f32 {
+    i32::from_be_bytes(bytes[..4].try_into().unwrap()) as f32 / 65536.0
+}
+
+fn s15_fixed16(value: f32) -> [u8; 4] {
+    ((value * 65536.0).round() as i32).to_be_bytes()
+}
+
+fn parse_xyz(data: &[u8]) -> Result<[f32; 3], String> {
+    if data.len() < 20 || &data[0..4] != b"XYZ " {
+        return Err("Invalid XYZ tag in ICC profile".into());
+    }
+    Ok([read_s15_fixed16(&data[8..]), read_s15_fixed16(&data[12..]), read_s15_fixed16(&data[16..])])
+}
+
+fn parse_curve(data: &[u8]) -> Result<Curve, String> {
+    let invalid = || "Invalid tone curve in ICC profile".to_string();
+    match data.get(0..4) {
+        Some(b"curv") => {
+            let count = u32::from_be_bytes(data.get(8..12).ok_or_else(invalid)?.try_into().unwrap()) as usize;
+            let values = data.get(12..12 + count * 2).ok_or_else(invalid)?;
+            match count {
+                0 => Ok(Curve::gamma(1.0)),
+                1 => Ok(Curve::gamma(u16::from_be_bytes([values[0], values[1]]) as f32 / 256.0)),
+                _ => Ok(Curve::Table(values.chunks_exact(2).map(|v| u16::from_be_bytes([v[0], v[1]]) as f32 / 65535.0).collect())),
+            }
+        },
+        Some(b"para") => {
+            let function = u16::from_be_bytes(data.get(8..10).ok_or_else(invalid)?.try_into().unwrap());
+            let count = match function {
+                0 => 1,
+                1 => 3,
+                2 => 4,
+                3 => 5,
+                4 => 7,
+                _ => return Err(invalid()),
+            };
+            let values = data.get(12..12 + count * 4).ok_or_else(invalid)?;
+            let p: Vec<f32> = values.chunks_exact(4).map(read_s15_fixed16).collect();
+            // Bring every function type to the 7-parameter form
+            Ok(Curve::Parametric(match function {
+                0 => [p[0], 1.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+                1 => [p[0], p[1], p[2], 0.0, -p[2] / p[1], 0.0, 0.0],
+                2 => [p[0], p[1], p[2], 0.0, -p[2] / p[1], p[3], p[3]],
+                3 => [p[0], p[1], p[2], p[3], p[4], 0.0, 0.0],
+                _ => [p[0], p[1], p[2], p[3], p[4], p[5], p[6]],
+            }))
+        },
+        _ => Err(invalid()),
+    }
+}
+
+// Description text from a v2 'desc' or v4 'mluc' tag
+fn parse_text(data: &[u8]) -> Option<String> {
+    match data.get(0..4)? {
+        b"desc" => {
+            let length = u32::from_be_bytes(data.get(8..12)?.try_into().ok()?) as usize;
+            let text = data.get(12..12 + length)?;
+            Some(String::from_utf8_lossy(text).trim_end_matches('\0').to_string())
+        },
+        b"mluc" => {
+            // First record only: length and offset of UTF-16BE text
+            let length = u32::from_be_bytes(data.get(20..24)?.try_into().ok()?) as usize;
+            let offset = u32::from_be_bytes(data.get(24..28)?.try_into().ok()?) as usize;
+            let units: Vec<u16> = data.get(offset..offset + length)?
+                .chunks_exact(2)
+                .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                .collect();
+            String::from_utf16(&units).ok()
+        },
+        _ => None,
+    }
+}
+
+fn mluc(text: &str) -> Vec<u8> {
+    let utf16: Vec<u8> = text.encode_utf16().flat_map(|u| u.to_be_bytes()).collect();
+    let mut tag = b"mluc\0\0\0\0".to_vec();
+    tag.extend_from_slice(&1u32.to_be_bytes());
+    tag.extend_from_slice(&12u32.to_be_bytes());
+    tag.extend_from_slice(b"enUS");
+    tag.extend_from_slice(&(utf16.len() as u32).to_be_bytes());
+    tag.extend_from_slice(&28u32.to_be_bytes());
+    tag.extend(utf16);
+    tag
+}
+
+// Tags that are just a list of s15Fixed16 numbers
+fn fixed_tag(kind: &[u8; 4], values: impl IntoIterator<Item = f32>) -> Vec<u8> {
+    let mut tag = kind.to_vec();
+    tag.extend_from_slice(&[0; 4]);
+    for value in values {
+        tag.extend_from_slice(&s15_fixed16(value));
+    }
+    tag
+}
+
+fn curve_tag(curve: &Curve) -> Vec<u8> {
+    match curve {
+        Curve::Parametric(params) => {
+            let mut tag = b"para\0\0\0\0\0\x04\0\0".to_vec();
+            for value in params {
+                tag.extend_from_slice(&s15_fixed16(*value));
+            }
+            tag
+        },
+        Curve::Table(table) => {
+            let mut tag = b"curv\0\0\0\0".to_vec();
+            tag.extend_from_slice(&(table.len() as u32).to_be_bytes());
+            for value in table {
+                tag.extend_from_slice(&((value * 65535.0).round() as u16).to_be_bytes());
+            }
+            tag
+        },
+    }
+}
+
+fn multiply(a: &[[f32; 3]; 3], b: &[[f32; 3]; 3]) -> [[f32; 3]; 3] {
+    let mut out = [[0.0; 3]; 3];
+    for i in 0..3 {
+        for j in 0..3 {
+            out[i][j] = (0..3).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    out
+}
+
+fn invert(m: &[[f32; 3]; 3]) -> [[f32; 3]; 3] {
+    let det = m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+        - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+        + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]);
+    let cofactor = |r1: usize, r2: usize, c1: usize, c2: usize| m[r1][c1] * m[r2][c2] - m[r1][c2] * m[r2][c1];
+    [
+        [cofactor(1, 2, 1, 2) / det, -cofactor(0, 2, 1, 2) / det, cofactor(0, 1, 1, 2) / det],
+        [-cofactor(1, 2, 0, 2) / det, cofactor(0, 2, 0, 2) / det, -cofactor(0, 1, 0, 2) / det],
+        [cofactor(1, 2, 0, 1) / det, -cofactor(0, 2, 0, 1) / det, cofactor(0, 1, 0, 1) / det],
+    ]
+}
+
+/// Converts `img` from the `from` profile to `to`, keeping its bit depth.
+/// Colors outside the target gamut are clipped.
+pub fn convert(img: &DynamicImage, from: &Profile, to: &Profile) -> DynamicImage {
+    if from.same_as(to) {
+        return img.clone();
+    }
+    let matrix = multiply(&invert(&to.to_xyz), &from.to_xyz);
+    if depth::is_16bit(img) {
+        DynamicImage::ImageRgba16(convert_buffer(&depth::rgba16(img), &matrix, from, to))
+    } else {
+        DynamicImage::ImageRgba8(convert_buffer(&parallel::rgba(img), &matrix, from, to))
+    }
+}
+
+fn convert_buffer<S: Sample>(source: &RgbaBuffer<S>, matrix: &[[f32; 3]; 3], from: &Profile, to: &Profile) -> RgbaBuffer<S> {
+    // Decoding goes through a table over every sample value
+    let levels = S::from_f32(255.0).to_u32() as usize + 1;
+    let decode: Vec<Vec<f32>> = from.curves.iter()
+        .map(|curve| (0..levels).map(|v| curve.eval(v as f32 / (levels - 1) as f32)).collect())
+        .collect();
+
+    let mut output = source.clone();
+    parallel::for_each_row(&mut output, |_, row| {
+        for pixel in row.chunks_exact_mut(4) {
+            let linear = [0, 1, 2].map(|c| decode[c][pixel[c].to_u32() as usize]);
+            for c in 0..3 {
+                let value = (0..3).map(|k| matrix[c][k] * linear[k]).sum::<f32>();
+                pixel[c] = S::from_f32_rounded(to.curves[c].inverse(value) * 255.0);
+            }
+        }
+    });
+    output
+}
@@ -1,5 +1,21 @@
+pub mod alpha;
+pub mod analyze;
+pub mod api;
 pub mod colorfx;
+pub mod colorspace;
+pub mod depth;
 pub mod glitchfx;
 pub mod edgesfx;
+pub mod effects;
 pub mod fx_json_generator;
-pub mod langsam_interface; 
\ No newline at end of file
+pub mod geometryfx;
+pub mod icc;
+pub mod langsam_interface;
+pub mod lut;
+pub mod metadata;
+pub mod output;
+pub mod parallel;
+pub mod pipeline;
+pub mod preview;
+pub mod server;
+pub mod watch;
@@ -0,0 +1,295 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::SystemTime;
+
+use image::{DynamicImage, ImageBuffer, Rgba};
+
+use crate::depth::{self, RgbaBuffer, Sample};
+use crate::effects::{self, Neighborhood};
+use crate::parallel;
+use crate::pipeline::{self, TransformConfig};
+
+/// Names of the 3D interpolation methods.
+pub const INTERPOLATIONS: &[&str] = &["tetrahedral", "trilinear"];
+
+// Largest sizes accepted from files; real 3D LUTs stop at 65 or so
+const MAX_1D_SIZE: usize = 65536;
+const MAX_3D_SIZE: usize = 256;
+
+// LUT files kept parsed in memory by `load_cached`
+const MAX_CACHED: usize = 8;
+
+// A parsed LUT with the modification time and length of its file
+type CachedLut = (SystemTime, u64, Arc<Lut>);
+
+// Point effects that draw random noise, so they have no single output color
+// for an input color
+const RANDOM_EFFECTS: &[&str] = &["add_noise", "deepfry"];
+
+/// How a 3D LUT is read between its lattice points. 1D LUTs are always
+/// interpolated linearly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interpolation {
+    /// Blends the four lattice points of the tetrahedron around the color,
+    /// which keeps the gray axis neutral
+    Tetrahedral,
+    /// Blends all eight corners of the cube around the color
+    Trilinear,
+}
+
+impl Interpolation {
+    /// One of `INTERPOLATIONS`.
+    pub fn named(name: &str) -> Result<Interpolation, String> {
+        match name {
+            "tetrahedral" => Ok(Interpolation::Tetrahedral),
+            "trilinear" => Ok(Interpolation::Trilinear),
+            _ => Err(format!("Unknown LUT interpolation: {} (use {})", name, INTERPOLATIONS.join(", "))),
+        }
+    }
+}
+
+/// A 1D or 3D color lookup table in the `.cube` format used by Resolve,
+/// Premiere and most grading tools. Colors are RGB on 0-1.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lut {
+    pub title: Option<String>,
+    three_d: bool,
+    size: usize,
+    domain_min: [f32; 3],
+    domain_max: [f32; 3],
+    // 3D tables run with red fastest, then green, then blue
+    table: Vec<[f32; 3]>,
+}
+
+impl Lut {
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_3d(&self) -> bool {
+        self.three_d
+    }
+
+    pub fn load(path: &str) -> Result<Lut, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("Failed to read LUT {}: {}", path, e))?;
+        Lut::parse(&text).map_err(|e| format!("Invalid LUT {}: {}", path, e))
+    }
+
+    pub fn parse(text: &str) -> Result<Lut, String> {
+        let mut lut = Lut { title: None, three_d: true, size: 0, domain_min: [0.0; 3], domain_max: [1.0; 3], table: Vec::new() };
+        let floats = |values: &[&str], count: usize, line: usize| -> Result<Vec<f32>, String> {
+            let parsed: Vec<f32> = values.iter().map(|v| v.parse::<f32>()).collect::<Result<_, _>>()
+                .map_err(|_| format!("line {}: expected numbers", line))?;
+            if parsed.len() != count {
+                return Err(format!("line {}: expected {} numbers, got {}", line, count, parsed.len()));
+            }
+            Ok(parsed)
+        };
+
+        for (index, line) in text.lines().enumerate() {
+            let number = index + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            let size = |max: usize| -> Result<usize, String> {
+                match tokens.get(1).and_then(|s| s.parse::<usize>().ok()) {
+                    Some(size) if (2..=max).contains(&size) => Ok(size),
+                    _ => Err(format!("line {}: the size must be between 2 and {}", number, max)),
+                }
+            };
+            match tokens[0] {
+                "TITLE" => lut.title = Some(line["TITLE".len()..].trim().trim_matches('"').to_string()),
+                "LUT_1D_SIZE" => {
+                    lut.three_d = false;
+                    lut.size = size(MAX_1D_SIZE)?;
+                },
+                "LUT_3D_SIZE" => {
+                    lut.three_d = true;
+                    lut.size = size(MAX_3D_SIZE)?;
+                },
+                "DOMAIN_MIN" => lut.domain_min = floats(&tokens[1..], 3, number)?.try_into().unwrap(),
+                "DOMAIN_MAX" => lut.domain_max = floats(&tokens[1..], 3, number)?.try_into().unwrap(),
+                // Resolve's form of the domain, the same for every channel
+                "LUT_1D_INPUT_RANGE" | "LUT_3D_INPUT_RANGE" => {
+                    let range = floats(&tokens[1..], 2, number)?;
+                    lut.domain_min = [range[0]; 3];
+                    lut.domain_max = [range[1]; 3];
+                },
+                // The line itself is left out, so errors never repeat the
+                // contents of a file that is not a LUT
+                keyword if keyword.starts_with(|c: char| c.is_ascii_alphabetic()) => {
+                    return Err(format!("line {}: unknown keyword", number));
+                },
+                _ => {
+                    if lut.size == 0 {
+                        return Err(format!("line {}: data before LUT_1D_SIZE or LUT_3D_SIZE", number));
+                    }
+                    let values = floats(&tokens, 3, number)?;
+                    lut.table.push([values[0], values[1], values[2]]);
+                },
+            }
+        }
+
+        if lut.size == 0 {
+            return Err("no LUT_1D_SIZE or LUT_3D_SIZE".to_string());
+        }
+        let expected = if lut.three_d { lut.size.pow(3) } else { lut.size };
+        if lut.table.len() != expected {
+            return Err(format!("expected {} entries for size {}, got {}", expected, lut.size, lut.table.len()));
+        }
+        if (0..3).any(|c| lut.domain_max[c] <= lut.domain_min[c]) {
+            return Err("DOMAIN_MAX must be above DOMAIN_MIN".to_string());
+        }
+        Ok(lut)
+    }
+
+    /// The LUT as the text of a `.cube` file.
+    pub fn to_cube(&self) -> String {
+        let mut cube = String::new();
+        if let Some(title) = &self.title {
+            cube.push_str(&format!("TITLE \"{}\"\n", title));
+        }
+        let keyword = if self.three_d { "LUT_3D_SIZE" } else { "LUT_1D_SIZE" };
+        cube.push_str(&format!("{} {}\n", keyword, self.size));
+        let [r, g, b] = self.domain_min;
+        cube.push_str(&format!("DOMAIN_MIN {} {} {}\n", r, g, b));
+        let [r, g, b] = self.domain_max;
+        cube.push_str(&format!("DOMAIN_MAX {} {} {}\n", r, g, b));
+        for [r, g, b] in &self.table {
+            cube.push_str(&format!("{:.6} {:.6} {:.6}\n", r, g, b));
+        }
+        cube
+    }
+
+    /// The color `rgb` maps to, both on 0-1.
+    pub fn lookup(&self, rgb: [f32; 3], interpolation: Interpolation) -> [f32; 3] {
+        let last = (self.size - 1) as f32;
+        // Position of every channel in lattice steps
+        let position: [f32; 3] = [0, 1, 2].map(|c| {
+            let t = (rgb[c] - self.domain_min[c]) / (self.domain_max[c] - self.domain_min[c]);
+            t.clamp(0.0, 1.0) * last
+        });
+        let base = position.map(|p| (p.floor() as usize).min(self.size - 2));
+        let fraction = [0, 1, 2].map(|c| position[c] - base[c] as f32);
+
+        if !self.three_d {
+            return [0, 1, 2].map(|c| {
+                let (low, high) = (self.table[base[c]][c], self.table[base[c] + 1][c]);
+                low + (high - low) * fraction[c]
+            });
+        }
+
+        let n = self.size;
+        let at = |r: usize, g: usize, b: usize| self.table[(base[0] + r) + (base[1] + g) * n + (base[2] + b) * n * n];
+        let [fr, fg, fb] = fraction;
+        let blend = |steps: [([usize; 3], f32); 3]| {
+            // Walks from the near corner to the far one, one axis at a time
+            let mut color = at(0, 0, 0);
+            let mut from = color;
+            for ([r, g, b], weight) in steps {
+                let to = at(r, g, b);
+                for c in 0..3 {
+                    color[c] += (to[c] - from[c]) * weight;
+                }
+                from = to;
+            }
+            color
+        };
+        match interpolation {
+            Interpolation::Tetrahedral => {
+                if fr > fg {
+                    if fg > fb {
+                        blend([([1, 0, 0], fr), ([1, 1, 0], fg), ([1, 1, 1], fb)])
+                    } else if fr > fb {
+                        blend([([1, 0, 0], fr), ([1, 0, 1], fb), ([1, 1, 1], fg)])
+                    } else {
+                        blend([([0, 0, 1], fb), ([1, 0, 1], fr), ([1, 1, 1], fg)])
+                    }
+                } else if fb > fg {
+                    blend([([0, 0, 1], fb), ([0, 1, 1], fg), ([1, 1, 1], fr)])
+                } else if fb > fr {
+                    blend([([0, 1, 0], fg), ([0, 1, 1], fb), ([1, 1, 1], fr)])
+                } else {
+                    blend([([0, 1, 0], fg), ([1, 1, 0], fr), ([1, 1, 1], fb)])
+                }
+            },
+            Interpolation::Trilinear => {
+                let lerp = |a: [f32; 3], b: [f32; 3], t: f32| [0, 1, 2].map(|c| a[c] + (b[c] - a[c]) * t);
+                let edge = |g: usize, b: usize| lerp(at(0, g, b), at(1, g, b), fr);
+                let face = |b: usize| lerp(edge(0, b), edge(1, b), fg);
+                lerp(face(0), face(1), fb)
+            },
+        }
+    }
+}
+
+/// Like `Lut::load`, but keeps the last few LUTs parsed, so tiled runs and
+/// re-renders read each file once. A file that changed on disk is read again.
+pub fn load_cached(path: &str) -> Result<Arc<Lut>, String> {
+    static CACHE: OnceLock<Mutex<HashMap<String, CachedLut>>> = OnceLock::new();
+    let metadata = fs::metadata(path).map_err(|e| format!("Failed to read LUT {}: {}", path, e))?;
+    let stamp = (metadata.modified().map_err(|e| format!("Failed to read LUT {}: {}", path, e))?, metadata.len());
+    let cache = CACHE.get_or_init(Default::default);
+    if let Some((modified, len, lut)) = cache.lock().unwrap().get(path) {
+        if (*modified, *len) == stamp {
+            return Ok(Arc::clone(lut));
+        }
+    }
+
+    let lut = Arc::new(Lut::load(path)?);
+    let mut cache = cache.lock().unwrap();
+    if cache.len() >= MAX_CACHED && !cache.contains_key(path) {
+        cache.clear();
+    }
+    cache.insert(path.to_string(), (stamp.0, stamp.1, Arc::clone(&lut)));
+    Ok(lut)
+}
+
+/// Runs every pixel through `lut`, mixing the result with the original
+/// colors by `strength`. Alpha is left untouched.
+pub fn apply(img: &DynamicImage, lut: &Lut, interpolation: Interpolation, strength: f32) -> DynamicImage {
+    if depth::is_16bit(img) {
+        DynamicImage::ImageRgba16(apply_buffer(&depth::rgba16(img), lut, interpolation, strength))
+    } else {
+        DynamicImage::ImageRgba8(apply_buffer(&parallel::rgba(img), lut, interpolation, strength))
+    }
+}
+
+fn apply_buffer<S: Sample>(source: &RgbaBuffer<S>, lut: &Lut, interpolation: Interpolation, strength: f32) -> RgbaBuffer<S> {
+    parallel::map_pixels(source, |pixel| {
+        let rgb = [pixel[0].to_f32() / 255.0, pixel[1].to_f32() / 255.0, pixel[2].to_f32() / 255.0];
+        let mapped = lut.lookup(rgb, interpolation);
+        let mix = |c: usize| S::from_f32_rounded((rgb[c] + (mapped[c] - rgb[c]) * strength).clamp(0.0, 1.0) * 255.0);
+        Rgba([mix(0), mix(1), mix(2), pixel[3]])
+    })
+}
+
+/// Bakes a pipeline of pure color effects into a 3D LUT of `size` points per
+/// side, by running it on an image holding every lattice color. Steps that
+/// look at neighboring pixels or draw noise cannot be baked.
+pub fn bake(transformations: &[TransformConfig], size: usize, title: Option<String>) -> Result<Lut, Box<dyn Error>> {
+    if !(2..=MAX_3D_SIZE).contains(&size) {
+        return Err(format!("The LUT size must be between 2 and {}", MAX_3D_SIZE).into());
+    }
+    for transform in transformations {
+        let effect = effects::find(&transform.name)
+            .ok_or_else(|| format!("Unknown effect: {}", transform.name))?;
+        if !matches!(effect.neighborhood, Neighborhood::Point) || RANDOM_EFFECTS.contains(&effect.name) {
+            return Err(format!("{} is not a pure color effect and cannot be baked into a LUT", effect.name).into());
+        }
+    }
+
+    // Lattice colors at 16 bits, red along x within each green block
+    let level = |i: u32| ((i as f32 / (size - 1) as f32) * 65535.0).round() as u16;
+    let n = size as u32;
+    let lattice = ImageBuffer::from_fn(n * n, n, |x, y| Rgba([level(x % n), level(x / n), level(y), u16::MAX]));
+    let processed = depth::to_rgba16(&pipeline::apply_pipeline(DynamicImage::ImageRgba16(lattice), transformations)?);
+    let table = processed.pixels()
+        .map(|p| [p[0], p[1], p[2]].map(|c| c as f32 / 65535.0))
+        .collect();
+    Ok(Lut { title, three_d: true, size, domain_min: [0.0; 3], domain_max: [1.0; 3], table })
+}
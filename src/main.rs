@@ -1,28 +1,36 @@
-use clap::Parser;
-use serde::Deserialize;
+use clap::{Parser, Subcommand};
 use std::fs;
 use std::path::PathBuf;
-use std::time::Instant;
-use log::{info, error, warn, debug};
-use env_logger;
+use std::thread;
+use std::time::{Duration, Instant};
+use log::{info, error, debug};
 use image::{self, GenericImageView};
-mod colorfx;
-mod glitchfx;
-mod edgesfx;
-mod fx_json_generator;
-mod langsam_interface;
-
+use img_corroder::analyze;
+use img_corroder::api::{self, ApiOptions};
+use img_corroder::colorfx::palette;
+use img_corroder::depth::Depth;
+use img_corroder::effects::ParamDefault;
+use img_corroder::metadata::{self, Metadata};
+use img_corroder::{effects, fx_json_generator, langsam_interface, lut, output, parallel, server};
+use img_corroder::output::OutputOptions;
+use img_corroder::pipeline::{self, Config, PipelineCache};
+use img_corroder::preview;
+use img_corroder::watch::FileWatcher;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Input image file
-    #[arg(short, long)]
-    input: PathBuf,
+    #[arg(short, long, required = true)]
+    input: Option<PathBuf>,
 
     /// Output image file
-    #[arg(short, long)]
-    output: PathBuf,
+    #[arg(short, long, required = true)]
+    output: Option<PathBuf>,
 
     /// Configuration file or number of effects
     #[arg(short, long)]
@@ -32,17 +40,177 @@ struct Args {
     #[arg(short, long)]
     prompt: Option<String>,
 
+    /// JPEG quality (1-100)
+    #[arg(long)]
+    jpeg_quality: Option<u8>,
+
+    /// JPEG chroma subsampling (4:4:4, 4:2:2 or 4:2:0)
+    #[arg(long)]
+    jpeg_subsampling: Option<String>,
+
+    /// PNG compression (fast, default, best, huffman or rle)
+    #[arg(long)]
+    png_compression: Option<String>,
+
+    /// Write WebP output losslessly; =false turns off a config's setting
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    webp_lossless: Option<bool>,
+
+    /// Lossy WebP quality (0-100)
+    #[arg(long)]
+    webp_quality: Option<f32>,
+
+    /// Background color (hex, rgb(), hsl() or a CSS name) to flatten transparency onto for formats without alpha
+    #[arg(long)]
+    background: Option<String>,
+
+    /// Input metadata to copy to the output: keep, strip, or a list like exif,icc,xmp
+    #[arg(long)]
+    metadata: Option<String>,
+
+    /// Keep the GPS position when copying EXIF data; =false turns off a config's setting
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    keep_gps: Option<bool>,
+
+    /// Output color profile: srgb, display-p3, adobe-rgb, input, or an .icc file
+    #[arg(long)]
+    profile: Option<String>,
+
+    /// Keep running and re-render whenever the input or config file changes
+    #[arg(short, long)]
+    watch: bool,
+
+    /// Polling interval for --watch in milliseconds
+    #[arg(long, default_value_t = 500)]
+    poll_interval: u64,
+
+    /// Number of threads effects run on (defaults to the CPU count)
+    #[arg(short = 't', long, global = true)]
+    threads: Option<usize>,
+
+    /// Working bit depth (auto, 8 or 16); auto keeps 16-bit inputs at 16 bits
+    #[arg(long, default_value = "auto")]
+    depth: String,
+
+    /// Process in overlapping tiles, keeping intermediate images within this many megabytes
+    #[arg(long)]
+    memory_limit: Option<u64>,
+
+    /// Render a quick preview with the longer side at most this many pixels, scaling pixel sizes in the pipeline to match
+    #[arg(long, value_name = "MAX_SIDE")]
+    preview: Option<u32>,
+
+    /// Render at full resolution with the pipeline recorded by the last --preview of this output
+    #[arg(long = "final", conflicts_with = "preview")]
+    final_render: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// List every available effect by category
+    ListEffects,
+    /// Show the parameters of an effect and an example pipeline step
+    Describe {
+        /// Effect name, as used in pipeline configs
+        effect: String,
+    },
+    /// Inspect an image
+    Analyze {
+        #[command(subcommand)]
+        analysis: Analysis,
+    },
+    /// Bake a pipeline of pure color effects into a 3D LUT (.cube) for use
+    /// in other tools
+    BakeLut {
+        /// Pipeline config to bake
+        config: PathBuf,
+        /// The .cube file to write
+        output: PathBuf,
+        /// Lattice points per side
+        #[arg(long, default_value_t = 33)]
+        size: usize,
+        /// Title stored in the file (defaults to the output file name)
+        #[arg(long)]
+        title: Option<String>,
+    },
+    /// Run a local web UI to build pipelines with a live preview
+    Serve {
+        /// Port to listen on (localhost only)
+        #[arg(long, default_value_t = 8080)]
+        port: u16,
+    },
+    /// Run an HTTP processing API with a render queue
+    Api {
+        /// Address to bind
+        #[arg(long, default_value = "127.0.0.1")]
+        host: String,
+        /// Port to listen on
+        #[arg(long, default_value_t = 8081)]
+        port: u16,
+        /// Number of renders processed concurrently (defaults to the CPU count)
+        #[arg(long)]
+        workers: Option<usize>,
+        /// Renders that may wait for a worker before requests are rejected with 503
+        #[arg(long, default_value_t = 16)]
+        queue_size: usize,
+        /// Maximum request body size in megabytes
+        #[arg(long, default_value_t = 64)]
+        max_body_mb: usize,
+        /// Largest accepted image in megapixels
+        #[arg(long, default_value_t = 50)]
+        max_megapixels: u64,
+        /// Directory holding the files pipelines may name (LUTs, palette
+        /// references, threshold maps, ICC profiles); without it they are rejected
+        #[arg(long)]
+        files_dir: Option<PathBuf>,
+        /// Seconds a render may run before it is abandoned. Checked between
+        /// pipeline steps: a step that has started always runs to the end
+        #[arg(long, default_value_t = 60)]
+        job_timeout: u64,
+    },
 }
 
-#[derive(Deserialize, Debug)]
-struct TransformConfig {
-    name: String,
-    params: serde_json::Value,
+#[derive(Subcommand, Debug)]
+enum Analysis {
+    /// Print the dominant colors of an image as JSON, found by k-means in Lab
+    Palette {
+        /// Image to analyze
+        image: PathBuf,
+        /// Number of colors to extract
+        #[arg(short = 'n', long, default_value_t = 8)]
+        colors: usize,
+        /// Also write the colors as a GIMP (.gpl) or Adobe Swatch Exchange (.ase) palette
+        #[arg(long)]
+        palette_file: Option<PathBuf>,
+        /// Also write a PNG with a square swatch of every color
+        #[arg(long)]
+        swatch: Option<PathBuf>,
+    },
 }
 
-#[derive(Deserialize, Debug)]
-struct Config {
-    transformations: Vec<TransformConfig>,
+impl Args {
+    // Both are required by clap whenever no subcommand is given
+    fn input(&self) -> &PathBuf {
+        self.input.as_ref().expect("input is required")
+    }
+
+    fn output(&self) -> &PathBuf {
+        self.output.as_ref().expect("output is required")
+    }
+
+    fn output_options(&self) -> OutputOptions {
+        OutputOptions {
+            jpeg_quality: self.jpeg_quality,
+            jpeg_subsampling: self.jpeg_subsampling.clone(),
+            png_compression: self.png_compression.clone(),
+            webp_lossless: self.webp_lossless,
+            webp_quality: self.webp_quality,
+            background: self.background.clone(),
+            metadata: self.metadata.clone(),
+            keep_gps: self.keep_gps,
+            profile: self.profile.clone(),
+        }
+    }
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -50,23 +218,132 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::init();
 
     let args = Args::parse();
-    info!("Starting image processing with input: {:?}, output: {:?}", args.input, args.output);
+    if let Some(threads) = args.threads {
+        if threads == 0 {
+            return Err("--threads must be at least 1".into());
+        }
+        parallel::set_threads(threads)?;
+    }
+    debug!("Running effects on {} thread(s)", parallel::current_threads());
+
+    match &args.command {
+        Some(Command::ListEffects) => return list_effects(),
+        Some(Command::Describe { effect }) => return describe(effect),
+        Some(Command::Analyze { analysis }) => return analyze(analysis),
+        Some(Command::BakeLut { config, output, size, title }) => return bake_lut(config, output, *size, title.clone()),
+        Some(Command::Serve { port }) => return server::serve(*port),
+        Some(Command::Api { host, port, workers, queue_size, max_body_mb, max_megapixels, files_dir, job_timeout }) => {
+            return api::run(ApiOptions {
+                host: host.clone(),
+                port: *port,
+                workers: workers.unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get())),
+                queue_size: *queue_size,
+                max_body_bytes: max_body_mb * 1024 * 1024,
+                max_pixels: max_megapixels.saturating_mul(1_000_000),
+                files_dir: files_dir.clone(),
+                job_timeout: Duration::from_secs(*job_timeout),
+            });
+        },
+        None => {},
+    }
+    info!("Starting image processing with input: {:?}, output: {:?}", args.input(), args.output());
+
+    let (img, metadata) = load_input(&args)?;
+    let config = load_config(&args)?;
+
+    // Check if output directory exists
+    if let Some(parent) = args.output().parent() {
+        if !parent.as_os_str().is_empty() && !parent.exists() {
+            error!("Output directory {:?} does not exist", parent);
+            return Err("Output directory does not exist".into());
+        }
+    }
+
+    // Log the absolute path where we'll save the file
+    let absolute_output = if args.output().is_absolute() {
+        args.output().clone()
+    } else {
+        std::env::current_dir()?.join(args.output())
+    };
+    info!("Saving output to: {:?}", absolute_output);
+
+    if args.watch {
+        return watch(&args, img, metadata, config);
+    }
+
+    let (img, factor) = preview_input(&args, img);
+    let transformations = preview::scale_transformations(&config.transformations, factor);
+    let total_start = Instant::now();
+    let processed_img = match memory_budget(&args)? {
+        Some(budget) => pipeline::apply_pipeline_tiled(img, &transformations, budget)?,
+        None => pipeline::apply_pipeline(img, &transformations)?,
+    };
+    let total_duration = total_start.elapsed();
+
+    save_output(&args, &processed_img, &metadata, config.output)?;
+    info!("Transformations applied and saved to {:?}", args.output());
+    info!("Total time: {} ms", total_duration.as_millis());
+
+    Ok(())
+}
 
-    // Open the image; image::open auto-detects the file type
-    if !args.input.exists() {
-        error!("Input file {:?} does not exist", args.input);
+fn load_input(args: &Args) -> Result<(image::DynamicImage, Metadata), Box<dyn std::error::Error>> {
+    // Decode the image upright; the file type is auto-detected
+    if !args.input().exists() {
+        error!("Input file {:?} does not exist", args.input());
         return Err("Input file does not exist".into());
     }
-    let img = image::open(&args.input)?;
+    let bytes = fs::read(args.input())?;
+    let (img, metadata) = metadata::decode(&bytes)?;
     info!("Successfully loaded input image: {:?} ({}x{})", 
-          args.input, 
+          args.input(), 
           img.width(), 
           img.height());
     
-    // Convert to a standard format (e.g., RGBA8) for consistent processing.
-    let standardized_img = image::DynamicImage::ImageRgba8(img.to_rgba8());
+    // Convert to a standard format (RGBA8 or RGBA16) for consistent processing.
+    let standardized_img = Depth::parse(&args.depth)?.standardize(&img);
 
-    let config: Config = if let Some(num_effects) = args.config.as_ref().and_then(|s| s.parse::<usize>().ok()) {
+    let img = if let Some(prompt) = &args.prompt {
+        info!("Processing image with LangSAM using prompt: {}", prompt);
+        let path = args.input().to_str().unwrap();
+        match langsam_interface::run_langsam_python(path, prompt) {
+            Ok(image_buffer) => {
+                info!("Successfully processed image with LangSAM");
+                // LangSAM reads the file itself, so its mask follows the stored orientation
+                metadata::apply_orientation(image::DynamicImage::ImageRgba8(image_buffer), metadata::read_orientation(&bytes))
+            },
+            Err(e) => {
+                error!("Error running LangSAM: {}. Falling back to original image.", e);
+                standardized_img
+            }
+        }
+    } else {
+        standardized_img
+    };
+    Ok((img, metadata))
+}
+
+// With --preview, the input shrunk to the preview size and the factor its
+// sides were scaled by
+fn preview_input(args: &Args, img: image::DynamicImage) -> (image::DynamicImage, f64) {
+    match args.preview {
+        Some(max_side) => preview::downscale(&img, max_side),
+        None => (img, 1.0),
+    }
+}
+
+fn load_config(args: &Args) -> Result<Config, Box<dyn std::error::Error>> {
+    let mut config: Config = if args.final_render {
+        let path = preview::pipeline_path(args.output());
+        if !path.exists() {
+            error!("No preview pipeline at {:?}", path);
+            return Err("Render a --preview of this output before --final".into());
+        }
+        if args.config.is_some() {
+            info!("Using the previewed pipeline {:?} instead of --config", path);
+        }
+        serde_json::from_str(&fs::read_to_string(&path)?)?
+    } else if let Some(num_effects) = args.config.as_ref().and_then(|s| s.parse::<usize>().ok()) {
         info!("Generating random pipeline with {} effects", num_effects);
         let json = fx_json_generator::generate_random_pipeline(num_effects).to_string();
         serde_json::from_str(&json)?
@@ -86,201 +363,176 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     };
 
     info!("Loaded {} transformations to apply", config.transformations.len());
-    
-    let img = if let Some(prompt) = args.prompt {
-        info!("Processing image with LangSAM using prompt: {}", prompt);
-        let path = args.input.to_str().unwrap();
-        match langsam_interface::run_langsam_python(path, &prompt) {
-            Ok(image_buffer) => {
-                info!("Successfully processed image with LangSAM");
-                image::DynamicImage::ImageRgba8(image_buffer)
-            },
-            Err(e) => {
-                error!("Error running LangSAM: {}. Falling back to original image.", e);
-                image::open(&args.input)?
+
+    // Record the full-resolution pipeline, random seeds included, so --final
+    // renders exactly what the preview showed
+    if args.preview.is_some() {
+        preview::pin_seeds(&mut config.transformations);
+        let path = preview::pipeline_path(args.output());
+        fs::write(&path, serde_json::to_string_pretty(&config)?)?;
+        info!("Recorded the pipeline in {:?}, render it at full resolution with --final", path);
+    }
+    Ok(config)
+}
+
+fn save_output(args: &Args, img: &image::DynamicImage, metadata: &Metadata, config_output: OutputOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let output_options = args.output_options().or(config_output);
+    debug!("Output options: {:?}", output_options);
+    output::save_image(img, args.output(), &output_options, metadata)?;
+    Ok(())
+}
+
+// --memory-limit in bytes
+fn memory_budget(args: &Args) -> Result<Option<u64>, Box<dyn std::error::Error>> {
+    args.memory_limit
+        .map(|mb| mb.checked_mul(1024 * 1024).ok_or_else(|| format!("--memory-limit {} is too large", mb).into()))
+        .transpose()
+}
+
+// Re-render on every change to the input or config file until interrupted.
+// The decoded input and the per-step results are kept between renders, unless
+// --memory-limit is set: then every render runs in tiles and nothing is kept.
+fn watch(args: &Args, img: image::DynamicImage, mut metadata: Metadata, mut config: Config) -> Result<(), Box<dyn std::error::Error>> {
+    let budget = memory_budget(args)?;
+    let (mut img, mut factor) = preview_input(args, img);
+    let mut input_watcher = FileWatcher::new(args.input());
+    let mut config_watcher = args.config.as_ref()
+        .filter(|c| c.parse::<usize>().is_err())
+        .map(|c| FileWatcher::new(c.as_ref()));
+    let mut cache = PipelineCache::new();
+    let mut dirty = true;
+
+    info!("Watching {:?}{} for changes (Ctrl+C to stop)",
+          input_watcher.path(),
+          config_watcher.as_ref().map(|w| format!(" and {:?}", w.path())).unwrap_or_default());
+
+    loop {
+        if input_watcher.changed() {
+            info!("Input {:?} changed, reloading", args.input());
+            match load_input(args) {
+                Ok((new_img, new_metadata)) => {
+                    (img, factor) = preview_input(args, new_img);
+                    metadata = new_metadata;
+                    cache.clear();
+                    dirty = true;
+                },
+                Err(e) => error!("Failed to reload input: {}", e),
+            }
+        }
+        if let Some(watcher) = config_watcher.as_mut() {
+            if watcher.changed() {
+                info!("Config {:?} changed, reloading", watcher.path());
+                match load_config(args) {
+                    Ok(new_config) => {
+                        config = new_config;
+                        dirty = true;
+                    },
+                    Err(e) => error!("Failed to reload config: {}", e),
+                }
             }
         }
-    } else {
-        standardized_img
-    };
 
-    let mut processed_img = img;
-    let total_start = Instant::now();
-    for (i, transform) in config.transformations.iter().enumerate() {
-        debug!("Applying transformation {}/{}: {} with params: {:?}", 
-               i + 1, 
-               config.transformations.len(), 
-               transform.name, 
-               transform.params);
-        let start = Instant::now();
-        processed_img = apply_transformation(processed_img, &transform)?;
-        let duration = start.elapsed();
-        info!(
-            "Applied {}/{}: {}. Time: {} ms. Params: {:?}",
-            i + 1,
-            config.transformations.len(),
-            transform.name,
-            duration.as_millis(),
-            transform.params
-        );
+        if dirty {
+            dirty = false;
+            let start = Instant::now();
+            // Errors are reported and the previous output kept so the next edit can fix them
+            let transformations = preview::scale_transformations(&config.transformations, factor);
+            let rendered = match budget {
+                Some(budget) => pipeline::apply_pipeline_tiled(img.clone(), &transformations, budget),
+                None => cache.apply(&img, &transformations),
+            };
+            match rendered {
+                Ok(processed_img) => match save_output(args, &processed_img, &metadata, config.output.clone()) {
+                    Ok(()) => info!("Rendered {:?} in {} ms", args.output(), start.elapsed().as_millis()),
+                    Err(e) => error!("Failed to save output: {}", e),
+                },
+                Err(e) => error!("Failed to apply pipeline: {}", e),
+            }
+        }
+
+        thread::sleep(Duration::from_millis(args.poll_interval));
     }
-    let total_duration = total_start.elapsed();
+}
 
-    // Check if output directory exists
-    if let Some(parent) = args.output.parent() {
-        if !parent.as_os_str().is_empty() && !parent.exists() {
-            error!("Output directory {:?} does not exist", parent);
-            return Err("Output directory does not exist".into());
-        }
+fn analyze(analysis: &Analysis) -> Result<(), Box<dyn std::error::Error>> {
+    match analysis {
+        Analysis::Palette { image: input, colors, palette_file, swatch } => {
+            // Decoded like pipeline inputs: upright and in sRGB
+            let (img, _) = metadata::decode(&fs::read(input)?)?;
+            let dominant = palette::dominant_colors(&img, *colors)?;
+            println!("{}", serde_json::to_string_pretty(&analyze::palette_json(&dominant))?);
+
+            if let Some(path) = palette_file {
+                match path.extension().and_then(|e| e.to_str()).map(str::to_lowercase).as_deref() {
+                    Some("gpl") => {
+                        let name = input.file_stem().map(|s| s.to_string_lossy()).unwrap_or_default();
+                        fs::write(path, analyze::to_gpl(&dominant, &name))?;
+                    },
+                    Some("ase") => fs::write(path, analyze::to_ase(&dominant))?,
+                    _ => return Err(format!("Unsupported palette file {:?} (use .gpl or .ase)", path).into()),
+                }
+                info!("Wrote palette to {:?}", path);
+            }
+            if let Some(path) = swatch {
+                analyze::swatch(&dominant, SWATCH_SIZE).save_with_format(path, image::ImageFormat::Png)?;
+                info!("Wrote swatch to {:?}", path);
+            }
+            Ok(())
+        },
     }
+}
 
-    // Log the absolute path where we'll save the file
-    let absolute_output = if args.output.is_absolute() {
-        args.output.clone()
-    } else {
-        std::env::current_dir()?.join(&args.output)
-    };
-    info!("Saving output to: {:?}", absolute_output);
+fn bake_lut(config: &PathBuf, output: &PathBuf, size: usize, title: Option<String>) -> Result<(), Box<dyn std::error::Error>> {
+    let config: Config = serde_json::from_str(&fs::read_to_string(config)?)?;
+    let title = title.or_else(|| output.file_stem().map(|s| s.to_string_lossy().into_owned()));
+    let lut = lut::bake(&config.transformations, size, title)?;
+    fs::write(output, lut.to_cube())?;
+    info!("Baked {} steps into a {}x{}x{} LUT at {:?}", config.transformations.len(), size, size, size, output);
+    Ok(())
+}
 
-    processed_img.save(&args.output)?;
-    info!("Transformations applied and saved to {:?}", args.output);
-    info!("Total time: {} ms", total_duration.as_millis());
+// Side of each color square in `analyze palette --swatch`
+const SWATCH_SIZE: u32 = 64;
 
+fn list_effects() -> Result<(), Box<dyn std::error::Error>> {
+    for category in effects::Category::ALL {
+        println!("{}:", category);
+        for effect in effects::by_category(*category) {
+            println!("  {:<16} {}", effect.name, effect.description);
+        }
+    }
     Ok(())
 }
 
-fn apply_transformation(img: image::DynamicImage, transform: &TransformConfig) -> Result<image::DynamicImage, Box<dyn std::error::Error>> {
-    match transform.name.as_str() {
-        "grayscale" => {
-            Ok(img.grayscale())
-        },
-        "invert" => {
-            let mut inverted = img;
-            inverted.invert();
-            Ok(inverted)
-        },
-        "brightness" => {
-            let factor = transform.params["factor"].as_f64().unwrap_or(1.0) as f32;
-            Ok(colorfx::brightness(&img, factor)?)
-        },
-        "sepia" => {
-            Ok(colorfx::sepia(&img)?)
-        },
-        "contrast" => {
-            let factor = transform.params["factor"].as_f64().unwrap_or(1.0) as f32;
-            Ok(colorfx::contrast(&img, factor))
-        },
-        "saturation" => {
-            let factor = transform.params["factor"].as_f64().unwrap_or(1.0) as f32;
-            colorfx::saturation(&img, factor).map_err(|e| e.into())
-        },
-        "add_noise" => {
-            let intensity = transform.params["intensity"].as_f64().unwrap_or(0.1) as f32;
-            Ok(colorfx::add_noise(&img, intensity)?)
-        },
-        "deepfry" => {
-            let factor = transform.params["factor"].as_f64().unwrap_or(1.0) as f32;
-            Ok(colorfx::deep_fry(&img, factor)?)
-        },
-        "hue_rotate" => {
-            let angle = transform.params["angle"].as_f64().unwrap_or(90.0) as f32;
-            Ok(colorfx::hue_rotate(&img, angle)?)
-        },
-        "color_replacer" => {
-            let target_color: String = transform.params["target_color"].to_string();
-            let replacement_color: String = transform.params["replacement_color"].to_string();
-            let tolerance: u8 = transform.params.get("tolerance").and_then(|v| v.as_u64()).unwrap_or(50) as u8;
-            Ok(colorfx::color_replacer(&img, &target_color, &replacement_color, tolerance)?)
-        },
-        "vaporwave" => {
-            Ok(colorfx::vaporwave(&img)?)
-        },
-        "dither" => {
-            let levels = transform.params["levels"].as_u64().unwrap_or(4) as u8;
-            let matrix_size = transform.params.get("matrix_size").and_then(|v| v.as_u64()).map(|v| v as u32);
-            let point_size = transform.params.get("point_size").and_then(|v| v.as_u64()).map(|v| v as u32);
-            let threshold_bias = transform.params.get("threshold_bias").and_then(|v| v.as_f64()).map(|v| v as f32);
-            Ok(colorfx::dither(&img, levels, matrix_size, point_size, threshold_bias)?)
-        },
-        "neon_edge" => {
-            let strength = transform.params["strength"].as_f64().unwrap_or(1.0) as f32;
-            let color_shift = transform.params["color_shift"].as_f64().unwrap_or(0.0) as f32;
-            let brightness = transform.params["brightness"].as_f64().unwrap_or(1.0) as f32;
-            Ok(edgesfx::neon_edge(&img, strength, color_shift, brightness)?)
-        },
-        "sketch" => {
-            let intensity = transform.params["intensity"].as_f64().unwrap_or(10.0) as f32;
-            let contrast = transform.params["contrast"].as_f64().unwrap_or(1.0) as f32;
-            let invert = transform.params["invert"].as_bool().unwrap_or(false);
-            Ok(edgesfx::sketch(&img, intensity, contrast, invert)?)
-        },
-        "emboss" => {
-            let strength = transform.params["strength"].as_f64().unwrap_or(100000.0) as f32;
-            let angle = transform.params["angle"].as_f64().unwrap_or(45.0) as f32;
-            Ok(edgesfx::emboss(&img, strength, angle)?)
-        },
-        "quantized_edge" => {
-            let threshold = transform.params["threshold"].as_u64().unwrap_or(80) as f32;
-            let level: u8 = transform.params["level"].as_u64().unwrap_or(1) as u8;
-            Ok(edgesfx::quantized_edge(&img, level,threshold)?)
-        },
-        "extrusion_edge" => {
-            let threshold = transform.params["threshold"].as_u64().unwrap_or(100) as f32;
-            let strength = transform.params["strength"].as_f64().unwrap_or(2.0) as f32;
-            let depth = transform.params["depth"].as_u64().unwrap_or(15) as u32;
-            Ok(edgesfx::edge_extrusion(&img, strength, depth, threshold)?)
-        },
-        "blur" => {
-            let sigma = transform.params["sigma"].as_f64().unwrap_or(2.0) as f32;
-            Ok(img.blur(sigma))
-        },
-        "pixelate" => {
-            let block_size = transform.params["block_size"].as_u64().unwrap_or(10) as u32;
-            Ok(glitchfx::pixelate(&img, block_size))
-        },
-        "oil_painting" => {
-            let radius = transform.params["radius"].as_u64().unwrap_or(4) as u32;
-            let intensity = transform.params["intensity"].as_u64().unwrap_or(30) as u32;
-            Ok(glitchfx::oil_painting(&img, radius, intensity.try_into().unwrap()))
-        },
-        "glitch" => {
-            let amount = transform.params["amount"].as_u64().unwrap_or(50) as u32;
-            let max_offset = transform.params["max_offset"].as_u64().unwrap_or(10) as i32;
-            let direction: String = transform.params["direction"].to_string();
-            let noisy_pixels: bool = transform.params["noisy"].as_bool().unwrap_or(false);
-            Ok(glitchfx::glitch(&img, amount, max_offset, &direction, noisy_pixels))
-        },
-        "pixel_sort" => {
-            let low_threshold: u8 = transform.params.get("low-threshold").and_then(|v| v.as_u64()).unwrap_or(150) as u8;
-            let high_threshold: u8 = transform.params.get("high-threshold").and_then(|v| v.as_u64()).unwrap_or(200) as u8;
-            let direction: String = transform.params["direction"].to_string();
-            let window_size: usize = transform.params.get("window_size").and_then(|v| v.as_u64()).unwrap_or(100) as usize;
-            Ok(glitchfx::pixel_sort(&img, &direction, low_threshold,high_threshold,  window_size))
-        },
-        "rotate" => {
-            let angle = transform.params["angle"].as_u64().unwrap_or(90) as f32;
-            Ok(glitchfx::rotate(&img, angle)?)
-        },
-        "desync" => {
-            let x_shift = transform.params["x_shift"].as_i64().unwrap_or(10) as i32;
-            let y_shift = transform.params["y_shift"].as_i64().unwrap_or(10) as i32;
-            Ok(glitchfx::desync(&img, x_shift, y_shift)?)
-        },
-        "wind" => {
-            let direction = transform.params["direction"].as_str().unwrap_or("right").to_string();
-            let strength: u32 = transform.params["strength"].as_u64().unwrap_or(10) as u32;
-            Ok(glitchfx::wind(&img, &direction, strength)?)
-        },
-        "scan_lines" => {
-            let line_thickness = transform.params["line_thickness"].as_u64().unwrap_or(2) as u32;
-            let line_spacing = transform.params["line_spacing"].as_u64().unwrap_or(10) as u32;
-            let opacity = transform.params["opacity"].as_f64().unwrap_or(0.5) as f32;
-            let angle = transform.params["angle"].as_f64().unwrap_or(0.0) as f32;
-            Ok(glitchfx::scan_lines(&img, Some(line_thickness), Some(line_spacing), Some(angle), Some(opacity))?)
-        },
-        _ => {
-            warn!("Invalid transformation specified: {}", transform.name);
-            Ok(img)
+fn describe(name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let effect = effects::find(name)
+        .ok_or_else(|| format!("Unknown effect: {}. Run list-effects to see all effects", name))?;
+    println!("{} ({})", effect.name, effect.category);
+    println!("  {}", effect.description);
+    println!("  alpha: {}", effect.alpha.as_str());
+    println!("  depth: {}", if effect.full_depth { "16 bits" } else { "8 bits (16-bit images lose precision)" });
+    println!();
+    if effect.params.is_empty() {
+        println!("No parameters");
+    } else {
+        println!("Parameters:");
+        for param in effect.params {
+            let default = match param.default {
+                ParamDefault::Required => "required".to_string(),
+                ParamDefault::Unset => "effect decides".to_string(),
+                default => default.to_json().to_string(),
+            };
+            println!("  {}", param.name);
+            println!("    type:    {}", param.kind);
+            println!("    default: {}", default);
+            if let Some(range) = param.range_string() {
+                println!("    range:   {}", range);
+            }
+            println!("    {}", param.description);
         }
     }
+    println!();
+    println!("Example:");
+    println!("{}", serde_json::to_string_pretty(&effect.example())?);
+    Ok(())
 }
@@ -0,0 +1,483 @@
+use image::{DynamicImage, GenericImageView, ImageFormat, ImageResult};
+use log::{debug, info, warn};
+
+use crate::icc::{self, Profile};
+
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const ICC_HEADER: &[u8] = b"ICC_PROFILE\0";
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+const XMP_KEYWORD: &[u8] = b"XML:com.adobe.xmp";
+
+// Largest payload of a JPEG marker segment (the length field counts itself)
+const JPEG_SEGMENT_MAX: usize = 65533;
+
+const TAG_ORIENTATION: u16 = 0x0112;
+const TAG_GPS_IFD: u16 = 0x8825;
+
+/// Metadata blocks carried over from the input file. `exif` is the raw TIFF
+/// structure without the `Exif\0\0` prefix.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metadata {
+    pub exif: Option<Vec<u8>>,
+    pub icc: Option<Vec<u8>>,
+    pub xmp: Option<Vec<u8>>,
+}
+
+impl Metadata {
+    pub fn is_empty(&self) -> bool {
+        self.exif.is_none() && self.icc.is_none() && self.xmp.is_none()
+    }
+}
+
+/// Decodes an image file, turning it upright according to its EXIF
+/// orientation and converting it from its embedded ICC profile to the sRGB
+/// working space. The returned EXIF is updated to match the upright pixels:
+/// the orientation is reset and the embedded thumbnail of the original
+/// dropped. The original ICC profile is kept for `--profile input`.
+pub fn decode(bytes: &[u8]) -> ImageResult<(DynamicImage, Metadata)> {
+    let img = image::load_from_memory(bytes)?;
+    let mut metadata = read(bytes);
+    let mut orientation = 1;
+    if let Some(exif) = metadata.exif.as_mut() {
+        orientation = exif_orientation(exif).unwrap_or(1);
+        sanitize_exif(exif);
+    }
+    if orientation != 1 {
+        debug!("Applying EXIF orientation {}", orientation);
+    }
+    let mut img = apply_orientation(img, orientation);
+    if let Some(bytes) = &metadata.icc {
+        match Profile::parse(bytes) {
+            Ok(profile) if profile.same_as(&Profile::srgb()) => {},
+            Ok(profile) => {
+                info!("Converting from the embedded {} profile to sRGB", profile.name);
+                img = icc::convert(&img, &profile, &Profile::srgb());
+            },
+            Err(e) => warn!("Ignoring the embedded ICC profile, colors are processed as sRGB: {}", e),
+        }
+    }
+    Ok((img, metadata))
+}
+
+/// EXIF orientation (1-8) of a JPEG, PNG or WebP file, 1 when there is none.
+pub fn read_orientation(bytes: &[u8]) -> u16 {
+    read(bytes).exif.and_then(|exif| exif_orientation(&exif)).unwrap_or(1)
+}
+
+/// Turns an image stored with EXIF orientation `orientation` upright.
+pub fn apply_orientation(img: DynamicImage, orientation: u16) -> DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
+
+/// Extracts EXIF, ICC and XMP from a JPEG, PNG or WebP file. Other formats and
+/// malformed files give empty metadata.
+pub fn read(bytes: &[u8]) -> Metadata {
+    if bytes.starts_with(&[0xFF, 0xD8]) {
+        read_jpeg(bytes)
+    } else if bytes.starts_with(PNG_SIGNATURE) {
+        read_png(bytes)
+    } else if bytes.len() >= 12 && &bytes[0..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        read_webp(bytes)
+    } else {
+        Metadata::default()
+    }
+}
+
+fn read_jpeg(bytes: &[u8]) -> Metadata {
+    let mut metadata = Metadata::default();
+    let mut icc_chunks: Vec<(u8, &[u8])> = Vec::new();
+    let mut pos = 2;
+    while pos + 4 <= bytes.len() && bytes[pos] == 0xFF {
+        let marker = bytes[pos + 1];
+        // Fill bytes and markers without a length
+        if marker == 0xFF {
+            pos += 1;
+            continue;
+        }
+        if marker == 0x01 || (0xD0..=0xD7).contains(&marker) {
+            pos += 2;
+            continue;
+        }
+        // Metadata always comes before the scan data
+        if marker == 0xDA || marker == 0xD9 {
+            break;
+        }
+        let length = u16::from_be_bytes([bytes[pos + 2], bytes[pos + 3]]) as usize;
+        let Some(data) = bytes.get(pos + 4..pos + 2 + length) else { break };
+        match marker {
+            0xE1 if data.starts_with(EXIF_HEADER) => metadata.exif = Some(data[EXIF_HEADER.len()..].to_vec()),
+            0xE1 if data.starts_with(XMP_HEADER) => metadata.xmp = Some(data[XMP_HEADER.len()..].to_vec()),
+            0xE2 if data.starts_with(ICC_HEADER) && data.len() > ICC_HEADER.len() + 2 => {
+                icc_chunks.push((data[ICC_HEADER.len()], &data[ICC_HEADER.len() + 2..]));
+            },
+            _ => {},
+        }
+        pos += 2 + length;
+    }
+    // Large profiles are split over several numbered segments
+    if !icc_chunks.is_empty() {
+        icc_chunks.sort_by_key(|(seq, _)| *seq);
+        metadata.icc = Some(icc_chunks.iter().flat_map(|(_, chunk)| chunk.iter().copied()).collect());
+    }
+    metadata
+}
+
+fn png_chunks(bytes: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    let mut pos = PNG_SIGNATURE.len();
+    std::iter::from_fn(move || {
+        let length = u32::from_be_bytes(bytes.get(pos..pos + 4)?.try_into().ok()?) as usize;
+        let kind = bytes.get(pos + 4..pos + 8)?;
+        let data = bytes.get(pos + 8..pos + 8 + length)?;
+        pos += 12 + length;
+        Some((kind, data))
+    })
+}
+
+fn read_png(bytes: &[u8]) -> Metadata {
+    let mut metadata = Metadata::default();
+    for (kind, data) in png_chunks(bytes) {
+        match kind {
+            b"eXIf" => metadata.exif = Some(data.to_vec()),
+            b"iCCP" => {
+                // Profile name, then the compression method and zlib data
+                if let Some(name_end) = data.iter().position(|&b| b == 0) {
+                    metadata.icc = data.get(name_end + 2..)
+                        .and_then(|compressed| miniz_oxide::inflate::decompress_to_vec_zlib(compressed).ok());
+                }
+            },
+            b"iTXt" if data.starts_with(XMP_KEYWORD) && data.get(XMP_KEYWORD.len()) == Some(&0) => {
+                metadata.xmp = read_itxt(&data[XMP_KEYWORD.len() + 1..]);
+            },
+            b"IEND" => break,
+            _ => {},
+        }
+    }
+    metadata
+}
+
+// The part of an iTXt chunk after the keyword
+fn read_itxt(data: &[u8]) -> Option<Vec<u8>> {
+    let (&compressed, rest) = data.split_first()?;
+    let rest = rest.get(1..)?;
+    // Skip the language tag and the translated keyword
+    let language_end = rest.iter().position(|&b| b == 0)?;
+    let rest = &rest[language_end + 1..];
+    let translated_end = rest.iter().position(|&b| b == 0)?;
+    let text = &rest[translated_end + 1..];
+    if compressed == 1 {
+        miniz_oxide::inflate::decompress_to_vec_zlib(text).ok()
+    } else {
+        Some(text.to_vec())
+    }
+}
+
+fn riff_chunks(bytes: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    let mut pos = 12;
+    std::iter::from_fn(move || {
+        let kind = bytes.get(pos..pos + 4)?;
+        let length = u32::from_le_bytes(bytes.get(pos + 4..pos + 8)?.try_into().ok()?) as usize;
+        let data = bytes.get(pos + 8..pos + 8 + length)?;
+        // Chunks are padded to an even size
+        pos += 8 + length + length % 2;
+        Some((kind, data))
+    })
+}
+
+fn read_webp(bytes: &[u8]) -> Metadata {
+    let mut metadata = Metadata::default();
+    for (kind, data) in riff_chunks(bytes) {
+        match kind {
+            // Some writers keep the JPEG-style prefix
+            b"EXIF" => metadata.exif = Some(data.strip_prefix(EXIF_HEADER).unwrap_or(data).to_vec()),
+            b"ICCP" => metadata.icc = Some(data.to_vec()),
+            b"XMP " => metadata.xmp = Some(data.to_vec()),
+            _ => {},
+        }
+    }
+    metadata
+}
+
+// Minimal reader for the TIFF structure inside EXIF blocks
+struct Tiff<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+struct IfdEntry {
+    tag: u16,
+    kind: u16,
+    count: u32,
+    // Offset of the entry itself within the block
+    offset: usize,
+}
+
+impl<'a> Tiff<'a> {
+    fn new(data: &'a [u8]) -> Option<Self> {
+        let big_endian = match data.get(0..4)? {
+            b"MM\0*" => true,
+            b"II*\0" => false,
+            _ => return None,
+        };
+        Some(Tiff { data, big_endian })
+    }
+
+    fn u16_at(&self, offset: usize) -> Option<u16> {
+        let bytes = self.data.get(offset..offset + 2)?.try_into().ok()?;
+        Some(if self.big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) })
+    }
+
+    fn u32_at(&self, offset: usize) -> Option<u32> {
+        let bytes = self.data.get(offset..offset + 4)?.try_into().ok()?;
+        Some(if self.big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) })
+    }
+
+    fn ifd0(&self) -> Option<usize> {
+        self.u32_at(4).map(|offset| offset as usize)
+    }
+
+    fn entries(&self, ifd: usize) -> Option<Vec<IfdEntry>> {
+        let count = self.u16_at(ifd)? as usize;
+        (0..count)
+            .map(|i| {
+                let offset = ifd + 2 + i * 12;
+                Some(IfdEntry {
+                    tag: self.u16_at(offset)?,
+                    kind: self.u16_at(offset + 2)?,
+                    count: self.u32_at(offset + 4)?,
+                    offset,
+                })
+            })
+            .collect()
+    }
+
+    // Where the value of an entry lives: inline when it fits in 4 bytes
+    fn value_range(&self, entry: &IfdEntry) -> Option<(usize, usize)> {
+        let size = match entry.kind {
+            1 | 2 | 6 | 7 => 1,
+            3 | 8 => 2,
+            4 | 9 | 11 => 4,
+            5 | 10 | 12 => 8,
+            _ => return None,
+        } * entry.count as usize;
+        if size <= 4 {
+            Some((entry.offset + 8, size))
+        } else {
+            Some((self.u32_at(entry.offset + 8)? as usize, size))
+        }
+    }
+}
+
+// Does nothing when the block is cut short before `offset`
+fn write_u16(data: &mut [u8], offset: usize, value: u16, big_endian: bool) {
+    let bytes = if big_endian { value.to_be_bytes() } else { value.to_le_bytes() };
+    if let Some(slot) = data.get_mut(offset..offset + 2) {
+        slot.copy_from_slice(&bytes);
+    }
+}
+
+fn exif_orientation(exif: &[u8]) -> Option<u16> {
+    let tiff = Tiff::new(exif)?;
+    let entry = tiff.entries(tiff.ifd0()?)?.into_iter().find(|e| e.tag == TAG_ORIENTATION)?;
+    tiff.u16_at(entry.offset + 8)
+}
+
+// Resets the orientation to upright and unlinks the thumbnail IFD, which
+// still shows the unprocessed image
+fn sanitize_exif(exif: &mut [u8]) {
+    let Some(tiff) = Tiff::new(exif) else { return };
+    let Some(ifd0) = tiff.ifd0() else { return };
+    let Some(entries) = tiff.entries(ifd0) else { return };
+    let big_endian = tiff.big_endian;
+    let orientation = entries.iter().find(|e| e.tag == TAG_ORIENTATION).map(|e| e.offset + 8);
+    let next_ifd = ifd0 + 2 + entries.len() * 12;
+
+    if let Some(offset) = orientation {
+        write_u16(exif, offset, 1, big_endian);
+    }
+    if let Some(next) = exif.get_mut(next_ifd..next_ifd + 4) {
+        next.fill(0);
+    }
+}
+
+/// Removes the GPS block from an EXIF structure, wiping its values rather than
+/// just unlinking them. Returns false when the structure could not be parsed,
+/// in which case the caller should drop the EXIF altogether.
+pub fn strip_gps(exif: &mut [u8]) -> bool {
+    let Some(tiff) = Tiff::new(exif) else { return false };
+    let Some(ifd0) = tiff.ifd0() else { return false };
+    let Some(entries) = tiff.entries(ifd0) else { return false };
+    let Some(index) = entries.iter().position(|e| e.tag == TAG_GPS_IFD) else { return true };
+    let Some(gps_ifd) = tiff.u32_at(entries[index].offset + 8).map(|o| o as usize) else { return false };
+
+    // Everything the GPS IFD points at, then the IFD itself
+    let mut wipe = Vec::new();
+    if let Some(gps_entries) = tiff.entries(gps_ifd) {
+        for entry in &gps_entries {
+            if let Some(range) = tiff.value_range(entry) {
+                wipe.push(range);
+            }
+        }
+        wipe.push((gps_ifd, 2 + gps_entries.len() * 12 + 4));
+    }
+    let count = entries.len();
+    let big_endian = tiff.big_endian;
+
+    for (start, size) in wipe {
+        if let Some(bytes) = exif.get_mut(start..start + size) {
+            bytes.fill(0);
+        }
+    }
+    // Drop the entry from IFD0, moving the later entries and the next-IFD
+    // offset up
+    let entry_start = ifd0 + 2 + index * 12;
+    let table_end = ifd0 + 2 + count * 12 + 4;
+    if table_end > exif.len() {
+        return false;
+    }
+    exif.copy_within(entry_start + 12..table_end, entry_start);
+    exif[table_end - 12..table_end].fill(0);
+    write_u16(exif, ifd0, count as u16 - 1, big_endian);
+    true
+}
+
+fn png_chunk(kind: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut chunk = Vec::with_capacity(data.len() + 12);
+    chunk.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    chunk.extend_from_slice(kind);
+    chunk.extend_from_slice(data);
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(kind);
+    crc.update(data);
+    chunk.extend_from_slice(&crc.finalize().to_be_bytes());
+    chunk
+}
+
+fn embed_png(bytes: Vec<u8>, metadata: &Metadata) -> Result<Vec<u8>, String> {
+    // IHDR is always the first chunk, and everything goes right after it
+    let ihdr_end = PNG_SIGNATURE.len() + 25;
+    if !bytes.starts_with(PNG_SIGNATURE) || bytes.get(12..16) != Some(b"IHDR") {
+        return Err("Encoder produced an invalid PNG".into());
+    }
+    let mut chunks = Vec::new();
+    if let Some(icc) = &metadata.icc {
+        let mut data = b"ICC Profile\0\0".to_vec();
+        data.extend(miniz_oxide::deflate::compress_to_vec_zlib(icc, 6));
+        chunks.extend(png_chunk(b"iCCP", &data));
+    }
+    if let Some(exif) = &metadata.exif {
+        chunks.extend(png_chunk(b"eXIf", exif));
+    }
+    if let Some(xmp) = &metadata.xmp {
+        // Uncompressed, with empty language and translated keyword
+        let mut data = XMP_KEYWORD.to_vec();
+        data.extend_from_slice(b"\0\0\0\0\0");
+        data.extend_from_slice(xmp);
+        chunks.extend(png_chunk(b"iTXt", &data));
+    }
+    let mut output = bytes;
+    output.splice(ihdr_end..ihdr_end, chunks);
+    Ok(output)
+}
+
+fn riff_chunk(kind: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut chunk = Vec::with_capacity(data.len() + 9);
+    chunk.extend_from_slice(kind);
+    chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    chunk.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        chunk.push(0);
+    }
+    chunk
+}
+
+fn embed_webp(bytes: Vec<u8>, metadata: &Metadata, width: u32, height: u32, has_alpha: bool) -> Result<Vec<u8>, String> {
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WEBP" {
+        return Err("Encoder produced an invalid WebP".into());
+    }
+    // Metadata needs the extended format: a VP8X header, then ICCP, the
+    // image chunks, EXIF and XMP in that order
+    let mut flags = 0u8;
+    let mut image_chunks = Vec::new();
+    for (kind, data) in riff_chunks(&bytes) {
+        match kind {
+            b"VP8X" => flags = data.first().copied().unwrap_or(0),
+            b"ICCP" | b"EXIF" | b"XMP " => {},
+            _ => image_chunks.extend(riff_chunk(kind, data)),
+        }
+    }
+    if has_alpha {
+        flags |= 0x10;
+    }
+    let mut chunks = Vec::new();
+    if let Some(icc) = &metadata.icc {
+        flags |= 0x20;
+        chunks.extend(riff_chunk(b"ICCP", icc));
+    }
+    chunks.extend(image_chunks);
+    if let Some(exif) = &metadata.exif {
+        flags |= 0x08;
+        chunks.extend(riff_chunk(b"EXIF", exif));
+    }
+    if let Some(xmp) = &metadata.xmp {
+        flags |= 0x04;
+        chunks.extend(riff_chunk(b"XMP ", xmp));
+    }
+
+    let mut vp8x = vec![flags, 0, 0, 0];
+    vp8x.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+    vp8x.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+    let mut body = b"WEBP".to_vec();
+    body.extend(riff_chunk(b"VP8X", &vp8x));
+    body.extend(chunks);
+
+    let mut output = b"RIFF".to_vec();
+    output.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    output.extend(body);
+    Ok(output)
+}
+
+/// Adds `metadata` to an encoded PNG or WebP file. JPEG metadata is written by
+/// the encoder itself; other formats are returned unchanged.
+pub fn embed(bytes: Vec<u8>, format: ImageFormat, metadata: &Metadata, img: &DynamicImage) -> Result<Vec<u8>, String> {
+    if metadata.is_empty() {
+        return Ok(bytes);
+    }
+    match format {
+        ImageFormat::Png => embed_png(bytes, metadata),
+        ImageFormat::WebP => embed_webp(bytes, metadata, img.width(), img.height(), img.color().has_alpha()),
+        ImageFormat::Jpeg => Ok(bytes),
+        other => {
+            warn!("Metadata is not written for {:?} output", other);
+            Ok(bytes)
+        },
+    }
+}
+
+/// Adds `metadata` to a JPEG encoder, skipping EXIF and XMP blocks too large
+/// for a single segment.
+pub fn add_to_jpeg<W: jpeg_encoder::JfifWrite>(encoder: &mut jpeg_encoder::Encoder<W>, metadata: &Metadata) -> Result<(), String> {
+    if let Some(icc) = &metadata.icc {
+        encoder.add_icc_profile(icc).map_err(|e| e.to_string())?;
+    }
+    for (header, block) in [(EXIF_HEADER, &metadata.exif), (XMP_HEADER, &metadata.xmp)] {
+        let Some(block) = block else { continue };
+        if header.len() + block.len() > JPEG_SEGMENT_MAX {
+            warn!("Metadata block of {} bytes is too large for JPEG, skipping it", block.len());
+            continue;
+        }
+        let mut data = header.to_vec();
+        data.extend_from_slice(block);
+        encoder.add_app_segment(1, &data).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
@@ -0,0 +1,292 @@
+use std::borrow::Cow;
+use std::fs;
+use std::path::Path;
+
+use image::codecs::png::{CompressionType, FilterType, PngEncoder};
+use image::{DynamicImage, GenericImageView, ImageEncoder, ImageFormat, Pixel, Rgb, RgbImage};
+use jpeg_encoder::SamplingFactor;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::colorfx::utils::parse_color;
+use crate::depth;
+use crate::icc::{self, Profile};
+use crate::metadata::{self, Metadata};
+
+/// Encoder settings for the final image, read from the `output` block of a
+/// pipeline config and overridable from the command line.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct OutputOptions {
+    /// JPEG quality, 1-100 (default 75).
+    pub jpeg_quality: Option<u8>,
+    /// JPEG chroma subsampling: "4:4:4", "4:2:2" or "4:2:0" (default).
+    pub jpeg_subsampling: Option<String>,
+    /// PNG compression: "fast" (default), "default", "best", "huffman" or "rle".
+    pub png_compression: Option<String>,
+    /// Write WebP losslessly instead of using `webp_quality`.
+    pub webp_lossless: Option<bool>,
+    /// Lossy WebP quality, 0-100 (default 80).
+    pub webp_quality: Option<f32>,
+    /// Color translucent pixels are flattened onto when the output format has
+    /// no alpha channel, in any syntax `parse_color` accepts.
+    pub background: Option<String>,
+    /// Input metadata to copy to the output: "keep" (default), "strip", or a
+    /// comma-separated list of "exif", "icc" and "xmp".
+    pub metadata: Option<String>,
+    /// Keep the GPS position in copied EXIF data (default false).
+    pub keep_gps: Option<bool>,
+    /// Color profile of the output: "srgb" (default), "display-p3",
+    /// "adobe-rgb", "input" for the input's own profile, or an .icc file.
+    pub profile: Option<String>,
+}
+
+impl OutputOptions {
+    /// Fills every unset field from `other`, so values already set here win.
+    pub fn or(self, other: OutputOptions) -> OutputOptions {
+        OutputOptions {
+            jpeg_quality: self.jpeg_quality.or(other.jpeg_quality),
+            jpeg_subsampling: self.jpeg_subsampling.or(other.jpeg_subsampling),
+            png_compression: self.png_compression.or(other.png_compression),
+            webp_lossless: self.webp_lossless.or(other.webp_lossless),
+            webp_quality: self.webp_quality.or(other.webp_quality),
+            background: self.background.or(other.background),
+            metadata: self.metadata.or(other.metadata),
+            keep_gps: self.keep_gps.or(other.keep_gps),
+            profile: self.profile.or(other.profile),
+        }
+    }
+}
+
+/// The part of the input metadata `options` asks to copy to the output.
+pub fn select_metadata(input: &Metadata, options: &OutputOptions) -> Result<Metadata, String> {
+    let (mut exif, mut icc, mut xmp) = (false, false, false);
+    match options.metadata.as_deref().unwrap_or("keep") {
+        "keep" => (exif, icc, xmp) = (true, true, true),
+        "strip" => {},
+        list => {
+            for block in list.split(',').map(str::trim) {
+                match block {
+                    "exif" => exif = true,
+                    "icc" => icc = true,
+                    "xmp" => xmp = true,
+                    other => return Err(format!("Invalid metadata block: {} (use exif, icc or xmp)", other)),
+                }
+            }
+        },
+    }
+
+    let mut selected = Metadata {
+        exif: input.exif.clone().filter(|_| exif),
+        icc: input.icc.clone().filter(|_| icc),
+        xmp: input.xmp.clone().filter(|_| xmp),
+    };
+    if !options.keep_gps.unwrap_or(false) {
+        if let Some(block) = selected.exif.as_mut() {
+            if !metadata::strip_gps(block) {
+                warn!("Could not parse the EXIF data to remove the GPS position; dropping EXIF");
+                selected.exif = None;
+            }
+        }
+    }
+    Ok(selected)
+}
+
+// Formats whose encoders can store 16 bits per channel
+fn supports_16bit(format: ImageFormat) -> bool {
+    matches!(format, ImageFormat::Png | ImageFormat::Tiff)
+}
+
+// Formats whose encoders cannot store an alpha channel
+fn supports_alpha(format: ImageFormat) -> bool {
+    !matches!(format, ImageFormat::Jpeg | ImageFormat::Pnm)
+}
+
+fn has_translucency(img: &DynamicImage) -> bool {
+    img.color().has_alpha() && img.to_rgba8().pixels().any(|p| p[3] < 255)
+}
+
+// Composite the image over a solid background, discarding alpha
+fn flatten(img: &DynamicImage, background: Rgb<u8>) -> RgbImage {
+    let rgba = img.to_rgba8();
+    let mut output = RgbImage::new(rgba.width(), rgba.height());
+    for (x, y, pixel) in rgba.enumerate_pixels() {
+        let alpha = pixel[3] as f32 / 255.0;
+        let mut flat = [0u8; 3];
+        for c in 0..3 {
+            flat[c] = (pixel[c] as f32 * alpha + background[c] as f32 * (1.0 - alpha)).round() as u8;
+        }
+        output.put_pixel(x, y, Rgb(flat));
+    }
+    output
+}
+
+/// Resolves the image that will actually be encoded, flattening or dropping
+/// alpha when the target format cannot hold it. 16-bit images are reduced
+/// to 8 bits for formats that only store 8.
+pub fn prepare_for_format(img: &DynamicImage, format: ImageFormat, options: &OutputOptions) -> Result<DynamicImage, String> {
+    if depth::is_16bit(img) && !supports_16bit(format) {
+        info!("{:?} stores 8 bits per channel; reducing the 16-bit image", format);
+        return prepare_for_format(&DynamicImage::ImageRgba8(depth::to_rgba8(img)), format, options);
+    }
+    if supports_alpha(format) || !img.color().has_alpha() {
+        return Ok(img.clone());
+    }
+    match &options.background {
+        Some(color) => {
+            let background = parse_color(color)?.to_rgb();
+            info!("Flattening alpha onto {} for {:?} output", color, format);
+            Ok(DynamicImage::ImageRgb8(flatten(img, background)))
+        },
+        None => {
+            if has_translucency(img) {
+                warn!("{:?} has no alpha channel; transparency will be lost. Set a background color to flatten instead", format);
+            }
+            Ok(DynamicImage::ImageRgb8(img.to_rgb8()))
+        }
+    }
+}
+
+// Target of `options.profile` with the ICC data to embed, or `None` to stay
+// in the sRGB working space
+fn output_profile(options: &OutputOptions, input: &Metadata) -> Result<Option<(Profile, Vec<u8>)>, String> {
+    match options.profile.as_deref().unwrap_or("srgb") {
+        "srgb" => Ok(None),
+        "input" => Ok(input.icc.as_ref().and_then(|bytes| Some((Profile::parse(bytes).ok()?, bytes.clone())))),
+        name => match Profile::named(name) {
+            Some(profile) => {
+                let bytes = profile.to_icc();
+                Ok(Some((profile, bytes)))
+            },
+            None => {
+                let bytes = fs::read(name).map_err(|e| format!("Could not read output profile {}: {}", name, e))?;
+                Ok(Some((Profile::parse(&bytes)?, bytes)))
+            },
+        },
+    }
+}
+
+/// Converts the working-space image to the output profile picked in
+/// `options`, returning the ICC profile that describes the result. Profiles
+/// other than sRGB are always embedded, since the colors are wrong without
+/// them; an sRGB profile only replaces an input profile that `keep_icc` keeps.
+pub fn apply_profile<'a>(img: &'a DynamicImage, options: &OutputOptions, input: &Metadata, keep_icc: bool) -> Result<(Cow<'a, DynamicImage>, Option<Vec<u8>>), String> {
+    if let Some((profile, bytes)) = output_profile(options, input)? {
+        info!("Converting to the {} output profile", profile.name);
+        return Ok((Cow::Owned(icc::convert(img, &Profile::srgb(), &profile)), Some(bytes)));
+    }
+    // Profiles that could not be read were not converted from, so they still apply
+    let icc = input.icc.as_ref()
+        .filter(|_| keep_icc)
+        .map(|bytes| if Profile::parse(bytes).is_ok() { Profile::srgb().to_icc() } else { bytes.clone() });
+    Ok((Cow::Borrowed(img), icc))
+}
+
+fn jpeg_sampling(subsampling: Option<&str>) -> Result<SamplingFactor, String> {
+    match subsampling.unwrap_or("4:2:0") {
+        "4:4:4" | "444" => Ok(SamplingFactor::R_4_4_4),
+        "4:2:2" | "422" => Ok(SamplingFactor::R_4_2_2),
+        "4:2:0" | "420" => Ok(SamplingFactor::R_4_2_0),
+        other => Err(format!("Invalid JPEG subsampling: {}", other)),
+    }
+}
+
+fn png_compression(compression: Option<&str>) -> Result<CompressionType, String> {
+    match compression.unwrap_or("fast") {
+        "fast" => Ok(CompressionType::Fast),
+        "default" => Ok(CompressionType::Default),
+        "best" => Ok(CompressionType::Best),
+        "huffman" => Ok(CompressionType::Huffman),
+        "rle" => Ok(CompressionType::Rle),
+        other => Err(format!("Invalid PNG compression: {}", other)),
+    }
+}
+
+fn encode_jpeg(img: &DynamicImage, options: &OutputOptions, metadata: &Metadata) -> Result<Vec<u8>, String> {
+    let quality = options.jpeg_quality.unwrap_or(75);
+    if !(1..=100).contains(&quality) {
+        return Err("JPEG quality must be between 1 and 100".into());
+    }
+    let (width, height) = img.dimensions();
+    if width > u16::MAX as u32 || height > u16::MAX as u32 {
+        return Err(format!("Image too large for JPEG: {}x{}", width, height));
+    }
+
+    let mut bytes = Vec::new();
+    let mut encoder = jpeg_encoder::Encoder::new(&mut bytes, quality);
+    encoder.set_sampling_factor(jpeg_sampling(options.jpeg_subsampling.as_deref())?);
+    metadata::add_to_jpeg(&mut encoder, metadata)?;
+    encoder
+        .encode(&img.to_rgb8(), width as u16, height as u16, jpeg_encoder::ColorType::Rgb)
+        .map_err(|e| e.to_string())?;
+    Ok(bytes)
+}
+
+fn encode_png(img: &DynamicImage, options: &OutputOptions) -> Result<Vec<u8>, String> {
+    let compression = png_compression(options.png_compression.as_deref())?;
+    let mut bytes = Vec::new();
+    let encoder = PngEncoder::new_with_quality(&mut bytes, compression, FilterType::Sub);
+    let (width, height) = img.dimensions();
+    // write_image, unlike encode, swaps 16-bit samples to PNG's big endian
+    encoder
+        .write_image(img.as_bytes(), width, height, img.color())
+        .map_err(|e| e.to_string())?;
+    Ok(bytes)
+}
+
+fn encode_webp(img: &DynamicImage, options: &OutputOptions) -> Result<Vec<u8>, String> {
+    let (width, height) = img.dimensions();
+    let rgba = img.to_rgba8();
+    let encoder = webp::Encoder::from_rgba(&rgba, width, height);
+    let encoded = if options.webp_lossless.unwrap_or(false) {
+        encoder.encode_lossless()
+    } else {
+        let quality = options.webp_quality.unwrap_or(80.0);
+        if !(0.0..=100.0).contains(&quality) {
+            return Err("WebP quality must be between 0 and 100".into());
+        }
+        encoder.encode(quality)
+    };
+    Ok(encoded.to_vec())
+}
+
+/// Encodes `img` in memory as `format`, applying the encoder settings in
+/// `options` and embedding the parts of the input `metadata` they select.
+pub fn encode_image(img: &DynamicImage, format: ImageFormat, options: &OutputOptions, metadata: &Metadata) -> Result<Vec<u8>, String> {
+    let selected = select_metadata(metadata, options)?;
+    let (img, icc) = apply_profile(img, options, metadata, selected.icc.is_some())?;
+    let metadata = Metadata { icc, ..selected };
+    let img = prepare_for_format(&img, format, options)?;
+
+    let bytes = match format {
+        ImageFormat::Jpeg => encode_jpeg(&img, options, &metadata)?,
+        ImageFormat::Png => encode_png(&img, options)?,
+        ImageFormat::WebP => encode_webp(&img, options)?,
+        _ => {
+            let mut bytes = Vec::new();
+            img.write_to(&mut bytes, format).map_err(|e| e.to_string())?;
+            bytes
+        }
+    };
+    metadata::embed(bytes, format, &metadata, &img)
+}
+
+/// Encodes `img` to `path`, picking the format from the file extension and
+/// applying the encoder settings in `options`.
+pub fn save_image(img: &DynamicImage, path: &Path, options: &OutputOptions, metadata: &Metadata) -> Result<(), String> {
+    let format = ImageFormat::from_path(path).map_err(|e| e.to_string())?;
+    let bytes = encode_image(img, format, options, metadata)?;
+    fs::write(path, bytes).map_err(|e| e.to_string())
+}
+
+/// MIME type for the formats `encode_image` can write.
+pub fn mime_type(format: ImageFormat) -> &'static str {
+    match format {
+        ImageFormat::Jpeg => "image/jpeg",
+        ImageFormat::Png => "image/png",
+        ImageFormat::WebP => "image/webp",
+        ImageFormat::Gif => "image/gif",
+        ImageFormat::Bmp => "image/bmp",
+        ImageFormat::Tiff => "image/tiff",
+        _ => "application/octet-stream",
+    }
+}
@@ -0,0 +1,93 @@
+use std::borrow::Cow;
+
+use image::{DynamicImage, ImageBuffer, Pixel, Primitive, RgbaImage};
+use rayon::prelude::*;
+
+/// Sets the number of threads every effect runs on. Has to be called before
+/// the first effect runs; without it rayon uses one thread per CPU.
+pub fn set_threads(threads: usize) -> Result<(), String> {
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build_global()
+        .map_err(|e| format!("Could not configure the thread pool: {}", e))
+}
+
+/// Runs `f` on a pool of its own with `threads` threads, whatever the
+/// global setting, so results can be compared across thread counts.
+pub fn with_threads<R: Send>(threads: usize, f: impl FnOnce() -> R + Send) -> Result<R, String> {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .map_err(|e| format!("Could not build a thread pool: {}", e))?;
+    Ok(pool.install(f))
+}
+
+pub fn current_threads() -> usize {
+    rayon::current_num_threads()
+}
+
+/// Parallel version of `ImageBuffer::from_fn`: rows are computed on the
+/// thread pool, so `f` must only read shared state.
+pub fn from_fn<P, F>(width: u32, height: u32, f: F) -> ImageBuffer<P, Vec<P::Subpixel>>
+where
+    P: Pixel + Send + Sync + 'static,
+    P::Subpixel: Send + Sync + 'static,
+    F: Fn(u32, u32) -> P + Sync,
+{
+    let mut output = ImageBuffer::new(width, height);
+    for_each_row(&mut output, |y, row| {
+        for (x, pixel) in row.chunks_exact_mut(P::CHANNEL_COUNT as usize).enumerate() {
+            *P::from_slice_mut(pixel) = f(x as u32, y);
+        }
+    });
+    output
+}
+
+/// Runs `f` on every row of `img` in parallel, with the row's raw channels.
+pub fn for_each_row<P, F>(img: &mut ImageBuffer<P, Vec<P::Subpixel>>, f: F)
+where
+    P: Pixel + 'static,
+    P::Subpixel: Send + Sync + 'static,
+    F: Fn(u32, &mut [P::Subpixel]) + Sync,
+{
+    let row_len = img.width() as usize * P::CHANNEL_COUNT as usize;
+    if row_len == 0 {
+        return;
+    }
+    let data: &mut [P::Subpixel] = img;
+    data.par_chunks_mut(row_len)
+        .enumerate()
+        .for_each(|(y, row)| f(y as u32, row));
+}
+
+/// The canonical RGBA8 buffer effects work on. Borrowed when the image
+/// already is RGBA8, which is what the pipeline feeds them.
+pub fn rgba(img: &DynamicImage) -> Cow<'_, RgbaImage> {
+    match img {
+        DynamicImage::ImageRgba8(buffer) => Cow::Borrowed(buffer),
+        other => Cow::Owned(other.to_rgba8()),
+    }
+}
+
+/// Point operation: maps every pixel of `source` to a pixel of the output,
+/// walking both buffers as row slices in parallel.
+pub fn map_pixels<S, P, T, F>(source: &ImageBuffer<S, Vec<T>>, f: F) -> ImageBuffer<P, Vec<T>>
+where
+    S: Pixel<Subpixel = T> + Sync + 'static,
+    P: Pixel<Subpixel = T> + 'static,
+    T: Primitive + Send + Sync + 'static,
+    F: Fn(&S) -> P + Sync,
+{
+    let (width, height) = source.dimensions();
+    let source_channels = S::CHANNEL_COUNT as usize;
+    let source_row_len = width as usize * source_channels;
+    let mut output = ImageBuffer::new(width, height);
+    for_each_row(&mut output, |y, row| {
+        let start = y as usize * source_row_len;
+        let source_row = &source.as_raw()[start..start + source_row_len];
+        for (out, pixel) in row.chunks_exact_mut(P::CHANNEL_COUNT as usize).zip(source_row.chunks_exact(source_channels)) {
+            *P::from_slice_mut(out) = f(S::from_slice(pixel));
+        }
+    });
+    output
+}
@@ -0,0 +1,292 @@
+use std::time::Instant;
+
+use image::{imageops, DynamicImage, GenericImageView};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::alpha::{self, AlphaPolicy};
+use crate::colorspace::{self, ColorSpace};
+use crate::depth;
+use crate::effects::{self, Params};
+use crate::output::OutputOptions;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TransformConfig {
+    pub name: String,
+    #[serde(default)]
+    pub params: serde_json::Value,
+    /// Color space this step runs in; unset steps follow the pipeline's
+    /// `color_space` if the effect works on light, and sRGB otherwise.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color_space: Option<ColorSpace>,
+    /// Alpha handling of this step; unset steps follow the pipeline's
+    /// `alpha`, or the effect's own default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alpha: Option<AlphaPolicy>,
+}
+
+// Serialized with the pipeline-wide settings already resolved into the steps
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(from = "ConfigFile")]
+pub struct Config {
+    pub transformations: Vec<TransformConfig>,
+    pub output: OutputOptions,
+}
+
+// Config as written; the pipeline-wide color space is resolved into the steps
+#[derive(Deserialize)]
+struct ConfigFile {
+    transformations: Vec<TransformConfig>,
+    #[serde(default)]
+    output: OutputOptions,
+    #[serde(default)]
+    color_space: ColorSpace,
+    #[serde(default)]
+    alpha: Option<AlphaPolicy>,
+}
+
+impl From<ConfigFile> for Config {
+    fn from(file: ConfigFile) -> Self {
+        let mut transformations = file.transformations;
+        for transform in &mut transformations {
+            let linear_light = effects::find(&transform.name).is_some_and(|e| e.linear_light);
+            if transform.color_space.is_none() && linear_light {
+                transform.color_space = Some(file.color_space);
+            }
+            transform.alpha = transform.alpha.or(file.alpha);
+        }
+        Config { transformations, output: file.output }
+    }
+}
+
+/// Applies every transformation in order, logging the time each step takes.
+pub fn apply_pipeline(img: DynamicImage, transformations: &[TransformConfig]) -> Result<DynamicImage, Box<dyn std::error::Error>> {
+    run_steps(img, transformations, None)
+}
+
+/// Like `apply_pipeline`, but gives up once `deadline` has passed. Steps are
+/// not interrupted, so the check happens between steps.
+pub fn apply_pipeline_until(img: DynamicImage, transformations: &[TransformConfig], deadline: Instant) -> Result<DynamicImage, Box<dyn std::error::Error>> {
+    run_steps(img, transformations, Some(deadline))
+}
+
+fn run_steps(img: DynamicImage, transformations: &[TransformConfig], deadline: Option<Instant>) -> Result<DynamicImage, Box<dyn std::error::Error>> {
+    let mut processed_img = img;
+    for (i, transform) in transformations.iter().enumerate() {
+        if deadline.is_some_and(|d| Instant::now() >= d) {
+            return Err(format!("Timed out after {} of {} steps", i, transformations.len()).into());
+        }
+        debug!("Applying transformation {}/{}: {} with params: {:?}",
+               i + 1,
+               transformations.len(),
+               transform.name,
+               transform.params);
+        let start = Instant::now();
+        processed_img = apply_transformation(processed_img, transform)?;
+        let duration = start.elapsed();
+        info!(
+            "Applied {}/{}: {}. Time: {} ms. Params: {:?}",
+            i + 1,
+            transformations.len(),
+            transform.name,
+            duration.as_millis(),
+            transform.params
+        );
+    }
+    Ok(processed_img)
+}
+
+// Bytes per tile pixel: an RGBA crop plus about three intermediate buffers
+// allocated by the effects themselves
+const TILE_BYTES_PER_PIXEL: u64 = 16;
+const MIN_TILE_SIZE: u32 = 64;
+
+/// Like `apply_pipeline`, but runs consecutive steps that only look at a
+/// bounded neighborhood on overlapping tiles, so their intermediate images
+/// stay within about `memory_budget` bytes. The overlap is the sum of the
+/// steps' radii, which makes the result match full-frame processing. Steps
+/// that need the whole image still run on the full frame.
+pub fn apply_pipeline_tiled(img: DynamicImage, transformations: &[TransformConfig], memory_budget: u64) -> Result<DynamicImage, Box<dyn std::error::Error>> {
+    let mut processed_img = img;
+    let mut start = 0;
+    while start < transformations.len() {
+        let mut end = start;
+        let mut radius = 0;
+        while let Some(transform) = transformations.get(end) {
+            match step_radius(transform)? {
+                Some(r) => radius += r,
+                None => break,
+            }
+            end += 1;
+        }
+
+        if end == start {
+            debug!("{} needs the whole image", transformations[start].name);
+            processed_img = run_steps(processed_img, &transformations[start..=start], None)?;
+            start += 1;
+        } else {
+            processed_img = run_tiles(processed_img, &transformations[start..end], radius, memory_budget)?;
+            start = end;
+        }
+    }
+    Ok(processed_img)
+}
+
+fn step_radius(transform: &TransformConfig) -> Result<Option<u32>, Box<dyn std::error::Error>> {
+    match effects::find(&transform.name) {
+        Some(effect) => effect.radius(&Params::new(effect, &transform.params)),
+        // Unknown effects leave the image alone
+        None => Ok(Some(0)),
+    }
+}
+
+fn run_tiles(img: DynamicImage, transformations: &[TransformConfig], radius: u32, memory_budget: u64) -> Result<DynamicImage, Box<dyn std::error::Error>> {
+    let (width, height) = img.dimensions();
+    if width as u64 * height as u64 * TILE_BYTES_PER_PIXEL <= memory_budget {
+        return run_steps(img, transformations, None);
+    }
+
+    // Largest square tile whose padded crop fits in the budget
+    let padded = ((memory_budget / TILE_BYTES_PER_PIXEL) as f64).sqrt() as u32;
+    let mut tile_size = padded.saturating_sub(2 * radius);
+    if tile_size < MIN_TILE_SIZE {
+        warn!("Memory budget is too small for an overlap of {} px, using {}px tiles", radius, MIN_TILE_SIZE);
+        tile_size = MIN_TILE_SIZE;
+    }
+    let names: Vec<&str> = transformations.iter().map(|t| t.name.as_str()).collect();
+    info!("Applying {} in {}x{} tiles with {} px overlap", names.join(", "), tile_size, tile_size, radius);
+
+    let start = Instant::now();
+    // Stitch at the working depth of the input
+    let mut output = if depth::is_16bit(&img) {
+        DynamicImage::new_rgba16(width, height)
+    } else {
+        DynamicImage::new_rgba8(width, height)
+    };
+    for tile_y in (0..height).step_by(tile_size as usize) {
+        for tile_x in (0..width).step_by(tile_size as usize) {
+            let tile_width = tile_size.min(width - tile_x);
+            let tile_height = tile_size.min(height - tile_y);
+            // Pad the tile by the radius, but never past the image edges
+            let crop_x = tile_x.saturating_sub(radius);
+            let crop_y = tile_y.saturating_sub(radius);
+            let crop_width = (tile_x + tile_width + radius).min(width) - crop_x;
+            let crop_height = (tile_y + tile_height + radius).min(height) - crop_y;
+
+            let mut tile = img.crop_imm(crop_x, crop_y, crop_width, crop_height);
+            for transform in transformations {
+                tile = apply_transformation(tile, transform)?;
+            }
+            if tile.dimensions() != (crop_width, crop_height) {
+                return Err(format!("Tiled steps must keep the image size ({})", names.join(", ")).into());
+            }
+            let inner = tile.crop_imm(tile_x - crop_x, tile_y - crop_y, tile_width, tile_height);
+            match &mut output {
+                DynamicImage::ImageRgba16(output) => imageops::replace(output, &depth::to_rgba16(&inner), tile_x, tile_y),
+                DynamicImage::ImageRgba8(output) => imageops::replace(output, &inner.to_rgba8(), tile_x, tile_y),
+                _ => unreachable!("tiles are stitched into an RGBA buffer"),
+            }
+        }
+    }
+    info!("Applied {} tiled. Time: {} ms", names.join(", "), start.elapsed().as_millis());
+    Ok(output)
+}
+
+/// Keeps the output of every step of the last run so that re-running an
+/// edited pipeline only recomputes the steps from the first change onwards.
+#[derive(Default)]
+pub struct PipelineCache {
+    steps: Vec<(TransformConfig, DynamicImage)>,
+}
+
+impl PipelineCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Drops every cached step, e.g. after the input image changed.
+    pub fn clear(&mut self) {
+        self.steps.clear();
+    }
+
+    /// Runs the pipeline on `input`, reusing cached results for the longest
+    /// prefix of steps whose name and params are unchanged.
+    pub fn apply(&mut self, input: &DynamicImage, transformations: &[TransformConfig]) -> Result<DynamicImage, Box<dyn std::error::Error>> {
+        let reused = self.steps.iter()
+            .zip(transformations)
+            .take_while(|((cached, _), transform)| cached == *transform)
+            .count();
+        self.steps.truncate(reused);
+        if reused > 0 {
+            info!("Reusing {} cached step(s)", reused);
+        }
+
+        let mut processed_img = match self.steps.last() {
+            Some((_, img)) => img.clone(),
+            None => input.clone(),
+        };
+        for (i, transform) in transformations.iter().enumerate().skip(reused) {
+            let start = Instant::now();
+            processed_img = apply_transformation(processed_img, transform)?;
+            info!(
+                "Applied {}/{}: {}. Time: {} ms. Params: {:?}",
+                i + 1,
+                transformations.len(),
+                transform.name,
+                start.elapsed().as_millis(),
+                transform.params
+            );
+            self.steps.push((transform.clone(), processed_img.clone()));
+        }
+        Ok(processed_img)
+    }
+}
+
+/// Size of the image after each step, worked out from the params without
+/// running anything, so oversized outputs can be refused up front. Steps
+/// whose params do not parse are taken to keep the size; they fail when run.
+/// Stops after the first size that no image can have.
+pub fn planned_sizes(width: u32, height: u32, transformations: &[TransformConfig]) -> Vec<(u64, u64)> {
+    let mut sizes = Vec::new();
+    let (mut width, mut height) = (width, height);
+    for transform in transformations {
+        let Some(effect) = effects::find(&transform.name) else { continue };
+        let params = Params::new(effect, &transform.params);
+        let size = effects::output_size(effect, width, height, &params).unwrap_or((width as u64, height as u64));
+        sizes.push(size);
+        match (u32::try_from(size.0), u32::try_from(size.1)) {
+            (Ok(w), Ok(h)) => (width, height) = (w, h),
+            _ => break,
+        }
+    }
+    sizes
+}
+
+pub fn apply_transformation(img: DynamicImage, transform: &TransformConfig) -> Result<DynamicImage, Box<dyn std::error::Error>> {
+    match effects::find(&transform.name) {
+        Some(effect) => {
+            let params = Params::new(effect, &transform.params);
+            for unknown in params.unknown() {
+                warn!("Ignoring unknown param '{}' for {}", unknown, effect.name);
+            }
+            params.check_required()?;
+            let was_16bit = depth::is_16bit(&img);
+            let space = transform.color_space.unwrap_or_default();
+            let policy = transform.alpha.unwrap_or(effect.alpha);
+            let output = colorspace::apply_in(img, space, |img| {
+                alpha::apply_with(img, policy, |img| (effect.apply)(img, &params))
+            })?;
+            // Effects without a 16-bit path drop to 8 bits; keep the working
+            // depth so later steps and the encoder still see 16 bits
+            if was_16bit && !depth::is_16bit(&output) {
+                warn!("{} works in 8 bits, so the 16-bit image loses precision at this step", effect.name);
+                return Ok(DynamicImage::ImageRgba16(depth::to_rgba16(&output)));
+            }
+            Ok(output)
+        },
+        None => {
+            warn!("Invalid transformation specified: {}", transform.name);
+            Ok(img)
+        }
+    }
+}
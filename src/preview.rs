@@ -0,0 +1,66 @@
+use std::path::{Path, PathBuf};
+
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView};
+use log::info;
+use rand::Rng;
+use serde_json::Value;
+
+use crate::effects;
+use crate::pipeline::TransformConfig;
+
+/// Shrinks `img` so its longer side is at most `max_side`, returning the
+/// smaller image and the factor its sides were scaled by. Images that already
+/// fit are returned as they are, with a factor of 1.
+pub fn downscale(img: &DynamicImage, max_side: u32) -> (DynamicImage, f64) {
+    let (width, height) = img.dimensions();
+    let longest = width.max(height);
+    if longest <= max_side || max_side == 0 {
+        return (img.clone(), 1.0);
+    }
+    let factor = max_side as f64 / longest as f64;
+    let preview_width = ((width as f64 * factor).round() as u32).max(1);
+    let preview_height = ((height as f64 * factor).round() as u32).max(1);
+    info!("Previewing at {}x{} ({:.1}% of {}x{})", preview_width, preview_height, factor * 100.0, width, height);
+    (img.resize_exact(preview_width, preview_height, FilterType::Triangle), factor)
+}
+
+/// The pipeline with every pixel-sized param scaled by `factor`, so it gives
+/// the same look on an image scaled by it. Unknown effects are left as they
+/// are.
+pub fn scale_transformations(transformations: &[TransformConfig], factor: f64) -> Vec<TransformConfig> {
+    if factor == 1.0 {
+        return transformations.to_vec();
+    }
+    transformations.iter()
+        .map(|transform| match effects::find(&transform.name) {
+            Some(effect) => TransformConfig {
+                params: effect.scale_params(&transform.params, factor),
+                ..transform.clone()
+            },
+            None => transform.clone(),
+        })
+        .collect()
+}
+
+/// Gives every step that takes a `seed` but has none a random one, so the
+/// preview and the full-resolution render make the same random choices.
+pub fn pin_seeds(transformations: &mut [TransformConfig]) {
+    let mut rng = rand::thread_rng();
+    for transform in transformations {
+        let seeded = effects::find(&transform.name).is_some_and(|e| e.param("seed").is_some());
+        if !seeded || transform.params.get("seed").is_some_and(|s| !s.is_null()) {
+            continue;
+        }
+        if !transform.params.is_object() {
+            transform.params = Value::Object(Default::default());
+        }
+        transform.params["seed"] = Value::from(rng.gen::<u32>());
+    }
+}
+
+/// Where a preview records the full-resolution pipeline it was rendered
+/// from: `out.png` gets `out.preview.json`.
+pub fn pipeline_path(output: &Path) -> PathBuf {
+    output.with_extension("preview.json")
+}
@@ -0,0 +1,104 @@
+use std::io::Cursor;
+
+use image::{DynamicImage, GenericImageView, ImageFormat};
+use log::{error, info};
+use serde_json::json;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::effects;
+use crate::metadata::{self, Metadata};
+use crate::output::{self, OutputOptions};
+use crate::pipeline::{Config, PipelineCache};
+
+const INDEX_HTML: &str = include_str!("server/index.html");
+
+pub(crate) type HttpResponse = Response<Cursor<Vec<u8>>>;
+
+struct State {
+    input: Option<DynamicImage>,
+    cache: PipelineCache,
+}
+
+pub(crate) fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap()
+}
+
+pub(crate) fn text(status: u16, message: &str) -> HttpResponse {
+    Response::from_string(message)
+        .with_status_code(status)
+        .with_header(header("Content-Type", "text/plain; charset=utf-8"))
+}
+
+pub(crate) fn json_response(value: &serde_json::Value) -> HttpResponse {
+    Response::from_string(value.to_string())
+        .with_header(header("Content-Type", "application/json"))
+}
+
+fn read_body(request: &mut Request) -> Result<Vec<u8>, String> {
+    let mut body = Vec::new();
+    request.as_reader().read_to_end(&mut body).map_err(|e| e.to_string())?;
+    Ok(body)
+}
+
+// Decodes an uploaded image and makes it the input of every following render
+fn upload(state: &mut State, body: &[u8]) -> Result<HttpResponse, String> {
+    // Metadata is not kept, but the preview should be upright
+    let (img, _) = metadata::decode(body).map_err(|e| format!("Could not decode image: {}", e))?;
+    let (width, height) = img.dimensions();
+    info!("Loaded uploaded image ({}x{})", width, height);
+    state.input = Some(DynamicImage::ImageRgba8(img.to_rgba8()));
+    state.cache.clear();
+    Ok(json_response(&json!({ "width": width, "height": height })))
+}
+
+fn render(state: &mut State, body: &[u8]) -> Result<HttpResponse, String> {
+    let input = state.input.as_ref().ok_or("Upload an image first")?;
+    let config: Config = serde_json::from_slice(body).map_err(|e| format!("Invalid pipeline: {}", e))?;
+    let processed = state.cache.apply(input, &config.transformations).map_err(|e| e.to_string())?;
+    let png = output::encode_image(&processed, ImageFormat::Png, &OutputOptions::default(), &Metadata::default())?;
+    Ok(Response::from_data(png).with_header(header("Content-Type", "image/png")))
+}
+
+fn handle(state: &mut State, request: &mut Request) -> Result<HttpResponse, String> {
+    let path = request.url().split('?').next().unwrap_or("/").to_string();
+    match (request.method(), path.as_str()) {
+        (Method::Get, "/") => Ok(Response::from_string(INDEX_HTML)
+            .with_header(header("Content-Type", "text/html; charset=utf-8"))),
+        (Method::Get, "/api/effects") => {
+            let effects: Vec<_> = effects::EFFECTS.iter().map(|e| e.to_json()).collect();
+            Ok(json_response(&json!(effects)))
+        },
+        (Method::Post, "/api/image") => {
+            let body = read_body(request)?;
+            upload(state, &body)
+        },
+        (Method::Post, "/api/render") => {
+            let body = read_body(request)?;
+            render(state, &body)
+        },
+        _ => Ok(text(404, "Not found")),
+    }
+}
+
+/// Serves the browser preview UI on localhost until the process is stopped.
+/// Requests are handled one at a time against a single uploaded image.
+pub fn serve(port: u16) -> Result<(), Box<dyn std::error::Error>> {
+    let server = Server::http(("127.0.0.1", port)).map_err(|e| e.to_string())?;
+    info!("Preview UI running at http://127.0.0.1:{}/", port);
+    println!("Open http://127.0.0.1:{}/ in your browser (Ctrl+C to stop)", port);
+
+    let mut state = State {
+        input: None,
+        cache: PipelineCache::new(),
+    };
+    for mut request in server.incoming_requests() {
+        let response = handle(&mut state, &mut request).unwrap_or_else(|e| {
+            error!("{} {}: {}", request.method(), request.url(), e);
+            text(400, &e)
+        });
+        if let Err(e) = request.respond(response) {
+            error!("Failed to send response: {}", e);
+        }
+    }
+    Ok(())
+}
@@ -1,8 +1,19 @@
 use image::{DynamicImage, ImageBuffer, Rgba, GenericImageView};
+use img_corroder::alpha::AlphaPolicy;
 use img_corroder::colorfx;
+use img_corroder::colorfx::replace::{Metric, ReplaceMode};
+use img_corroder::colorspace::ColorSpace;
+use img_corroder::depth;
 use img_corroder::edgesfx;
+use img_corroder::effects;
 use img_corroder::glitchfx;
 use img_corroder::fx_json_generator;
+use img_corroder::icc::Profile;
+use img_corroder::metadata::{self, Metadata};
+use img_corroder::output::{self, OutputOptions};
+use img_corroder::parallel;
+use img_corroder::pipeline;
+use img_corroder::preview;
 
 fn create_test_image() -> DynamicImage {
     let width = 100;
@@ -55,11 +66,51 @@ fn test_sepia() {
 #[test]
 fn test_color_replacer() {
     let img = create_test_image();
-    // assuming our color map has keys "red" and "blue"
-    let processed = colorfx::color_replacer(&img, "red", "blue", 50).expect("Color replacer failed");
+    let processed = colorfx::replace::color_replacer(&img, "red", "blue", 20.0, 10.0, Metric::Ciede2000, ReplaceMode::Color)
+        .expect("Color replacer failed");
     assert_eq!(img.dimensions(), processed.dimensions());
 }
 
+#[test]
+fn test_perceptual_color_replacer() {
+    use img_corroder::colorfx::utils::delta_e_2000;
+
+    // Reference pairs from Sharma, Wu and Dalal's CIEDE2000 test data
+    assert!((delta_e_2000([50.0, 2.6772, -79.7751], [50.0, 0.0, -82.7485]) - 2.0425).abs() < 1e-3);
+    assert!((delta_e_2000([50.0, 2.5, 0.0], [73.0, 25.0, -18.0]) - 27.1492).abs() < 1e-3);
+    assert!((delta_e_2000([2.0776, 0.0795, -1.135], [0.9033, -0.0636, -0.5514]) - 0.9082).abs() < 1e-3);
+
+    // Red fading into white, with a translucent column
+    let ramp = DynamicImage::ImageRgba8(ImageBuffer::from_fn(64, 2, |x, y| {
+        let v = (x * 4) as u8;
+        Rgba([255, v, v, if y == 1 { 128 } else { 255 }])
+    }));
+    let replace = |mode| colorfx::replace::color_replacer(&ramp, "red", "blue", 10.0, 20.0, Metric::Ciede2000, mode).unwrap().to_rgba8();
+    let painted = replace(ReplaceMode::Color);
+    assert_eq!(painted.get_pixel(0, 0).0, [0, 0, 255, 255]);
+    assert_eq!(painted.get_pixel(0, 1)[3], 128);
+    assert_eq!(painted.get_pixel(63, 0), ramp.to_rgba8().get_pixel(63, 0));
+    // The soft edge hands over gradually instead of jumping back to red
+    let blues: Vec<i32> = (0..64).map(|x| painted.get_pixel(x, 0)[2] as i32 - ramp.to_rgba8().get_pixel(x, 0)[2] as i32).collect();
+    assert!(blues.windows(2).all(|pair| pair[1] <= pair[0] + 1), "{:?}", blues);
+    assert!(blues.iter().any(|&b| b > 10 && b < 200), "{:?}", blues);
+
+    // Hue mode keeps the shading: the ramp stays a ramp, only bluer
+    let hued = replace(ReplaceMode::Hue);
+    let lightness = |p: &Rgba<u8>| colorfx::utils::rgb_to_oklab(p[0] as f32, p[1] as f32, p[2] as f32)[0];
+    for x in 0..64 {
+        let (before, after) = (ramp.to_rgba8().get_pixel(x, 0).to_owned(), hued.get_pixel(x, 0).to_owned());
+        assert!((lightness(&before) - lightness(&after)).abs() < 0.03, "{:?} {:?}", before, after);
+    }
+    assert!(hued.get_pixel(0, 0)[2] > hued.get_pixel(0, 0)[0]);
+    assert_ne!(hued.get_pixel(0, 0), painted.get_pixel(0, 0));
+
+    let oklab = colorfx::replace::color_replacer(&ramp, "red", "blue", 10.0, 0.0, Metric::Oklab, ReplaceMode::Color).unwrap();
+    assert_eq!(oklab.to_rgba8().get_pixel(0, 0).0, [0, 0, 255, 255]);
+    assert!(colorfx::replace::color_replacer(&ramp, "red", "blue", -1.0, 0.0, Metric::Oklab, ReplaceMode::Color).is_err());
+    assert!(Metric::named("cie76").is_err() && ReplaceMode::named("shade").is_err());
+}
+
 #[test]
 fn test_vaporwave() {
     let img = create_test_image();
@@ -119,15 +170,16 @@ fn test_oil_painting() {
 #[test]
 fn test_glitch() {
     let img = create_test_image();
-    let processed = glitchfx::glitch(&img, 10, 5, "vertical", false);
+    let processed = glitchfx::glitch(&img, 10, 5, 19, "vertical", false, None);
     assert_eq!(img.dimensions(), processed.dimensions());
 }
 
 #[test]
 fn test_pixel_sort() {
     let img = create_test_image();
-    let processed = glitchfx::pixel_sort(&img, "horizontal", 150, 200, 100);
+    let processed = glitchfx::pixel_sort(&img, "row", 150, 200, 100).expect("Pixel sort failed");
     assert_eq!(img.dimensions(), processed.dimensions());
+    assert!(glitchfx::pixel_sort(&img, "horizontal", 150, 200, 100).is_err());
 }
 
 #[test]
@@ -156,6 +208,10 @@ fn test_scan_lines() {
     let img = create_test_image();
     let processed = glitchfx::scan_lines(&img, Some(2), Some(10), Some(0.0), Some(0.5)).expect("Scan lines failed");
     assert_eq!(img.dimensions(), processed.dimensions());
+    // A zero period would divide by zero
+    assert!(glitchfx::scan_lines(&img, Some(0), Some(0), Some(0.0), Some(0.5)).is_err());
+    let spacing = effects::find("scan_lines").unwrap().params.iter().find(|p| p.name == "line_spacing").unwrap();
+    assert_eq!(spacing.range.map(|(min, _)| min), Some(1.0));
 }
 
 #[test]
@@ -168,6 +224,730 @@ fn test_generate_random_pipeline() {
 #[test]
 fn test_dither() {
     let img = create_test_image();
-    let processed = img_corroder::colorfx::dither(&img, 4, None, None, None).expect("Dither filter failed");
+    let processed = img_corroder::colorfx::dither(&img, 4, colorfx::ThresholdMap::Bayer, None, None, None).expect("Dither filter failed");
     assert_eq!(img.dimensions(), processed.dimensions());
-} 
\ No newline at end of file
+} 
+
+#[test]
+fn test_save_jpeg_flattens_alpha() {
+    let mut img = create_test_image().to_rgba8();
+    img.put_pixel(0, 0, Rgba([255, 0, 0, 0]));
+    let options = OutputOptions {
+        jpeg_quality: Some(95),
+        jpeg_subsampling: Some("4:4:4".into()),
+        background: Some("#ffffff".into()),
+        ..Default::default()
+    };
+    let flattened = output::prepare_for_format(&DynamicImage::ImageRgba8(img.clone()), image::ImageFormat::Jpeg, &options).expect("Flatten failed");
+    assert_eq!(flattened.get_pixel(0, 0), Rgba([255, 255, 255, 255]));
+
+    let path = std::env::temp_dir().join("img_corroder_output_test.jpg");
+    output::save_image(&DynamicImage::ImageRgba8(img), &path, &options, &Metadata::default()).expect("Save JPEG failed");
+    assert_eq!(image::open(&path).expect("Reopen failed").dimensions(), (100, 100));
+}
+
+#[test]
+fn test_save_webp() {
+    let img = create_test_image();
+    let path = std::env::temp_dir().join("img_corroder_output_test.webp");
+    let options = OutputOptions { webp_lossless: Some(true), ..Default::default() };
+    output::save_image(&img, &path, &options, &Metadata::default()).expect("Save WebP failed");
+    assert!(std::fs::metadata(&path).expect("Missing WebP output").len() > 0);
+}
+
+#[test]
+fn test_pipeline_cache_matches_full_run() {
+    let img = create_test_image();
+    let config: pipeline::Config = serde_json::from_str(r#"{"transformations": [
+        {"name": "contrast", "params": {"factor": 1.5}},
+        {"name": "pixelate", "params": {"block_size": 4}}
+    ]}"#).unwrap();
+    let mut cache = pipeline::PipelineCache::new();
+    cache.apply(&img, &config.transformations).expect("Cached run failed");
+
+    let mut edited = config.transformations.clone();
+    edited[1].params = serde_json::json!({"block_size": 8});
+    let cached = cache.apply(&img, &edited).expect("Cached re-run failed");
+    let full = pipeline::apply_pipeline(img, &edited).expect("Full run failed");
+    assert_eq!(cached.to_rgba8().into_raw(), full.to_rgba8().into_raw());
+}
+
+#[test]
+fn test_every_effect_runs_with_defaults() {
+    let img = create_test_image();
+    for effect in effects::EFFECTS.iter().filter(|e| !e.has_required()) {
+        let params = effect.default_params();
+        let processed = (effect.apply)(img.clone(), &effects::Params::new(effect, &params))
+            .unwrap_or_else(|e| panic!("{} failed: {}", effect.name, e));
+        assert_eq!(img.dimensions(), processed.dimensions(), "{}", effect.name);
+    }
+}
+
+#[test]
+fn test_params_are_validated() {
+    let brightness = effects::find("brightness").unwrap();
+    let params = serde_json::json!({"factor": 5.0});
+    assert!((brightness.apply)(create_test_image(), &effects::Params::new(brightness, &params)).is_err());
+
+    let pixel_sort = effects::find("pixel_sort").unwrap();
+    let params = serde_json::json!({"direction": "diagonal"});
+    assert!((pixel_sort.apply)(create_test_image(), &effects::Params::new(pixel_sort, &params)).is_err());
+}
+
+#[test]
+fn test_pipeline_deadline() {
+    let config: pipeline::Config = serde_json::from_str(r#"{"transformations": [{"name": "grayscale"}]}"#).unwrap();
+    let past = std::time::Instant::now();
+    assert!(pipeline::apply_pipeline_until(create_test_image(), &config.transformations, past).is_err());
+}
+
+#[test]
+fn test_parse_multipart() {
+    let body = b"--XyZ\r\nContent-Disposition: form-data; name=\"pipeline\"\r\n\r\n{\"transformations\": []}\r\n--XyZ\r\nContent-Disposition: form-data; name=\"image\"; filename=\"a.png\"\r\nContent-Type: image/png\r\n\r\n\x89PNG\r\n\r\n--XyZ--\r\n";
+    let parts = img_corroder::api::parse_multipart("multipart/form-data; boundary=XyZ", body).expect("Parse failed");
+    assert_eq!(parts.len(), 2);
+    assert_eq!(parts[0].name, "pipeline");
+    assert_eq!(parts[0].data, b"{\"transformations\": []}");
+    assert_eq!(parts[1].name, "image");
+    assert_eq!(parts[1].data, b"\x89PNG\r\n");
+}
+
+#[test]
+fn test_geometry() {
+    let img = create_test_image();
+    let rotated = img_corroder::geometryfx::rotate(&img, 45.0, "bicubic", true, Rgba([0, 0, 0, 0])).expect("Rotate failed");
+    assert_eq!(rotated.dimensions(), (141, 141));
+
+    let config: pipeline::Config = serde_json::from_str(r#"{"transformations": [
+        {"name": "crop", "params": {"width": "50%", "height": 40}},
+        {"name": "resize", "params": {"width": 100}},
+        {"name": "pad", "params": {"top": 10, "left": "5%"}}
+    ]}"#).unwrap();
+    let sizes = pipeline::planned_sizes(100, 100, &config.transformations);
+    assert_eq!(sizes, vec![(50, 40), (100, 80), (105, 90)]);
+    let processed = pipeline::apply_pipeline(img, &config.transformations).expect("Pipeline failed");
+    assert_eq!(processed.dimensions(), (105, 90));
+    let rotate: Vec<pipeline::TransformConfig> = serde_json::from_str(r#"[{"name": "rotate", "params": {"angle": 45}}]"#).unwrap();
+    assert_eq!(pipeline::planned_sizes(100, 100, &rotate), vec![(141, 141)]);
+
+    // Oversized canvases are refused before anything is allocated
+    let small = create_test_image();
+    assert!(img_corroder::geometryfx::pad(&small, 0, u32::MAX, 0, u32::MAX, Rgba([0, 0, 0, 0])).is_err());
+    assert!(img_corroder::geometryfx::pad(&small, 20_000, 20_000, 0, 0, Rgba([0, 0, 0, 0])).is_err());
+    assert!(img_corroder::geometryfx::resize(&small, Some(u32::MAX), None, "nearest").is_err());
+    assert!(img_corroder::geometryfx::rotate(&DynamicImage::new_rgba8(1, 40_000), 45.0, "nearest", true, Rgba([0, 0, 0, 0])).is_err());
+}
+
+#[test]
+fn test_tiled_pipeline_matches_full_frame() {
+    let img = create_test_image();
+    let config: pipeline::Config = serde_json::from_str(r#"{"transformations": [
+        {"name": "contrast", "params": {"factor": 1.5}},
+        {"name": "blur", "params": {"sigma": 1.5}},
+        {"name": "oil_painting", "params": {"radius": 2}},
+        {"name": "sketch"},
+        {"name": "flip"},
+        {"name": "wind", "params": {"strength": 5}},
+        {"name": "quantized_edge"}
+    ]}"#).unwrap();
+    let full = pipeline::apply_pipeline(img.clone(), &config.transformations).expect("Full run failed");
+    // A tiny budget forces 64px tiles on the 100x100 image
+    let tiled = pipeline::apply_pipeline_tiled(img, &config.transformations, 1).expect("Tiled run failed");
+    assert_eq!(full.to_rgba8().into_raw(), tiled.to_rgba8().into_raw());
+}
+
+#[test]
+fn test_parallel_from_fn_matches_serial() {
+    let pixel = |x: u32, y: u32| Rgba([(x * 3) as u8, (y * 5) as u8, (x ^ y) as u8, 255]);
+    let serial = ImageBuffer::from_fn(37, 23, pixel);
+    let parallel = parallel::from_fn(37, 23, pixel);
+    assert_eq!(serial, parallel);
+}
+
+#[test]
+fn test_effects_match_across_thread_counts() {
+    // Effects that draw fresh random numbers on every run
+    const RANDOM: &[&str] = &["add_noise", "deepfry"];
+    let img = create_test_image();
+    for effect in effects::EFFECTS.iter().filter(|e| !e.has_required() && !RANDOM.contains(&e.name)) {
+        let mut params = effect.default_params();
+        if effect.param("seed").is_some() {
+            params["seed"] = serde_json::json!(7);
+        }
+        let run = |threads| parallel::with_threads(threads, || {
+            (effect.apply)(img.clone(), &effects::Params::new(effect, &params)).map(|img| img.to_rgba8()).map_err(|e| e.to_string())
+        }).unwrap();
+        assert_eq!(run(1), run(4), "{} depends on the thread count", effect.name);
+    }
+}
+
+#[test]
+fn test_16bit_round_trip() {
+    let img = DynamicImage::ImageRgba16(ImageBuffer::from_fn(40, 30, |x, y| Rgba([x as u16 * 1000, y as u16 * 2000, 12345, 65535])));
+    let processed = colorfx::contrast(&img, 1.0);
+    // Values between the 8-bit steps survive a neutral contrast
+    assert_eq!(processed.as_rgba16(), img.as_rgba16());
+
+    let path = std::env::temp_dir().join("img_corroder_16bit_test.png");
+    output::save_image(&processed, &path, &OutputOptions::default(), &Metadata::default()).expect("Save failed");
+    let reloaded = image::open(&path).expect("Reload failed");
+    std::fs::remove_file(&path).ok();
+    assert_eq!(reloaded.as_rgba16(), img.as_rgba16());
+
+    // The registry says which effects keep 16 bits, as describe shows
+    for effect in effects::EFFECTS.iter().filter(|e| !e.has_required()) {
+        let processed = (effect.apply)(img.clone(), &effects::Params::new(effect, &effect.default_params())).unwrap();
+        assert_eq!(depth::is_16bit(&processed), effect.full_depth, "{} has the wrong full_depth", effect.name);
+    }
+    // Ported glitch effects give the same picture at both depths, up to the
+    // 16-bit rounding where 8 bits truncate
+    let eight = DynamicImage::ImageRgba8(depth::to_rgba8(&img));
+    let wide = DynamicImage::ImageRgba16(depth::to_rgba16(&eight));
+    for processed in [
+        |img: &DynamicImage| glitchfx::desync(img, 7, -3).unwrap(),
+        |img: &DynamicImage| glitchfx::pixelate(img, 6),
+        |img: &DynamicImage| glitchfx::scan_lines(img, Some(2), Some(3), Some(0.3), Some(0.5)).unwrap(),
+    ] {
+        let (wide, eight) = (depth::to_rgba8(&processed(&wide)), processed(&eight).to_rgba8());
+        assert!(wide.as_raw().iter().zip(eight.as_raw()).all(|(a, b)| a.abs_diff(*b) <= 1));
+    }
+}
+
+#[test]
+fn test_linear_color_space() {
+    let config: pipeline::Config = serde_json::from_str(r#"{"color_space": "linear", "transformations": [
+        {"name": "grayscale"},
+        {"name": "blur", "params": {"sigma": 3.0}},
+        {"name": "blur", "params": {"sigma": 3.0}, "color_space": "srgb"}
+    ]}"#).unwrap();
+    assert_eq!(config.transformations[0].color_space, None);
+    assert_eq!(config.transformations[1].color_space, Some(ColorSpace::Linear));
+    assert_eq!(config.transformations[2].color_space, Some(ColorSpace::Srgb));
+
+    // Blurring a black/white edge in linear light keeps the edge brighter
+    let edge = DynamicImage::ImageRgba8(ImageBuffer::from_fn(40, 10, |x, _| if x < 20 { Rgba([0, 0, 0, 255]) } else { Rgba([255, 255, 255, 255]) }));
+    let linear = pipeline::apply_pipeline(edge.clone(), &config.transformations[1..2]).expect("Linear blur failed");
+    let srgb = pipeline::apply_pipeline(edge, &config.transformations[2..]).expect("sRGB blur failed");
+    assert_eq!(linear.color(), srgb.color());
+    assert!(linear.to_rgba8().get_pixel(20, 5)[0] > srgb.to_rgba8().get_pixel(20, 5)[0] + 20);
+}
+
+// Little-endian EXIF with orientation 6 and a GPS IFD holding one latitude
+fn exif_with_gps() -> Vec<u8> {
+    let mut exif = b"II*\0\x08\0\0\0".to_vec();
+    exif.extend([2, 0]);
+    exif.extend([0x12, 0x01, 3, 0, 1, 0, 0, 0, 6, 0, 0, 0]);
+    exif.extend([0x25, 0x88, 4, 0, 1, 0, 0, 0, 38, 0, 0, 0]);
+    exif.extend([0, 0, 0, 0]);
+    exif.extend([1, 0]);
+    exif.extend([0x02, 0x00, 5, 0, 3, 0, 0, 0, 56, 0, 0, 0]);
+    exif.extend([0, 0, 0, 0]);
+    exif.extend([0xAB; 24]);
+    exif
+}
+
+#[test]
+fn test_exif_orientation_and_metadata() {
+    let img = DynamicImage::ImageRgba8(ImageBuffer::from_pixel(20, 10, Rgba([200, 100, 50, 255])));
+    let input = Metadata { exif: Some(exif_with_gps()), icc: Some(vec![7; 300]), xmp: Some(b"<x:xmpmeta/>".to_vec()) };
+    let keep_all = OutputOptions { keep_gps: Some(true), ..Default::default() };
+    let jpeg = output::encode_image(&img, image::ImageFormat::Jpeg, &keep_all, &input).expect("Encode failed");
+    assert_eq!(metadata::read(&jpeg), input);
+
+    // Decoding turns the image upright and resets the orientation
+    let (upright, decoded) = metadata::decode(&jpeg).expect("Decode failed");
+    assert_eq!(upright.dimensions(), (10, 20));
+    assert_eq!(metadata::read_orientation(&jpeg), 6);
+
+    // GPS values are wiped by default
+    let png = output::encode_image(&upright, image::ImageFormat::Png, &OutputOptions::default(), &decoded).expect("Encode failed");
+    let kept = metadata::read(&png);
+    assert_eq!(metadata::read_orientation(&png), 1);
+    assert!(!kept.exif.unwrap().windows(4).any(|w| w == [0xAB; 4]));
+    assert_eq!((kept.icc, kept.xmp), (input.icc, input.xmp));
+
+    let only_xmp = OutputOptions { metadata: Some("xmp".into()), ..Default::default() };
+    let webp = output::encode_image(&upright, image::ImageFormat::WebP, &only_xmp, &decoded).expect("Encode failed");
+    assert_eq!(metadata::read(&webp), Metadata { xmp: decoded.xmp, ..Default::default() });
+    assert_eq!(image::load_from_memory(&webp).expect("WebP decode failed").dimensions(), (10, 20));
+
+    // EXIF cut off right after the orientation entry's count is ignored, not a panic
+    let mut exif = exif_with_gps()[..18].to_vec();
+    exif[8] = 1;
+    let truncated = Metadata { exif: Some(exif), ..Default::default() };
+    let keep_gps = OutputOptions { keep_gps: Some(true), ..Default::default() };
+    let png = output::encode_image(&img, image::ImageFormat::Png, &keep_gps, &truncated).expect("Encode failed");
+    assert_eq!(metadata::read(&png).exif.unwrap().len(), 18);
+    let (decoded, _) = metadata::decode(&png).expect("Decode failed");
+    assert_eq!(decoded.dimensions(), (20, 10));
+}
+
+#[test]
+fn test_icc_profiles() {
+    let p3 = Profile::display_p3();
+    let parsed = Profile::parse(&p3.to_icc()).expect("Parse failed");
+    assert_eq!(parsed.name, "Display P3");
+    assert!(parsed.same_as(&p3));
+
+    // sRGB red written as Display P3, then read back into the sRGB working space
+    let red = DynamicImage::ImageRgba8(ImageBuffer::from_pixel(4, 4, Rgba([255, 0, 0, 255])));
+    let options = OutputOptions { profile: Some("display-p3".into()), ..Default::default() };
+    let png = output::encode_image(&red, image::ImageFormat::Png, &options, &Metadata::default()).expect("Encode failed");
+    let stored = image::load_from_memory(&png).expect("Decode failed").to_rgba8();
+    assert_eq!(stored.get_pixel(0, 0).0, [234, 51, 35, 255]);
+
+    let (working, metadata) = metadata::decode(&png).expect("Decode failed");
+    let pixel = working.to_rgba8().get_pixel(0, 0).0;
+    assert!(pixel[0] >= 254 && pixel[1] <= 1 && pixel[2] <= 1, "{:?}", pixel);
+    assert!(Profile::parse(&metadata.icc.unwrap()).expect("Embedded profile unreadable").same_as(&p3));
+
+    // A tag count far beyond the profile's size is an error, not an allocation
+    let mut huge = vec![0u8; 132];
+    huge[16..20].copy_from_slice(b"RGB ");
+    huge[36..40].copy_from_slice(b"acsp");
+    huge[128..132].copy_from_slice(&[0xFF; 4]);
+    assert!(Profile::parse(&huge).is_err());
+}
+
+#[test]
+fn test_transparency_survives_every_effect() {
+    // Transparent left half, translucent gradient on the right, read back from PNG
+    let img = ImageBuffer::from_fn(64, 64, |x, y| Rgba([x as u8 * 4, y as u8 * 4, 200, if x < 32 { 0 } else { y as u8 * 4 }]));
+    let png = output::encode_image(&DynamicImage::ImageRgba8(img), image::ImageFormat::Png, &OutputOptions::default(), &Metadata::default()).expect("Encode failed");
+    let (input, _) = metadata::decode(&png).expect("Decode failed");
+    let input_alpha: Vec<u8> = input.to_rgba8().pixels().map(|p| p[3]).collect();
+
+    for effect in effects::EFFECTS.iter().filter(|e| !e.has_required()) {
+        let config: pipeline::Config = serde_json::from_value(serde_json::json!({"transformations": [{"name": effect.name}]})).unwrap();
+        let output = pipeline::apply_pipeline(input.clone(), &config.transformations).expect(effect.name);
+        assert!(output.color().has_alpha(), "{} dropped alpha", effect.name);
+        let alpha: Vec<u8> = output.to_rgba8().pixels().map(|p| p[3]).collect();
+        assert!(alpha.contains(&0), "{} lost the transparent pixels", effect.name);
+        if effect.alpha == AlphaPolicy::Preserve {
+            assert_eq!(alpha, input_alpha, "{} changed alpha", effect.name);
+        }
+    }
+
+    let config: pipeline::Config = serde_json::from_str(r#"{"alpha": "process", "transformations": [
+        {"name": "sepia"}, {"name": "blur", "alpha": "premultiply"}
+    ]}"#).unwrap();
+    assert_eq!(config.transformations[0].alpha, Some(AlphaPolicy::Process));
+    assert_eq!(config.transformations[1].alpha, Some(AlphaPolicy::Premultiply));
+
+    // The color functions keep alpha themselves, without the pipeline's help
+    let library_outputs = [
+        colorfx::vaporwave(&input).unwrap(),
+        colorfx::hue_rotate(&input, 90.0).unwrap(),
+        glitchfx::oil_painting(&input, 2, 20),
+    ];
+    for output in library_outputs {
+        assert_eq!(output.to_rgba8().pixels().map(|p| p[3]).collect::<Vec<u8>>(), input_alpha);
+    }
+}
+
+#[test]
+fn test_preview_scales_pixel_params() {
+    let (small, factor) = preview::downscale(&create_test_image(), 25);
+    assert_eq!(small.dimensions(), (25, 25));
+    assert_eq!(factor, 0.25);
+
+    let mut config: pipeline::Config = serde_json::from_str(r#"{"transformations": [
+        {"name": "pixelate", "params": {"block_size": 10}},
+        {"name": "blur"},
+        {"name": "crop", "params": {"width": "50%", "height": 40}},
+        {"name": "scan_lines", "params": {"line_thickness": 1, "opacity": 0.8}},
+        {"name": "glitch", "params": {"amount": 5}}
+    ], "color_space": "linear"}"#).unwrap();
+    preview::pin_seeds(&mut config.transformations);
+    let seed = config.transformations[4].params["seed"].clone();
+    assert!(seed.is_u64());
+
+    let scaled = preview::scale_transformations(&config.transformations, factor);
+    assert_eq!(scaled[0].params["block_size"], 3);
+    assert_eq!(scaled[1].params["sigma"], 0.5);
+    assert_eq!(scaled[2].params["width"], "50%");
+    assert_eq!(scaled[2].params["height"], 10);
+    // Set sizes never vanish, and other params are untouched
+    assert_eq!(scaled[3].params["line_thickness"], 1);
+    assert_eq!(scaled[3].params["opacity"], 0.8);
+    assert_eq!(scaled[4].params["amount"], 5);
+    assert_eq!(scaled[4].params["seed"], seed);
+    pipeline::apply_pipeline(small, &scaled).expect("Preview failed");
+
+    // The recorded pipeline reads back as the same full-resolution steps
+    let recorded: pipeline::Config = serde_json::from_str(&serde_json::to_string(&config).unwrap()).unwrap();
+    assert_eq!(recorded.transformations, config.transformations);
+
+    // A seeded glitch picks the same bands every time
+    let img = create_test_image();
+    let first = glitchfx::glitch(&img, 10, 5, 19, "horizontal", false, Some(7));
+    let second = glitchfx::glitch(&img, 10, 5, 19, "horizontal", false, Some(7));
+    assert_eq!(first.to_rgba8().into_raw(), second.to_rgba8().into_raw());
+}
+
+#[test]
+fn test_error_diffusion_dither() {
+    let img = create_test_image();
+    let mean = |img: &DynamicImage| {
+        let rgba = img.to_rgba8();
+        rgba.pixels().map(|p| p[0] as f64 + p[1] as f64 + p[2] as f64).sum::<f64>() / (rgba.len() / 4 * 3) as f64
+    };
+    for method in colorfx::DIFFUSION_METHODS {
+        let processed = colorfx::error_diffusion(&img, 2, method, true).expect("Error diffusion failed");
+        let rgba = processed.to_rgba8();
+        assert!(rgba.pixels().all(|p| [p[0], p[1], p[2]].iter().all(|&v| v == 0 || v == 255)), "{}", method);
+        // Diffusion keeps the average tone; Atkinson drops a quarter of the error
+        let tolerance = if *method == "atkinson" { 12.0 } else { 3.0 };
+        assert!((mean(&processed) - mean(&img)).abs() < tolerance, "{}: {} vs {}", method, mean(&processed), mean(&img));
+    }
+    assert!(colorfx::error_diffusion(&img, 2, "random", true).is_err());
+
+    let dither = effects::find("dither").unwrap();
+    let params = serde_json::json!({"method": "stucki", "mode": "luminance", "levels": 3});
+    let processed = (dither.apply)(img.clone(), &effects::Params::new(dither, &params)).expect("Luminance dither failed");
+    assert!(processed.to_rgba8().pixels().all(|p| p[0] == p[1] && p[1] == p[2] && [0, 128, 255].contains(&p[0])));
+    let params = serde_json::json!({"method": "floyd-steinberg", "serpentine": false});
+    let raster = (dither.apply)(img.clone(), &effects::Params::new(dither, &params)).expect("Raster dither failed");
+    assert_ne!(raster.to_rgba8().into_raw(), colorfx::error_diffusion(&img, 4, "floyd-steinberg", true).unwrap().to_rgba8().into_raw());
+}
+
+#[test]
+fn test_ordered_dither_threshold_maps() {
+    let gray = DynamicImage::ImageRgba8(ImageBuffer::from_pixel(64, 64, Rgba([128, 128, 128, 255])));
+    let share_on = |img: &DynamicImage| {
+        img.to_rgba8().pixels().filter(|p| p[0] == 255).count() as f64 / (64.0 * 64.0)
+    };
+    let tile = image::GrayImage::from_fn(4, 2, |x, y| image::Luma([(x * 64 + y * 32) as u8]));
+    let maps = [
+        ("bayer", colorfx::ThresholdMap::Bayer, 4),
+        ("blue-noise", colorfx::ThresholdMap::BlueNoise, 16),
+        ("clustered-dot", colorfx::ThresholdMap::ClusteredDot, 8),
+        ("lines", colorfx::ThresholdMap::Lines, 8),
+        ("custom", colorfx::ThresholdMap::Image(&tile), 0),
+    ];
+    for (name, map, size) in maps {
+        let processed = colorfx::dither(&gray, 2, map, Some(size), Some(2), None).expect("Dither failed");
+        // Every map spreads its thresholds evenly, so mid-gray is half on
+        assert!((share_on(&processed) - 0.5).abs() < 0.02, "{}: {}", name, share_on(&processed));
+    }
+
+    // Lines switch whole rows on and off
+    let lines = colorfx::dither(&gray, 2, colorfx::ThresholdMap::Lines, Some(4), None, None).unwrap().to_rgba8();
+    assert!(lines.rows().all(|row| row.clone().all(|p| p[0] == row.clone().next().unwrap()[0])));
+
+    let dither = effects::find("dither").unwrap();
+    let params = serde_json::json!({"method": "custom"});
+    assert!((dither.apply)(gray.clone(), &effects::Params::new(dither, &params)).is_err());
+    let params = serde_json::json!({"method": "blue-noise", "levels": 2, "threshold_bias": 0.5});
+    let biased = (dither.apply)(gray, &effects::Params::new(dither, &params)).expect("Blue noise failed");
+    assert!(share_on(&biased) > 0.9);
+
+    // Generated maps are capped, so a size cannot overflow or run for minutes
+    for map in [colorfx::ThresholdMap::Bayer, colorfx::ThresholdMap::BlueNoise, colorfx::ThresholdMap::ClusteredDot, colorfx::ThresholdMap::Lines] {
+        assert!(colorfx::threshold_matrix(map, Some(70_000)).is_err());
+    }
+    assert!(colorfx::threshold_matrix(colorfx::ThresholdMap::BlueNoise, Some(256)).is_err());
+}
+
+#[test]
+fn test_palette_quantization() {
+    use img_corroder::colorfx::palette::{self, Palette, PaletteDither};
+
+    let img = create_test_image();
+    let palette_effect = effects::find("palette").unwrap();
+    let colors_of = |img: &DynamicImage| {
+        let mut colors: Vec<[u8; 3]> = img.to_rgba8().pixels().map(|p| [p[0], p[1], p[2]]).collect();
+        colors.sort();
+        colors.dedup();
+        colors
+    };
+
+    let gameboy = Palette::builtin("gameboy").unwrap();
+    let allowed: Vec<[u8; 3]> = gameboy.colors().iter().map(|c| c.0).collect();
+    for dither in ["none", "bayer", "floyd-steinberg"] {
+        let params = serde_json::json!({"palette": "gameboy", "dither": dither});
+        let processed = (palette_effect.apply)(img.clone(), &effects::Params::new(palette_effect, &params)).expect("Palette failed");
+        assert!(colors_of(&processed).iter().all(|c| allowed.contains(c)), "{}", dither);
+    }
+
+    let params = serde_json::json!({"colors": ["#000000", "#ff0000", "#ffffff"]});
+    let processed = (palette_effect.apply)(img.clone(), &effects::Params::new(palette_effect, &params)).expect("Custom palette failed");
+    assert!(colors_of(&processed).iter().all(|c| [[0, 0, 0], [255, 0, 0], [255, 255, 255]].contains(c)));
+    let params = serde_json::json!({"colors": "#000000, nope"});
+    assert!((palette_effect.apply)(img.clone(), &effects::Params::new(palette_effect, &params)).is_err());
+
+    // Dark navy is closest to black in RGB, but keeps its hue in Lab
+    let palette = Palette::new(vec![image::Rgb([0, 0, 0]), image::Rgb([255, 255, 255]), image::Rgb([0, 0, 255])]).unwrap();
+    assert_eq!(palette.nearest([0.0, 0.0, 120.0]), 2);
+
+    // A four-color reference gives back its own colors
+    let quadrants = DynamicImage::ImageRgba8(ImageBuffer::from_fn(32, 32, |x, y| match (x < 16, y < 16) {
+        (true, true) => Rgba([200, 30, 30, 255]),
+        (false, true) => Rgba([30, 200, 30, 255]),
+        (true, false) => Rgba([30, 30, 200, 255]),
+        (false, false) => Rgba([240, 240, 240, 255]),
+    }));
+    for method in ["median-cut", "k-means"] {
+        let extracted = palette::extract(&quadrants, 4, method).expect("Extraction failed");
+        let mut colors: Vec<[u8; 3]> = extracted.colors().iter().map(|c| c.0).collect();
+        colors.sort();
+        assert_eq!(colors, vec![[30, 30, 200], [30, 200, 30], [200, 30, 30], [240, 240, 240]], "{}", method);
+    }
+
+    // Diffused onto black and white, a mid-gray comes out about half white
+    let gray = DynamicImage::ImageRgba8(ImageBuffer::from_pixel(64, 64, Rgba([119, 119, 119, 255])));
+    let monochrome = Palette::builtin("monochrome").unwrap();
+    let dither = PaletteDither::Diffusion { kernel: colorfx::DiffusionKernel::named("floyd-steinberg").unwrap(), serpentine: true };
+    let processed = palette::quantize(&gray, &monochrome, dither, 1.0).unwrap();
+    let white = processed.to_rgba8().pixels().filter(|p| p[0] == 255).count() as f64 / (64.0 * 64.0);
+    assert!((white - 119.0 / 255.0).abs() < 0.03, "{}", white);
+}
+
+#[test]
+fn test_halftone() {
+    let halftone = effects::find("halftone").unwrap();
+    let run = |color: [u8; 3], params: serde_json::Value| {
+        let flat = DynamicImage::ImageRgba8(ImageBuffer::from_pixel(96, 96, Rgba([color[0], color[1], color[2], 255])));
+        let processed = (halftone.apply)(flat, &effects::Params::new(halftone, &params)).expect("Halftone failed");
+        let pixels: Vec<[u8; 4]> = processed.to_rgba8().pixels().map(|p| p.0).collect();
+        let mean = |c: usize| pixels.iter().map(|p| p[c] as f32).sum::<f32>() / pixels.len() as f32;
+        (pixels.clone(), [mean(0), mean(1), mean(2)])
+    };
+
+    // Dots keep the tone on average, but are made of ink and paper
+    let (pixels, mean) = run([128, 128, 128], serde_json::json!({"mode": "monochrome", "cell_size": 8}));
+    assert!((mean[0] - 128.0).abs() < 10.0, "{:?}", mean);
+    assert!(pixels.iter().all(|p| p[0] == p[1] && p[1] == p[2]));
+    assert!(pixels.iter().filter(|p| p[0] < 32).count() > pixels.len() / 4);
+    assert!(pixels.iter().filter(|p| p[0] > 224).count() > pixels.len() / 4);
+
+    for shape in ["round", "ellipse", "line"] {
+        let (_, mean) = run([200, 60, 60], serde_json::json!({"cell_size": 6, "shape": shape}));
+        assert!(mean[0] > mean[1] + 80.0 && (mean[1] - mean[2]).abs() < 15.0, "{} {:?}", shape, mean);
+    }
+
+    let (pixels, _) = run([255, 255, 255], serde_json::json!({}));
+    assert!(pixels.iter().all(|p| p == &[255, 255, 255, 255]));
+    let (pixels, _) = run([0, 0, 0], serde_json::json!({"black_generation": 1.0}));
+    assert!(pixels.iter().all(|p| p == &[0, 0, 0, 255]));
+
+    let params = serde_json::json!({"shape": "star"});
+    assert!((halftone.apply)(create_test_image(), &effects::Params::new(halftone, &params)).is_err());
+}
+
+#[test]
+fn test_analyze_palette() {
+    use img_corroder::analyze;
+    use img_corroder::colorfx::palette;
+
+    // Three quarters red, one quarter white
+    let img = DynamicImage::ImageRgba8(ImageBuffer::from_fn(40, 40, |x, y| {
+        if x < 20 && y < 20 { Rgba([255, 255, 255, 255]) } else { Rgba([200, 20, 20, 255]) }
+    }));
+    let dominant = palette::dominant_colors(&img, 2).expect("Palette extraction failed");
+    assert_eq!(dominant.len(), 2);
+    assert_eq!(dominant[0].0, image::Rgb([200, 20, 20]));
+    assert!((dominant[0].1 - 0.75).abs() < 0.01);
+    assert_eq!(dominant[1].0, image::Rgb([255, 255, 255]));
+
+    let report = analyze::palette_json(&dominant);
+    assert_eq!(report["colors"][0]["hex"], "#c81414");
+    assert_eq!(report["colors"][1]["lab"][0], 100.0);
+
+    let gpl = analyze::to_gpl(&dominant, "test");
+    assert!(gpl.starts_with("GIMP Palette\nName: test\n"));
+    assert!(gpl.contains("200  20  20\t#c81414 (75.0%)"));
+
+    let ase = analyze::to_ase(&dominant);
+    assert_eq!(&ase[..4], b"ASEF");
+    assert_eq!(u32::from_be_bytes(ase[8..12].try_into().unwrap()), 2);
+    // Header, then per color: type, length, and a 36 byte block for a 7 character name
+    assert_eq!(ase.len(), 12 + 2 * (2 + 4 + 36));
+
+    assert_eq!(analyze::swatch(&dominant, 8).dimensions(), (16, 8));
+}
+
+#[test]
+fn test_curves_and_levels() {
+    use img_corroder::colorfx::tone::Curve;
+
+    let img = create_test_image();
+    let run = |name: &str, params: serde_json::Value| {
+        let effect = effects::find(name).unwrap();
+        (effect.apply)(img.clone(), &effects::Params::new(effect, &params))
+    };
+
+    // Without points, and with the default levels, nothing changes
+    assert_eq!(run("curves", serde_json::json!({})).unwrap().to_rgba8(), img.to_rgba8());
+    assert_eq!(run("levels", serde_json::json!({})).unwrap().to_rgba8(), img.to_rgba8());
+
+    // The spline passes through its points and never overshoots between them
+    let curve = Curve::new(&[(0.0, 0.0), (64.0, 20.0), (128.0, 200.0), (255.0, 255.0)]).unwrap();
+    assert!((curve.eval(64.0) - 20.0).abs() < 1e-3 && (curve.eval(128.0) - 200.0).abs() < 1e-3);
+    let values: Vec<f32> = (0..=255).map(|x| curve.eval(x as f32)).collect();
+    assert!(values.windows(2).all(|pair| pair[1] >= pair[0]));
+    assert!(values.iter().all(|&v| (0.0..=255.0).contains(&v)));
+    assert!(Curve::new(&[(10.0, 0.0), (10.0, 255.0)]).is_err());
+
+    // A channel curve inverts red only, and the master curve comes after it
+    let inverted = run("curves", serde_json::json!({"red": [[0, 255], [255, 0]]})).unwrap().to_rgba8();
+    for (before, after) in img.to_rgba8().pixels().zip(inverted.pixels()) {
+        assert_eq!([after[0], after[1], after[2], after[3]], [255 - before[0], before[1], before[2], before[3]]);
+    }
+    let flat = run("curves", serde_json::json!({"red": [[0, 0], [255, 255]], "master": [[0, 100], [255, 100]]})).unwrap();
+    assert!(flat.to_rgba8().pixels().all(|p| p[0] == 100 && p[1] == 100 && p[2] == 100));
+    assert!(run("curves", serde_json::json!({"master": [[0, 0], [300, 255]]})).is_err());
+    assert!(run("curves", serde_json::json!({"master": [[0, 0]]})).is_err());
+
+    // Levels clip below the black point, stretch, and bend the midtones
+    let leveled = run("levels", serde_json::json!({"in_black": 50, "in_white": 150, "out_black": 10, "out_white": 210})).unwrap().to_rgba8();
+    for (before, after) in img.to_rgba8().pixels().zip(leveled.pixels()) {
+        let expected = 10.0 + ((before[0] as f32 - 50.0) / 100.0).clamp(0.0, 1.0) * 200.0;
+        assert!((after[0] as f32 - expected).abs() <= 1.0, "{} -> {}", before[0], after[0]);
+    }
+    let gray = DynamicImage::ImageRgba8(ImageBuffer::from_pixel(4, 4, Rgba([128, 128, 128, 255])));
+    let effect = effects::find("levels").unwrap();
+    let brighter = (effect.apply)(gray, &effects::Params::new(effect, &serde_json::json!({"gamma": 2.0}))).unwrap();
+    assert!(brighter.to_rgba8().get_pixel(0, 0)[0] > 170);
+    assert!(run("levels", serde_json::json!({"in_black": 200, "in_white": 100})).is_err());
+}
+
+#[test]
+fn test_gradient_map() {
+    use img_corroder::colorfx::gradient::{Gradient, Interpolation};
+    use img_corroder::colorfx::utils::rgb_to_oklab;
+
+    let ramp = DynamicImage::ImageRgba8(ImageBuffer::from_fn(256, 1, |x, _| Rgba([x as u8, x as u8, x as u8, 255])));
+    let effect = effects::find("gradient_map").unwrap();
+    let run = |params: serde_json::Value| {
+        (effect.apply)(ramp.clone(), &effects::Params::new(effect, &params)).map(|img| img.to_rgba8())
+    };
+    let near = |pixel: &Rgba<u8>, expected: [u8; 3]| (0..3).all(|c| (pixel[c] as i32 - expected[c] as i32).abs() <= 2);
+
+    // Presets run from their first color at black to their last at white
+    let viridis = run(serde_json::json!({})).unwrap();
+    assert!(near(viridis.get_pixel(0, 0), [0x44, 0x01, 0x54]), "{:?}", viridis.get_pixel(0, 0));
+    assert!(near(viridis.get_pixel(255, 0), [0xfd, 0xe7, 0x25]), "{:?}", viridis.get_pixel(255, 0));
+    let reversed = run(serde_json::json!({"reverse": true})).unwrap();
+    assert!(near(reversed.get_pixel(0, 0), [0xfd, 0xe7, 0x25]));
+
+    // Custom stops with a position, CSS style
+    let stops = run(serde_json::json!({"stops": ["#000000", "#ff0000 25%", "#ffffff"], "interpolation": "rgb"})).unwrap();
+    assert!(near(stops.get_pixel(0, 0), [0, 0, 0]) && near(stops.get_pixel(255, 0), [255, 255, 255]));
+    assert!(near(stops.get_pixel(64, 0), [255, 0, 0]), "{:?}", stops.get_pixel(64, 0));
+    let same = run(serde_json::json!({"stops": "#000000, #ff0000 0.25, #ffffff", "interpolation": "rgb"})).unwrap();
+    assert_eq!(stops, same);
+
+    // An OKLab duotone gets lighter all the way along
+    let duotone = Gradient::new(&[(image::Rgb([20, 30, 90]), None), (image::Rgb([250, 200, 60]), None)], Interpolation::Oklab).unwrap();
+    let lightness: Vec<f32> = (0..=20).map(|i| {
+        let [r, g, b] = duotone.sample(i as f32 / 20.0);
+        rgb_to_oklab(r, g, b)[0]
+    }).collect();
+    assert!(lightness.windows(2).all(|pair| pair[1] > pair[0]));
+
+    assert_eq!(run(serde_json::json!({"strength": 0.0})).unwrap(), ramp.to_rgba8());
+    assert!(run(serde_json::json!({"stops": ["#000000", "#ffffff 150%"]})).is_err());
+    assert!(run(serde_json::json!({"stops": ["#000000", "#ff0000 60%", "#00ff00 30%", "#ffffff"]})).is_err());
+    assert!(run(serde_json::json!({"preset": "rainbow"})).is_err());
+}
+
+#[test]
+fn test_cube_luts() {
+    use std::sync::Arc;
+    use img_corroder::lut::{self, Interpolation, Lut};
+
+    let img = create_test_image();
+    let max_difference = |a: &DynamicImage, b: &DynamicImage| {
+        a.to_rgba8().pixels().zip(b.to_rgba8().pixels())
+            .flat_map(|(p, q)| (0..3).map(move |c| (p[c] as i32 - q[c] as i32).abs()))
+            .max()
+            .unwrap()
+    };
+
+    // A size-2 identity cube gives back the input under both interpolations
+    let identity = Lut::parse("# identity\nLUT_3D_SIZE 2\n0 0 0\n1 0 0\n0 1 0\n1 1 0\n0 0 1\n1 0 1\n0 1 1\n1 1 1\n").unwrap();
+    for interpolation in [Interpolation::Tetrahedral, Interpolation::Trilinear] {
+        assert!(max_difference(&lut::apply(&img, &identity, interpolation, 1.0), &img) <= 1);
+    }
+    let inverse = Lut::parse("TITLE \"invert\"\nLUT_1D_SIZE 2\n1 1 1\n0 0 0\n").unwrap();
+    assert!(!inverse.is_3d());
+    let mut inverted = img.clone();
+    inverted.invert();
+    assert!(max_difference(&lut::apply(&img, &inverse, Interpolation::Tetrahedral, 1.0), &inverted) <= 1);
+
+    // A baked pipeline matches running it, and survives a round trip through the file
+    let steps: Vec<pipeline::TransformConfig> = serde_json::from_value(serde_json::json!([
+        {"name": "contrast", "params": {"factor": 1.3}},
+        {"name": "hue_rotate", "params": {"angle": 40}},
+        {"name": "sepia"},
+    ])).unwrap();
+    let baked = lut::bake(&steps, 33, Some("look".to_string())).expect("Baking failed");
+    let reparsed = Lut::parse(&baked.to_cube()).unwrap();
+    assert_eq!((reparsed.size(), reparsed.title.as_deref()), (33, Some("look")));
+    let direct = pipeline::apply_pipeline(img.clone(), &steps).unwrap();
+    assert!(max_difference(&lut::apply(&img, &reparsed, Interpolation::Tetrahedral, 1.0), &direct) <= 6);
+
+    let path = std::env::temp_dir().join("img_corroder_lut_test.cube");
+    std::fs::write(&path, baked.to_cube()).unwrap();
+    let effect = effects::find("apply_lut").unwrap();
+    let params = serde_json::json!({"path": path.to_str().unwrap(), "strength": 0.0});
+    assert!(max_difference(&(effect.apply)(img.clone(), &effects::Params::new(effect, &params)).unwrap(), &img) <= 1);
+    // Cached until the file changes
+    assert!(Arc::ptr_eq(&lut::load_cached(path.to_str().unwrap()).unwrap(), &lut::load_cached(path.to_str().unwrap()).unwrap()));
+    std::fs::write(&path, inverse.to_cube()).unwrap();
+    assert!(!lut::load_cached(path.to_str().unwrap()).unwrap().is_3d());
+    std::fs::remove_file(&path).ok();
+    // A typo'd or missing path is an error, not a silent no-op
+    assert!((effect.apply)(img.clone(), &effects::Params::new(effect, &serde_json::json!({}))).is_err());
+    let missing: Vec<pipeline::TransformConfig> = serde_json::from_value(serde_json::json!([{"name": "apply_lut", "params": {"strength": 0.5}}])).unwrap();
+    let error = pipeline::apply_pipeline(img.clone(), &missing).unwrap_err().to_string();
+    assert!(error.contains("'path' is required"), "{}", error);
+    assert!(effect.has_required());
+    assert_eq!(effect.to_json()["params"][0]["required"], true);
+
+    let blur: Vec<pipeline::TransformConfig> = serde_json::from_value(serde_json::json!([{"name": "blur"}])).unwrap();
+    assert!(lut::bake(&blur, 17, None).is_err());
+    assert!(Lut::parse("LUT_3D_SIZE 2\n0 0 0\n1 1 1\n").is_err());
+    assert!(Lut::parse("0 0 0\nLUT_1D_SIZE 2\n").is_err());
+    // Errors do not repeat the contents of files that are not LUTs
+    let error = Lut::parse("root:x:0:0:root:/root:/bin/sh\n").unwrap_err();
+    assert!(!error.contains("root"), "{}", error);
+    let files: Vec<&str> = effects::EFFECTS.iter().flat_map(|e| e.params.iter().filter(|p| p.file).map(|p| p.name)).collect();
+    assert_eq!(files, ["path", "threshold_map", "reference"]);
+}
+
+#[test]
+fn test_color_parsing() {
+    use img_corroder::colorfx::utils::{parse_color, split_color_list};
+
+    let rgba = |color: &str| parse_color(color).map(|c| c.0);
+    assert_eq!(rgba("#f80"), Ok([255, 136, 0, 255]));
+    assert_eq!(rgba("#f808"), Ok([255, 136, 0, 136]));
+    assert_eq!(rgba("#FF8800"), Ok([255, 136, 0, 255]));
+    assert_eq!(rgba("ff880080"), Ok([255, 136, 0, 128]));
+    assert_eq!(rgba("rgb(255, 99, 71)"), Ok([255, 99, 71, 255]));
+    assert_eq!(rgba("rgba(100%, 0%, 0%, 0.5)"), Ok([255, 0, 0, 128]));
+    assert_eq!(rgba("rgb(255 99 71 / 50%)"), Ok([255, 99, 71, 128]));
+    assert_eq!(rgba("hsl(120, 100%, 25%)"), Ok([0, 128, 0, 255]));
+    assert_eq!(rgba("hsl(0.5turn 100% 50%)"), Ok([0, 255, 255, 255]));
+    assert_eq!(rgba("Tomato"), Ok([255, 99, 71, 255]));
+    assert_eq!(rgba("green"), Ok([0, 128, 0, 255]));
+    assert_eq!(rgba("transparent"), Ok([0, 0, 0, 0]));
+    for bad in ["#12345", "rgb(1, 2)", "hsl(x, 1%, 1%)", "notacolor", ""] {
+        assert!(parse_color(bad).is_err(), "{} should not parse", bad);
+    }
+    assert_eq!(split_color_list("red, rgb(0, 0, 255) 50%, #fff"), vec!["red", "rgb(0, 0, 255) 50%", "#fff"]);
+
+    // Effects taking colors accept every syntax, in lists and stops too
+    let img = DynamicImage::ImageRgba8(ImageBuffer::from_pixel(4, 4, Rgba([255, 0, 0, 255])));
+    let run = |name: &str, params: serde_json::Value| {
+        let effect = effects::find(name).unwrap();
+        (effect.apply)(img.clone(), &effects::Params::new(effect, &params)).map(|img| img.to_rgba8())
+    };
+    let replaced = run("color_replacer", serde_json::json!({"target_color": "rgb(255, 0, 0)", "replacement_color": "tomato"})).unwrap();
+    assert_eq!(replaced.get_pixel(0, 0).0, [255, 99, 71, 255]);
+    let mapped = run("gradient_map", serde_json::json!({"stops": "rgb(0, 0, 0), hsl(0, 100%, 50%) 30%, white"})).unwrap();
+    assert_eq!(mapped, run("gradient_map", serde_json::json!({"stops": ["#000", "#f00 30%", "#fff"]})).unwrap());
+    assert!(run("color_replacer", serde_json::json!({"target_color": "reddish"})).is_err());
+}